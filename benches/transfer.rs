@@ -0,0 +1,146 @@
+//! Baselines of the hot loops of the transfer paths. Run with `cargo bench --features bench`.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use richclip::clipboard::bench::decide_mime_type;
+use richclip::protocol::{self, SourceData, SourceDataItem};
+use std::hint::black_box;
+
+const MIB: usize = 1024 * 1024;
+
+fn source_items(count: usize, size: usize) -> Vec<SourceDataItem> {
+    (0..count)
+        .map(|i| {
+            let mime_type = format!("application/x-bench-{i}");
+            SourceDataItem::new(vec![mime_type], vec![i as u8; size])
+        })
+        .collect()
+}
+
+fn bench_source_data(c: &mut Criterion) {
+    let mut group = c.benchmark_group("source_data");
+    for size in [4 * 1024, MIB, 16 * MIB] {
+        let mut bulk = vec![];
+        protocol::send_data_bulk(&mut bulk, &source_items(4, size)).unwrap();
+        group.throughput(Throughput::Bytes(bulk.len() as u64));
+        group.bench_with_input(BenchmarkId::new("receive_bulk", size), &bulk, |b, bulk| {
+            b.iter(|| protocol::receive_data_bulk(black_box(&bulk[..])).unwrap())
+        });
+    }
+    group.finish();
+
+    let items = source_items(1000, 16);
+    c.bench_function("source_data/content_by_mime_type", |b| {
+        b.iter(|| items.content_by_mime_type(black_box("application/x-bench-999")))
+    });
+}
+
+fn bench_decide_mime_type(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decide_mime_type");
+    for count in [10, 100, 1000] {
+        // The matching types are at the end of the list, which is the worst case
+        let mut supported: Vec<String> = (0..count)
+            .map(|i| format!("application/x-bench-{i}"))
+            .collect();
+        supported.push("image/png".to_string());
+        supported.push("text/plain".to_string());
+        for preferred in ["", "image/png", "image/*", "x-not/offered"] {
+            let id = BenchmarkId::new(format!("'{preferred}'"), count);
+            group.bench_with_input(id, &supported, |b, supported| {
+                b.iter(|| decide_mime_type(black_box(preferred), supported))
+            });
+        }
+    }
+    group.finish();
+}
+
+#[cfg(feature = "x11")]
+fn bench_x_incr_chunks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("x_incr_chunks");
+    let len = 64 * MIB;
+    group.throughput(Throughput::Bytes(len as u64));
+    // From the tiny chunks of RICHCLIP_X_CHUNK_SIZE to the default of a 16 MiB request limit
+    for chunk_size in [64, 4096, 64 * 1024, 4 * MIB] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(chunk_size),
+            &chunk_size,
+            |b, &chunk_size| {
+                b.iter(|| {
+                    richclip::clipboard::bench::x_incr_chunks(black_box(len), chunk_size).count()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+#[cfg(not(feature = "x11"))]
+fn bench_x_incr_chunks(_c: &mut Criterion) {}
+
+/// Send the INCR chunks to a fake X server which discards them, flushing the connection after
+/// every chunk like before, or once after the batch of chunks like the selection owner does now.
+#[cfg(feature = "x11")]
+fn bench_x_incr_flush(c: &mut Criterion) {
+    use std::os::unix::net::UnixStream;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::{AtomEnum, PropMode, Setup};
+    use x11rb::rust_connection::{DefaultStream, RustConnection};
+    use x11rb::wrapper::ConnectionExt;
+
+    let (client, mut server) = UnixStream::pair().unwrap();
+    std::thread::spawn(move || std::io::copy(&mut server, &mut std::io::sink()));
+    let (stream, _) = DefaultStream::from_unix_stream(client).unwrap();
+    let setup = Setup {
+        resource_id_base: 0x400000,
+        resource_id_mask: 0x1fffff,
+        maximum_request_length: u16::MAX,
+        ..Default::default()
+    };
+    let conn = RustConnection::for_connected_stream(stream, setup).unwrap();
+
+    let content = vec![0u8; 16 * MIB];
+    let send = |chunk_size: usize, flush_each: bool| {
+        for range in richclip::clipboard::bench::x_incr_chunks(content.len(), chunk_size) {
+            conn.change_property8(
+                PropMode::REPLACE,
+                1,
+                AtomEnum::PRIMARY,
+                AtomEnum::STRING,
+                &content[range],
+            )
+            .unwrap();
+            if flush_each {
+                conn.flush().unwrap();
+            }
+        }
+        conn.flush().unwrap();
+    };
+
+    let mut group = c.benchmark_group("x_incr_flush");
+    group.throughput(Throughput::Bytes(content.len() as u64));
+    // Below the maximum request length of the fake server, so BIG-REQUESTS is not needed
+    for chunk_size in [4096, 64 * 1024] {
+        group.bench_with_input(
+            BenchmarkId::new("per_chunk", chunk_size),
+            &chunk_size,
+            |b, &chunk_size| b.iter(|| send(chunk_size, true)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("per_batch", chunk_size),
+            &chunk_size,
+            |b, &chunk_size| b.iter(|| send(chunk_size, false)),
+        );
+    }
+    group.finish();
+}
+
+#[cfg(not(feature = "x11"))]
+fn bench_x_incr_flush(_c: &mut Criterion) {}
+
+criterion_group!(
+    benches,
+    bench_source_data,
+    bench_decide_mime_type,
+    bench_x_incr_chunks,
+    bench_x_incr_flush
+);
+criterion_main!(benches);
@@ -1,20 +1,31 @@
+use super::Capabilities;
 use super::ClipBackend;
 use super::CopyConfig;
 use super::PasteConfig;
-use anyhow::{Context, Result, bail};
+use super::PasteError;
+use super::WatchConfig;
+use super::mime_type::{canonicalize_mime_type, mime_type_by_index};
+use crate::protocol::SourceData;
+use anyhow::{Result, bail};
 
 use cocoa::appkit;
 use cocoa::appkit::NSPasteboard;
+use cocoa::appkit::NSPasteboardItem;
 use cocoa::base::id;
 use cocoa::base::nil;
 use cocoa::foundation::NSArray;
 use cocoa::foundation::NSAutoreleasePool;
 use cocoa::foundation::NSData;
+use cocoa::foundation::NSRange;
 use cocoa::foundation::NSString;
+use objc::{class, msg_send, sel, sel_impl};
 
 use std::collections::HashMap;
 use std::ffi::CStr;
+use std::io::Write;
+use std::rc::Rc;
 use std::sync::LazyLock;
+use std::time::Instant;
 
 // cocoa's pasteboard system is strange, just support what is needed for now.
 // See https://developer.apple.com/documentation/appkit/nspasteboard/pasteboardtype
@@ -45,13 +56,51 @@ static SUPPORTED_TYPES_MAP: LazyLock<HashMap<String, Vec<&str>>> = unsafe {
                 nsstring_to_string(cocoa::appkit::NSPasteboardTypeRTF),
                 vec!["public.rtf", "application/rtf", "rtf"],
             ),
+            // The image types are passed through as they are, without converting between them
+            (
+                "public.png".to_string(),
+                vec!["public.png", "image/png", "png"],
+            ),
+            (
+                "public.tiff".to_string(),
+                vec!["public.tiff", "image/tiff", "tiff"],
+            ),
+            (
+                "public.jpeg".to_string(),
+                vec!["public.jpeg", "image/jpeg", "image/jpg", "jpeg"],
+            ),
+            (
+                "com.compuserve.gif".to_string(),
+                vec!["com.compuserve.gif", "image/gif", "gif"],
+            ),
         ])
     })
 };
 
+// File lists are copied as one pasteboard item per file, see `write_to_pasteboard`
+const URI_LIST_TYPE: &str = "text/uri-list";
+const FILE_URL_TYPE: &str = "public.file-url";
+
+// The pasted data is copied to the output by windows of this size
+const PASTE_CHUNK_SIZE: usize = 1024 * 1024;
+
 pub struct MacBackend {}
 
 impl ClipBackend for MacBackend {
+    fn name(&self) -> &'static str {
+        "mac"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            primary_selection: false,
+            watch: true,
+            image_types: true,
+            incr: false,
+            multiple_items: true,
+        }
+    }
+
     fn copy(&self, config: CopyConfig) -> Result<()> {
         unsafe { copy_mac(config) }
     }
@@ -59,44 +108,165 @@ impl ClipBackend for MacBackend {
     fn paste(&self, config: PasteConfig) -> Result<()> {
         unsafe { paste_mac(config) }
     }
+
+    fn watch(&self, config: WatchConfig) -> Result<()> {
+        unsafe { watch_mac(config) }
+    }
+}
+
+/// The content to be set for a pasteboard type.
+struct PasteboardContent {
+    mime_type: String,
+    ns_type: String,
+    content: Rc<Vec<u8>>,
 }
 
 unsafe fn copy_mac(config: CopyConfig) -> Result<()> {
     let _pool = NSAutoreleasePool::new(nil);
+    let start_time = Instant::now();
 
     let pb = NSPasteboard::generalPasteboard(nil);
-    let types = config.source_data.mime_types();
+    // Check all the types before touching the pasteboard, so an unsupported type doesn't leave it
+    // half-populated
+    let (urls, contents) = pasteboard_contents(config.source_data.as_ref())?;
 
-    pb.clearContents();
+    if config.mac_no_clear {
+        add_to_pasteboard(pb, &urls, &contents)?;
+    } else {
+        write_to_pasteboard(pb, &urls, &contents)?;
+    }
+    let total_bytes: usize = contents.iter().map(|c| c.content.len()).sum();
+    log::debug!("Copied {} bytes in {:?}", total_bytes, start_time.elapsed());
 
-    for t in &types {
-        let ns_pb_type = match_ns_pasteboard_type(t);
-        if ns_pb_type.is_empty() {
-            bail!("Failed to copy content of type {t}")
+    Ok(())
+}
+
+/// Map the mime-types of the source data to the pasteboard types, and collect their content.
+/// Return the file urls of `text/uri-list` as well. Fail if any mime-type is not supported.
+unsafe fn pasteboard_contents(
+    source_data: &dyn SourceData,
+) -> Result<(Vec<String>, Vec<PasteboardContent>)> {
+    let mut urls = vec![];
+    let mut contents = vec![];
+    for t in source_data.mime_types() {
+        if t.eq_ignore_ascii_case(URI_LIST_TYPE) {
+            let (_, content) = source_data.content_by_mime_type(&t);
+            urls = parse_uri_list(&content);
+            continue;
+        }
+        let ns_type = match_ns_pasteboard_type(&t);
+        if ns_type.is_empty() {
+            bail!("Failed to copy content of type {t}, it is not supported on macOS")
         }
-        let res = config.source_data.content_by_mime_type(t);
-        if !res.0 {
-            log::warn!("No content found fro {t}");
+        let (found, content) = source_data.content_by_mime_type(&t);
+        if !found {
+            log::warn!("No content found for {t}");
             continue;
         }
-        let nstr_type = NSString::alloc(nil).init_str(ns_pb_type.as_str());
-        let bytes = res.1.as_ptr() as *const std::os::raw::c_void;
-        let length = res.1.len() as u64;
+        contents.push(PasteboardContent {
+            mime_type: t,
+            ns_type,
+            content,
+        });
+    }
+    Ok((urls, contents))
+}
+
+/// Replace the pasteboard content with a single `writeObjects`, so it is either fully set or
+/// left as it is. File lists are copied as one pasteboard item per file, so Finder recognizes
+/// them as multiple files. Other types are set on the first item.
+unsafe fn write_to_pasteboard(
+    pb: id,
+    urls: &[String],
+    contents: &[PasteboardContent],
+) -> Result<()> {
+    let mut items = file_url_items(urls)?;
+    if items.is_empty() {
+        let item: id = msg_send![class!(NSPasteboardItem), new];
+        items.push(item.autorelease());
+    }
+    for c in contents {
+        let nstr_type = NSString::alloc(nil).init_str(&c.ns_type).autorelease();
+        // Copied, since the item may outlive the content
+        let bytes = c.content.as_ptr() as *const std::os::raw::c_void;
+        let nsdata = NSData::dataWithBytes_length_(nil, bytes, c.content.len() as u64);
+        if items[0].setData_forType(nsdata, nstr_type) != objc::runtime::YES {
+            bail!("Failed to set the content of {}", c.mime_type);
+        }
+    }
+    let array = NSArray::arrayWithObjects(nil, &items);
+    pb.clearContents();
+    if pb.writeObjects(array) != objc::runtime::YES {
+        bail!("Failed to write the content to the pasteboard");
+    }
+    Ok(())
+}
+
+/// Add the types to the pasteboard without clearing it, for `--no-clear`. The types are set one
+/// by one, since the existing items cannot be written again.
+unsafe fn add_to_pasteboard(pb: id, urls: &[String], contents: &[PasteboardContent]) -> Result<()> {
+    // The types have to be declared before setting their data without clearing
+    let ns_types: Vec<id> = contents
+        .iter()
+        .map(|c| NSString::alloc(nil).init_str(&c.ns_type).autorelease())
+        .collect();
+    let array = NSArray::arrayWithObjects(nil, &ns_types);
+    let _: isize = msg_send![pb, addTypes: array owner: nil];
+
+    // The file urls need to be written first, then the other types will be set on the first item.
+    let items = file_url_items(urls)?;
+    if !items.is_empty() {
+        let array = NSArray::arrayWithObjects(nil, &items);
+        if pb.writeObjects(array) != objc::runtime::YES {
+            bail!("Failed to write {} file urls to the pasteboard", urls.len());
+        }
+    }
+
+    for c in contents {
+        let nstr_type = NSString::alloc(nil).init_str(c.ns_type.as_str());
+        let bytes = c.content.as_ptr() as *const std::os::raw::c_void;
+        let length = c.content.len() as u64;
         let nsdata = NSData::dataWithBytesNoCopy_length_(nil, bytes, length);
         let r = pb.setData_forType(nsdata, nstr_type);
         if r != objc::runtime::YES {
-            log::error!("Failed to call setData_forType on {t}");
+            log::error!("Failed to call setData_forType on {}", c.mime_type);
         }
     }
-
     Ok(())
 }
 
-unsafe fn paste_mac(config: PasteConfig) -> Result<()> {
+/// Create a pasteboard item for every file url.
+unsafe fn file_url_items(urls: &[String]) -> Result<Vec<id>> {
+    let nstr_type = NSString::alloc(nil).init_str(FILE_URL_TYPE);
+    let mut items = vec![];
+    for url in urls {
+        let item: id = msg_send![class!(NSPasteboardItem), new];
+        let item = item.autorelease();
+        let nstr_url = NSString::alloc(nil).init_str(url).autorelease();
+        if item.setString_forType(nstr_url, nstr_type) != objc::runtime::YES {
+            bail!("Failed to set file url {url}");
+        }
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// Parse the `text/uri-list` content, see RFC 2483.
+fn parse_uri_list(content: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+unsafe fn paste_mac(mut config: PasteConfig) -> Result<()> {
     let _pool = NSAutoreleasePool::new(nil);
+    let start_time = Instant::now();
 
-    let mut writer = config.writer;
     let mut type_list: Vec<String> = vec![];
+    let mut raw_type_list: Vec<String> = vec![];
 
     let pb = NSPasteboard::generalPasteboard(nil);
     let types = pb.types();
@@ -104,55 +274,140 @@ unsafe fn paste_mac(config: PasteConfig) -> Result<()> {
 
     for i in 0..count {
         let t = types.objectAtIndex(i);
-        let str = nsstring_to_string(t);
+        let Some(str) = nsstring_to_string_opt(t) else {
+            log::debug!("Skip the pasteboard type which cannot be converted to string");
+            continue;
+        };
         if SUPPORTED_TYPES_MAP.contains_key(&str) {
-            type_list.push(str);
+            type_list.push(str.clone());
         }
+        raw_type_list.push(str);
     }
 
     if config.list_types_only {
-        for str in type_list {
-            writeln!(&mut writer, "{}", str).context("Failed to write to the output")?;
-        }
+        let list = if config.list_all_types {
+            raw_type_list
+        } else {
+            type_list
+        };
+        config.write_type_list(&list)?;
         return Ok(());
     }
 
-    let expected_type = match_ns_pasteboard_type(&config.expected_mime_type);
-    if expected_type.is_empty() {
-        bail!(
-            "Content for mime-type {} doesn't exist",
-            config.expected_mime_type
-        )
-    }
+    let expected_type = match config.target_index {
+        Some(index) => mime_type_by_index(index, &type_list)?,
+        None => {
+            let preferred = if config.expected_mime_types.is_empty() {
+                vec!["".to_string()]
+            } else {
+                config.expected_mime_types.clone()
+            };
+            let found = preferred.iter().find_map(|mime_type| {
+                let ns_type = match_ns_pasteboard_type(mime_type);
+                if !ns_type.is_empty() && type_list.contains(&ns_type) {
+                    return Some(ns_type);
+                }
+                // Try the raw pasteboard types without mapping
+                raw_type_list
+                    .iter()
+                    .find(|t| t.eq_ignore_ascii_case(mime_type))
+                    .inspect(|raw_type| log::debug!("Use the raw pasteboard type {raw_type}"))
+                    .cloned()
+            });
+            match found {
+                Some(t) => t,
+                None => return Err(config.not_offered_error()),
+            }
+        }
+    };
 
     let nstr_type: *mut objc::runtime::Object =
         NSString::alloc(nil).init_str(expected_type.as_str());
     let data = pb.dataForType(nstr_type);
-    let bytes = data.bytes() as *const u8;
+    if data == nil {
+        return Err(PasteError::NoContent(expected_type).into());
+    }
+    // The pasteboard types are not mime-types, use the mapped one for the writer.
+    let mime_type = match SUPPORTED_TYPES_MAP.get(&expected_type) {
+        Some(types) => types[1].to_string(),
+        None => expected_type.clone(),
+    };
+    config.prepare_writer(&mime_type);
+    let length = write_nsdata(data, &mut config.writer)?;
+    config.writer.flush()?;
+    log::debug!("Pasted {} bytes in {:?}", length, start_time.elapsed());
+
+    Ok(())
+}
+
+/// Write the content of the `NSData` to the writer. It is copied by `getBytes:range:` into a small
+/// reusable buffer, so the large content is streamed instead of being written as a whole. Return
+/// the number of written bytes.
+unsafe fn write_nsdata(data: id, writer: &mut dyn Write) -> Result<usize> {
     let length = data.length() as usize;
-    let slice = std::slice::from_raw_parts(bytes, length);
-    writer.write_all(slice)?;
-    writer.flush()?;
+    let mut buffer = vec![0u8; PASTE_CHUNK_SIZE.min(length)];
+    let mut offset = 0;
+    while offset < length {
+        let len = buffer.len().min(length - offset);
+        let range = NSRange::new(offset as u64, len as u64);
+        let ptr = buffer.as_mut_ptr() as *mut std::os::raw::c_void;
+        let _: () = msg_send![data, getBytes: ptr range: range];
+        writer.write_all(&buffer[..len])?;
+        offset += len;
+    }
+    Ok(length)
+}
 
+// There is no notification for the pasteboard changes, poll the change count instead.
+unsafe fn watch_mac(mut config: WatchConfig) -> Result<()> {
+    let pb = NSPasteboard::generalPasteboard(nil);
+    let mut last_count = pb.changeCount();
+    log::debug!(
+        "Watch the pasteboard from change count {last_count} every {:?}",
+        config.poll_interval
+    );
+
+    loop {
+        std::thread::sleep(config.poll_interval);
+        let count = pb.changeCount();
+        if count == last_count {
+            continue;
+        }
+        log::debug!("The pasteboard change count changed from {last_count} to {count}");
+        last_count = count;
+        // Release the objects created by pasting the changed content on every change, since
+        // the loop may run forever
+        let pool = NSAutoreleasePool::new(nil);
+        let keep_watching = config.notify_change();
+        pool.drain();
+        if !keep_watching? {
+            break;
+        }
+    }
     Ok(())
 }
 
 unsafe fn nsstring_to_string(ns_str: id) -> String {
+    nsstring_to_string_opt(ns_str).expect("Empty or null NSString content")
+}
+
+/// Return `None` if the `NSString` is null, invalid UTF-8 will be replaced.
+unsafe fn nsstring_to_string_opt(ns_str: id) -> Option<String> {
+    if ns_str == nil {
+        return None;
+    }
     let c_str: *const i8 = NSString::UTF8String(ns_str);
 
     if c_str.is_null() {
-        panic!("Empty or null NSString content");
+        return None;
     }
 
-    CStr::from_ptr(c_str)
-        .to_str()
-        .expect("Invalid UTF-8 string")
-        .to_string()
+    Some(CStr::from_ptr(c_str).to_string_lossy().into_owned())
 }
 
 unsafe fn match_ns_pasteboard_type(mime_type: &str) -> String {
     if !mime_type.is_empty() {
-        let target = mime_type.to_lowercase();
+        let target = canonicalize_mime_type(mime_type);
         if let Some((key, _)) = SUPPORTED_TYPES_MAP
             .iter()
             .find(|(_, types)| types.iter().any(|s| s.to_lowercase().contains(&target)))
@@ -165,3 +420,73 @@ unsafe fn match_ns_pasteboard_type(mime_type: &str) -> String {
         nsstring_to_string(appkit::NSPasteboardTypeString)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_uri_list() {
+        let r = parse_uri_list(b"# comment\r\nfile:///tmp/a\r\n\r\nfile:///tmp/b\n");
+        assert_eq!(r, vec!["file:///tmp/a", "file:///tmp/b"]);
+
+        let r = parse_uri_list(b"");
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn test_pasteboard_contents() {
+        use crate::protocol::SourceDataItem;
+        unsafe {
+            let items = vec![
+                SourceDataItem::new(vec!["text/plain".to_string()], b"GOOD".to_vec()),
+                SourceDataItem::new(
+                    vec!["text/uri-list".to_string()],
+                    b"file:///tmp/a\n".to_vec(),
+                ),
+            ];
+            let (urls, contents) = pasteboard_contents(&items).unwrap();
+            assert_eq!(urls, vec!["file:///tmp/a"]);
+            assert_eq!(contents.len(), 1);
+            assert_eq!(contents[0].ns_type, "public.utf8-plain-text");
+            assert_eq!(contents[0].content.as_slice(), b"GOOD");
+
+            // Nothing is collected if any type is not supported, so the pasteboard is untouched
+            let items = vec![
+                SourceDataItem::new(vec!["text/plain".to_string()], b"GOOD".to_vec()),
+                SourceDataItem::new(vec!["x-unknown/type".to_string()], b"BAD".to_vec()),
+            ];
+            assert!(pasteboard_contents(&items).is_err());
+        }
+    }
+
+    #[test]
+    fn test_write_nsdata() {
+        unsafe {
+            let _pool = NSAutoreleasePool::new(nil);
+            let content: Vec<u8> = (0..PASTE_CHUNK_SIZE * 3 + 123)
+                .map(|i| (i % 251) as u8)
+                .collect();
+            let data = NSData::dataWithBytes_length_(
+                nil,
+                content.as_ptr() as *const std::os::raw::c_void,
+                content.len() as u64,
+            );
+            let mut output = vec![];
+            assert_eq!(write_nsdata(data, &mut output).unwrap(), content.len());
+            assert!(output == content);
+
+            let empty = NSData::dataWithBytes_length_(nil, std::ptr::null(), 0);
+            assert_eq!(write_nsdata(empty, &mut output).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = MacBackend {}.capabilities();
+        assert!(!capabilities.primary_selection);
+        assert!(capabilities.watch);
+        assert!(!capabilities.incr);
+        assert!(capabilities.multiple_items);
+    }
+}
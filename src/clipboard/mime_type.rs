@@ -1,4 +1,9 @@
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::OnceLock;
 
 const TEXT_TYPE_EXACT: &[&str] = &[
     "text/plain;charset=utf-8",
@@ -6,11 +11,30 @@ const TEXT_TYPE_EXACT: &[&str] = &[
     "TEXT",
     "STRING",
     "UTF8_STRING",
+    "application/json",
     "json",
 ];
 
+// The aliases of UTF-8 plain text, from X and Windows
+const TEXT_ALIASES: &[&str] = &["TEXT", "STRING", "UTF8_STRING", "CF_TEXT", "CF_UNICODETEXT"];
+const CANONICAL_TEXT_TYPE: &str = "text/plain;charset=utf-8";
+
+// The aliases loaded from `--alias-file`, keyed by the lowercased alias
+static USER_ALIASES: OnceLock<HashMap<String, String>> = OnceLock::new();
+
 const TEXT_TYPE_SUFFIX: &[&str] = &["script", "xml", "yaml", "csv", "ini"];
 
+// The suffix must follow a boundary like `image/svg+xml` or `application/x-yaml`, so a made-up
+// `application/fooxml` is not taken as text. Except "script", which ends the names of the scripting
+// languages like `javascript` and `postscript`.
+fn has_text_suffix(mime_type: &str, suffix: &str) -> bool {
+    let mime_type = mime_type.to_ascii_lowercase();
+    let Some(rest) = mime_type.strip_suffix(suffix) else {
+        return false;
+    };
+    suffix == "script" || rest.ends_with(['/', '+', '-'])
+}
+
 fn try_any_text(supported: &[String]) -> Option<String> {
     // Match the exact type with priorities
     for expected in TEXT_TYPE_EXACT {
@@ -23,10 +47,7 @@ fn try_any_text(supported: &[String]) -> Option<String> {
     }
     // Match the suffix
     for suffix in TEXT_TYPE_SUFFIX {
-        if let Some(r) = supported
-            .iter()
-            .find(|str| str.to_ascii_lowercase().ends_with(suffix))
-        {
+        if let Some(r) = supported.iter().find(|str| has_text_suffix(str, suffix)) {
             return Some(r.clone());
         }
     }
@@ -40,6 +61,165 @@ fn try_any_text(supported: &[String]) -> Option<String> {
     None
 }
 
+/// Return the canonical form of the mime-type for matching. It is lowercased and trimmed, and the
+/// well-known text aliases like `UTF8_STRING` or `CF_UNICODETEXT` become
+/// `text/plain;charset=utf-8`.
+pub fn canonicalize_mime_type(mime_type: &str) -> String {
+    let trimmed = mime_type.trim();
+    if let Some(target) = USER_ALIASES
+        .get()
+        .and_then(|aliases| aliases.get(&trimmed.to_ascii_lowercase()))
+    {
+        return canonicalize_builtin(target);
+    }
+    canonicalize_builtin(trimmed)
+}
+
+fn canonicalize_builtin(mime_type: &str) -> String {
+    let trimmed = mime_type.trim();
+    if TEXT_ALIASES.iter().any(|a| a.eq_ignore_ascii_case(trimmed)) {
+        return CANONICAL_TEXT_TYPE.to_string();
+    }
+    trimmed
+        .split(';')
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(";")
+        .to_ascii_lowercase()
+}
+
+/// Parse the aliases file. Every line maps an alias to a mime-type like `md=text/markdown`, the
+/// empty lines and the lines starting with `#` are skipped.
+pub fn parse_aliases(content: &str) -> Result<HashMap<String, String>> {
+    let mut aliases = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((alias, mime_type)) = line
+            .split_once('=')
+            .map(|(a, t)| (a.trim(), t.trim()))
+            .filter(|(a, t)| !a.is_empty() && !t.is_empty())
+        else {
+            bail!(
+                "Invalid alias '{line}' at line {}, expected 'alias=mime/type'",
+                i + 1
+            );
+        };
+        aliases.insert(alias.to_ascii_lowercase(), mime_type.to_string());
+    }
+    Ok(aliases)
+}
+
+/// Load the aliases file to be used by the mime-type matching, in addition to the built-in ones.
+/// It can only be loaded once.
+pub fn load_aliases_file(path: &Path) -> Result<()> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the aliases file {}", path.display()))?;
+    let aliases = parse_aliases(&content)
+        .with_context(|| format!("Failed to parse the aliases file {}", path.display()))?;
+    log::debug!("Loaded {} aliases from {}", aliases.len(), path.display());
+    if USER_ALIASES.set(aliases).is_err() {
+        bail!("The aliases file has been loaded already");
+    }
+    Ok(())
+}
+
+/// Return true if the two mime-types are the same after canonicalization.
+pub fn mime_type_matches(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b) || canonicalize_mime_type(a) == canonicalize_mime_type(b)
+}
+
+/// Return true if the two mime-types only differ in their parameters and one of them has none,
+/// e.g. `text/plain` and `text/plain;charset=utf-8`. The different parameters of both, like two
+/// charsets, don't match.
+pub fn mime_type_base_matches(a: &str, b: &str) -> bool {
+    let (a, b) = (canonicalize_mime_type(a), canonicalize_mime_type(b));
+    let (a_base, a_params) = a.split_once(';').unwrap_or((&a, ""));
+    let (b_base, b_params) = b.split_once(';').unwrap_or((&b, ""));
+    !a_base.is_empty() && a_base == b_base && (a_params.is_empty() || b_params.is_empty())
+}
+
+/// Return true if the mime-type would be treated as a text type.
+pub fn is_text_mime_type(mime_type: &str) -> bool {
+    try_any_text(&[mime_type.to_string()]).is_some()
+}
+
+// The magic numbers of the common binary formats
+const MAGIC_NUMBERS: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"II*\0", "image/tiff"),
+    (b"MM\0*", "image/tiff"),
+    (b"%PDF-", "application/pdf"),
+    (b"{\\rtf", "application/rtf"),
+];
+
+/// Escape the control characters of the mime-type for printing, e.g. `\x1b` for ESC, so a
+/// bogus mime-type offered by a misbehaving owner cannot mess up the terminal. The raw one is
+/// still used to request the content.
+pub fn escape_mime_type(mime_type: &str) -> Cow<'_, str> {
+    if !mime_type.chars().any(char::is_control) {
+        return Cow::Borrowed(mime_type);
+    }
+    let mut escaped = String::with_capacity(mime_type.len());
+    for c in mime_type.chars() {
+        match c {
+            c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:04x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Guess the mime-type from the beginning of the content, for the owners which don't offer any
+/// mime-type. UTF-8 text without control characters is `text/plain;charset=utf-8` or
+/// `text/html`, and the unknown binary content is `application/octet-stream`.
+pub fn sniff_mime(content: &[u8]) -> &'static str {
+    if let Some((_, mime_type)) = MAGIC_NUMBERS
+        .iter()
+        .find(|(magic, _)| content.starts_with(magic))
+    {
+        return mime_type;
+    }
+    let text = match std::str::from_utf8(content) {
+        Ok(text) => text,
+        // The content could be cut in the middle of a character
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&content[..e.valid_up_to()]).unwrap()
+        }
+        Err(_) => return "application/octet-stream",
+    };
+    if text
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t' | '\x0c'))
+    {
+        return "application/octet-stream";
+    }
+    let start = text.trim_start().to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        "text/html"
+    } else {
+        CANONICAL_TEXT_TYPE
+    }
+}
+
+/// Guess the mime-types to offer the content with, for `copy --detect-type`. Text is offered as
+/// all the text types like the one-shot mode does, others as the sniffed type.
+pub fn detect_content_type(content: &[u8]) -> Vec<String> {
+    match sniff_mime(content) {
+        CANONICAL_TEXT_TYPE => crate::protocol::TEXT_TYPES
+            .iter()
+            .map(|t| t.to_string())
+            .collect(),
+        mime_type => vec![mime_type.to_string()],
+    }
+}
+
 /// Based on the given preferred mime-type, and the mime-types supported by the current clipboard
 /// content, return the best match mime-type to paste.
 pub(super) fn decide_mime_type(preferred: &str, supported: &Vec<String>) -> Result<String> {
@@ -57,18 +237,183 @@ pub(super) fn decide_mime_type(preferred: &str, supported: &Vec<String>) -> Resu
             log::debug!("Use mime-type '{}'", ret);
             return Ok(ret);
         }
-    } else if let Some(ret) = supported.iter().find(|t| t.eq_ignore_ascii_case(preferred)) {
+    } else if glob_major_type(preferred).is_some() {
+        if let Some(ret) = matching_mime_types(preferred, supported).into_iter().next() {
+            log::debug!("Use mime-type '{}' for '{}'", ret, preferred);
+            return Ok(ret);
+        }
+    } else if let Some(ret) = supported
+        .iter()
+        .find(|t| t.eq_ignore_ascii_case(preferred))
+        .or_else(|| supported.iter().find(|t| mime_type_matches(t, preferred)))
+        .or_else(|| {
+            supported
+                .iter()
+                .find(|t| mime_type_base_matches(t, preferred))
+        })
+    {
         log::debug!("Use mime-type '{}'", ret);
         return Ok(ret.clone());
     }
 
-    bail!("No mime-type matches")
+    bail!(
+        "No mime-type matches '{}' in the supported list [{}]",
+        if preferred.is_empty() {
+            "text"
+        } else {
+            preferred
+        },
+        supported.join(", ")
+    )
+}
+
+/// The major type of the glob like `image/*`, or `None` if it is not a glob.
+fn glob_major_type(preferred: &str) -> Option<&str> {
+    preferred
+        .strip_suffix("/*")
+        .filter(|major| !major.is_empty() && !major.contains('/'))
+}
+
+/// All the offered mime-types matching the preferred one in the offered order. Only a glob like
+/// `image/*` can match more than one.
+pub(super) fn matching_mime_types(preferred: &str, supported: &Vec<String>) -> Vec<String> {
+    match glob_major_type(preferred) {
+        Some(major) => supported
+            .iter()
+            .filter(|t| {
+                t.split_once('/')
+                    .is_some_and(|(m, _)| m.eq_ignore_ascii_case(major))
+            })
+            .cloned()
+            .collect(),
+        None => decide_mime_type(preferred, supported).into_iter().collect(),
+    }
+}
+
+/// Same as `decide_mime_type`, but try the preferred mime-types in order. An empty list means
+/// any text type.
+pub(super) fn decide_mime_type_in_order(
+    preferred: &[String],
+    supported: &Vec<String>,
+) -> Result<String> {
+    if preferred.is_empty() {
+        return decide_mime_type("", supported);
+    }
+    for p in preferred {
+        if let Ok(ret) = decide_mime_type(p, supported) {
+            return Ok(ret);
+        }
+    }
+
+    bail!(
+        "No mime-type matches any of '{}' in the supported list [{}]",
+        preferred.join(", "),
+        supported.join(", ")
+    )
+}
+
+/// Parse the preferred mime-types in the HTTP Accept header style, e.g.
+/// `text/html;q=0.9,text/plain;q=0.8`, into a list ordered by the weights. The weight is 1 if
+/// it is not given, and the mime-types with weight 0 are dropped. A single mime-type without
+/// weight is returned as it is.
+pub fn parse_preferred_mime_types(preferred: &str) -> Result<Vec<String>> {
+    let mut weighted: Vec<(String, f32)> = vec![];
+    for entry in preferred.split(',') {
+        let entry = entry.trim();
+        let mut params: Vec<&str> = entry.split(';').collect();
+        let mut weight = 1.0;
+        // The first one is the mime-type itself
+        if let Some(pos) = params
+            .iter()
+            .skip(1)
+            .position(|p| p.trim_start().starts_with("q="))
+        {
+            let q = params.remove(pos + 1).trim_start()[2..].trim();
+            weight = q
+                .parse::<f32>()
+                .ok()
+                .filter(|w| (0.0..=1.0).contains(w))
+                .with_context(|| format!("Invalid weight '{q}' of '{entry}'"))?;
+        }
+        let mime_type = params.join(";");
+        if mime_type.is_empty() && preferred.contains(',') {
+            bail!("Empty mime-type in '{preferred}'");
+        }
+        if weight > 0.0 {
+            weighted.push((mime_type, weight));
+        }
+    }
+    // The sort is stable, so the mime-types with the same weight keep the given order
+    weighted.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    Ok(weighted.into_iter().map(|(t, _)| t).collect())
+}
+
+/// Return the mime-type at the given index of the supported list, bypassing the matching.
+pub(super) fn mime_type_by_index(index: usize, supported: &[String]) -> Result<String> {
+    match supported.get(index) {
+        Some(ret) => {
+            log::debug!("Use mime-type '{}' at index {}", ret, index);
+            Ok(ret.clone())
+        }
+        None => bail!(
+            "Index {} is out of range, {} mime-types are offered",
+            index,
+            supported.len()
+        ),
+    }
+}
+
+/// The mime-types reported by an offer, in the order of the reports. The same mime-type could be
+/// reported several times (`wl-paste -l` shows the same), only the first one is kept.
+#[cfg_attr(not(all(target_os = "linux", feature = "wayland")), allow(dead_code))]
+#[derive(Default)]
+pub(super) struct OfferedTypes {
+    types: Vec<String>,
+    // For checking the duplicates without going through the list, some offers have many types
+    seen: HashSet<String>,
+}
+
+#[cfg_attr(not(all(target_os = "linux", feature = "wayland")), allow(dead_code))]
+impl OfferedTypes {
+    /// Add the mime-type if it hasn't been reported, return false if it is a duplicate.
+    pub(super) fn push(&mut self, mime_type: String) -> bool {
+        if self.seen.contains(&mime_type) {
+            return false;
+        }
+        self.seen.insert(mime_type.clone());
+        self.types.push(mime_type);
+        true
+    }
+}
+
+impl Deref for OfferedTypes {
+    type Target = Vec<String>;
+
+    fn deref(&self) -> &Vec<String> {
+        &self.types
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_offered_types() {
+        let mut offered = OfferedTypes::default();
+        for i in 0..1000 {
+            offered.push(format!("type/{}", i % 10));
+        }
+        assert!(offered.push("text/plain".to_string()));
+        assert!(!offered.push("type/0".to_string()));
+        let expected: Vec<String> = (0..10)
+            .map(|i| format!("type/{i}"))
+            .chain(["text/plain".to_string()])
+            .collect();
+        assert_eq!(*offered, expected);
+    }
+
     #[test]
     fn test_empty_preferred() {
         // match a text type
@@ -87,7 +432,10 @@ mod tests {
             "",
             &vec!["image/webp".to_string(), "video/x-flv".to_string()],
         );
-        assert!(r.is_err());
+        assert_eq!(
+            r.unwrap_err().to_string(),
+            "No mime-type matches 'text' in the supported list [image/webp, video/x-flv]"
+        );
 
         // Match suffix
         let r = decide_mime_type(
@@ -115,6 +463,350 @@ mod tests {
         assert_eq!(r, "text/plain;charset=utf-8");
     }
 
+    #[test]
+    fn test_sniff_mime() {
+        assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(sniff_mime(b"\xff\xd8\xff\xe0\0\x10JFIF"), "image/jpeg");
+        assert_eq!(sniff_mime(b"GIF89a\x01\0"), "image/gif");
+        assert_eq!(sniff_mime(b"%PDF-1.7\n"), "application/pdf");
+
+        assert_eq!(sniff_mime(b"hello\tworld\n"), "text/plain;charset=utf-8");
+        assert_eq!(sniff_mime("中文".as_bytes()), "text/plain;charset=utf-8");
+        // Cut in the middle of a character
+        assert_eq!(
+            sniff_mime(&"中文".as_bytes()[..4]),
+            "text/plain;charset=utf-8"
+        );
+        assert_eq!(sniff_mime(b"\n <!DOCTYPE html><html>"), "text/html");
+        assert_eq!(sniff_mime(b""), "text/plain;charset=utf-8");
+
+        assert_eq!(sniff_mime(b"\0\x01\x02"), "application/octet-stream");
+        assert_eq!(sniff_mime(b"a\xffb"), "application/octet-stream");
+        assert_eq!(sniff_mime(b"\x1b[0m"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_detect_content_type() {
+        let detect = |content: &[u8]| detect_content_type(content).join(",");
+        assert_eq!(detect(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(detect(b"\xff\xd8\xff\xdb\0\x43"), "image/jpeg");
+        assert_eq!(detect(b"GIF87a\x01\0"), "image/gif");
+        assert_eq!(detect(b"GIF89a\x01\0"), "image/gif");
+        assert_eq!(detect(b"II*\0\x08\0"), "image/tiff");
+        assert_eq!(detect(b"MM\0*\0\0"), "image/tiff");
+        assert_eq!(detect(b"%PDF-1.4\n"), "application/pdf");
+        assert_eq!(detect(b"{\\rtf1\\ansi"), "application/rtf");
+        assert_eq!(detect(b"<!DOCTYPE html>\n<p>hi</p>"), "text/html");
+        assert_eq!(detect(b"\0\x01\x02binary"), "application/octet-stream");
+        // Text is offered with all the text types
+        assert_eq!(
+            detect_content_type("hello 中文\n".as_bytes()),
+            crate::protocol::TEXT_TYPES
+        );
+    }
+
+    #[test]
+    fn test_escape_mime_type() {
+        assert_eq!(escape_mime_type("text/plain"), "text/plain");
+        assert!(matches!(escape_mime_type("text/plain"), Cow::Borrowed(_)));
+        assert_eq!(escape_mime_type("text/\x1b[31mred"), "text/\\x1b[31mred");
+        assert_eq!(escape_mime_type("a\0b\nc\x7f"), "a\\x00b\\x0ac\\x7f");
+        assert_eq!(escape_mime_type("中\u{9b}"), "中\\u{009b}");
+    }
+
+    #[test]
+    fn test_glob_preferred() {
+        let supported = vec![
+            "TARGETS".to_string(),
+            "text/plain".to_string(),
+            "image/png".to_string(),
+            "IMAGE/tiff".to_string(),
+        ];
+        assert_eq!(
+            decide_mime_type("image/*", &supported).unwrap(),
+            "image/png"
+        );
+        assert_eq!(
+            matching_mime_types("image/*", &supported),
+            vec!["image/png", "IMAGE/tiff"]
+        );
+        assert_eq!(
+            matching_mime_types("image/png", &supported),
+            vec!["image/png"]
+        );
+        let e = decide_mime_type("audio/*", &supported)
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("'audio/*'"), "{e}");
+        assert!(e.contains("image/png"), "{e}");
+        assert!(matching_mime_types("audio/*", &supported).is_empty());
+        // Not a glob
+        assert!(decide_mime_type("/*", &supported).is_err());
+    }
+
+    #[test]
+    fn test_mime_type_by_index() {
+        let supported = vec!["TARGETS".to_string(), "text/plain".to_string()];
+        assert_eq!(mime_type_by_index(1, &supported).unwrap(), "text/plain");
+        assert_eq!(mime_type_by_index(0, &supported).unwrap(), "TARGETS");
+        assert!(mime_type_by_index(2, &supported).is_err());
+        assert!(mime_type_by_index(0, &[]).is_err());
+    }
+
+    #[test]
+    fn test_decide_mime_type_in_order() {
+        let supported = vec!["text/plain".to_string(), "text/html".to_string()];
+        let preferred = vec!["image/png".to_string(), "text/html".to_string()];
+        let r = decide_mime_type_in_order(&preferred, &supported).unwrap();
+        assert_eq!(r, "text/html");
+
+        let r = decide_mime_type_in_order(&[], &supported).unwrap();
+        assert_eq!(r, "text/plain");
+
+        let r = decide_mime_type_in_order(
+            &["image/png".to_string(), "image/gif".to_string()],
+            &supported,
+        );
+        assert_eq!(
+            r.unwrap_err().to_string(),
+            "No mime-type matches any of 'image/png, image/gif' in the supported list \
+             [text/plain, text/html]"
+        );
+    }
+
+    #[test]
+    fn test_parse_preferred_mime_types() {
+        // A single mime-type is kept as it is
+        let r = parse_preferred_mime_types("text/plain;charset=utf-8").unwrap();
+        assert_eq!(r, vec!["text/plain;charset=utf-8"]);
+        let r = parse_preferred_mime_types("").unwrap();
+        assert_eq!(r, vec![""]);
+
+        // Ordered by the weights
+        let r = parse_preferred_mime_types("text/plain;q=0.8,text/html;q=0.9").unwrap();
+        assert_eq!(r, vec!["text/html", "text/plain"]);
+        let r = parse_preferred_mime_types("text/plain;charset=utf-8;q=0.5, image/png").unwrap();
+        assert_eq!(r, vec!["image/png", "text/plain;charset=utf-8"]);
+
+        // Same weights keep the given order, weight 0 is dropped
+        let r = parse_preferred_mime_types("text/html,text/plain;q=1,image/png;q=0").unwrap();
+        assert_eq!(r, vec!["text/html", "text/plain"]);
+
+        // Malformed weights
+        assert!(parse_preferred_mime_types("text/html;q=abc").is_err());
+        assert!(parse_preferred_mime_types("text/html;q=1.5").is_err());
+        assert!(parse_preferred_mime_types("text/html;q=-1").is_err());
+        assert!(parse_preferred_mime_types("text/html;q=").is_err());
+        assert!(parse_preferred_mime_types("text/html,;q=0.5").is_err());
+    }
+
+    #[test]
+    fn test_parse_aliases() {
+        let aliases = parse_aliases("# comment\n\nmd = text/markdown\nPNG=image/png\n").unwrap();
+        assert_eq!(aliases.len(), 2);
+        assert_eq!(aliases["md"], "text/markdown");
+        assert_eq!(aliases["png"], "image/png");
+
+        let e = parse_aliases("md=text/markdown\nbad line\n").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Invalid alias 'bad line' at line 2, expected 'alias=mime/type'"
+        );
+        assert!(parse_aliases("md=").is_err());
+        assert!(parse_aliases("=text/markdown").is_err());
+    }
+
+    #[test]
+    fn test_load_aliases_file() {
+        // The only test loading the aliases, they can't be unloaded
+        let path = std::env::temp_dir().join(format!("richclip-aliases-{}", std::process::id()));
+        std::fs::write(&path, "md=text/markdown\nMyText=UTF8_STRING\n").unwrap();
+        load_aliases_file(&path).unwrap();
+        assert!(load_aliases_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(canonicalize_mime_type("MD"), "text/markdown");
+        assert_eq!(canonicalize_mime_type("mytext"), "text/plain;charset=utf-8");
+        let supported = vec!["text/html".to_string(), "text/markdown".to_string()];
+        assert_eq!(decide_mime_type("md", &supported).unwrap(), "text/markdown");
+        // Not an alias of anything else
+        assert_eq!(canonicalize_mime_type("text/html"), "text/html");
+    }
+
+    #[test]
+    fn test_canonicalize_mime_type() {
+        assert_eq!(canonicalize_mime_type("text/html"), "text/html");
+        assert_eq!(canonicalize_mime_type(" Text/HTML "), "text/html");
+        assert_eq!(
+            canonicalize_mime_type("text/plain; charset=UTF-8"),
+            "text/plain;charset=utf-8"
+        );
+        for alias in [
+            "TEXT",
+            "text",
+            "STRING",
+            "UTF8_STRING",
+            "utf8_string",
+            "CF_TEXT",
+            "CF_UNICODETEXT",
+        ] {
+            assert_eq!(canonicalize_mime_type(alias), "text/plain;charset=utf-8");
+        }
+        // Only the ones with explicit charset are the same as the aliases
+        assert_eq!(canonicalize_mime_type("text/plain"), "text/plain");
+        assert_eq!(canonicalize_mime_type(""), "");
+
+        assert!(mime_type_matches("UTF8_STRING", "text/plain;charset=utf-8"));
+        assert!(mime_type_matches("TEXT", "STRING"));
+        assert!(mime_type_matches(
+            "Text/Plain;Charset=UTF-8",
+            "CF_UNICODETEXT"
+        ));
+        assert!(!mime_type_matches("text/plain", "UTF8_STRING"));
+        assert!(!mime_type_matches("text/html", "text/plain"));
+    }
+
+    #[test]
+    fn test_mime_type_base_matches() {
+        assert!(mime_type_base_matches(
+            "text/plain",
+            "text/plain;charset=utf-8"
+        ));
+        assert!(mime_type_base_matches(
+            "Text/Plain; Charset=UTF-8",
+            "text/plain"
+        ));
+        assert!(mime_type_base_matches("text/html", "text/html"));
+        assert!(!mime_type_base_matches(
+            "text/plain;charset=utf-16",
+            "text/plain;charset=utf-8"
+        ));
+        assert!(!mime_type_base_matches(
+            "text/html",
+            "text/plain;charset=utf-8"
+        ));
+        assert!(!mime_type_base_matches("", ";charset=utf-8"));
+    }
+
+    #[test]
+    fn test_parameter_preferred() {
+        // Both directions
+        let supported = vec!["image/png".to_string(), "text/plain".to_string()];
+        let r = decide_mime_type("text/plain;charset=utf-8", &supported).unwrap();
+        assert_eq!(r, "text/plain");
+        let supported = vec![
+            "image/png".to_string(),
+            "text/plain;charset=utf-8".to_string(),
+        ];
+        let r = decide_mime_type("text/plain", &supported).unwrap();
+        assert_eq!(r, "text/plain;charset=utf-8");
+
+        // The exact match is preferred
+        let supported = vec![
+            "text/plain;charset=utf-8".to_string(),
+            "text/plain".to_string(),
+        ];
+        let r = decide_mime_type("text/plain", &supported).unwrap();
+        assert_eq!(r, "text/plain");
+        let r = decide_mime_type("text/plain;charset=utf-8", &supported).unwrap();
+        assert_eq!(r, "text/plain;charset=utf-8");
+
+        // The different charset is not matched
+        let supported = vec!["text/plain;charset=utf-16".to_string()];
+        assert!(decide_mime_type("text/plain;charset=utf-8", &supported).is_err());
+    }
+
+    #[test]
+    fn test_aliased_preferred() {
+        let supported = vec!["image/png".to_string(), "UTF8_STRING".to_string()];
+        let r = decide_mime_type("text/plain;charset=UTF-8", &supported).unwrap();
+        assert_eq!(r, "UTF8_STRING");
+        let r = decide_mime_type("CF_UNICODETEXT", &supported).unwrap();
+        assert_eq!(r, "UTF8_STRING");
+
+        // The exact match is preferred
+        let supported = vec!["TEXT".to_string(), "STRING".to_string()];
+        let r = decide_mime_type("STRING", &supported).unwrap();
+        assert_eq!(r, "STRING");
+    }
+
+    #[test]
+    fn test_supported_case_preserved() {
+        let supported = vec![
+            "TARGETS".to_string(),
+            "UTF8_STRING".to_string(),
+            "Text/HTML".to_string(),
+        ];
+        assert_eq!(
+            decide_mime_type("utf8_string", &supported).unwrap(),
+            "UTF8_STRING"
+        );
+        assert_eq!(decide_mime_type("targets", &supported).unwrap(), "TARGETS");
+        assert_eq!(decide_mime_type("", &supported).unwrap(), "UTF8_STRING");
+        assert_eq!(
+            decide_mime_type("text/html", &supported).unwrap(),
+            "Text/HTML"
+        );
+        assert_eq!(
+            matching_mime_types("TEXT/*", &supported),
+            vec!["Text/HTML".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_is_text_mime_type() {
+        assert!(is_text_mime_type("text/plain"));
+        assert!(is_text_mime_type("UTF8_STRING"));
+        assert!(is_text_mime_type("text/html"));
+        assert!(is_text_mime_type("application/postscript"));
+        assert!(is_text_mime_type("application/json"));
+        assert!(is_text_mime_type("Application/JSON"));
+        assert!(!is_text_mime_type("image/png"));
+        assert!(!is_text_mime_type(""));
+    }
+
+    #[test]
+    fn test_text_suffix() {
+        assert!(is_text_mime_type("application/xml"));
+        assert!(is_text_mime_type("image/svg+xml"));
+        assert!(is_text_mime_type("Image/SVG+XML"));
+        assert!(is_text_mime_type("application/x-yaml"));
+        assert!(is_text_mime_type("application/javascript"));
+        assert!(!is_text_mime_type("application/fooxml"));
+        assert!(!is_text_mime_type("application/vnd.fooyaml"));
+        assert!(!is_text_mime_type("xml"));
+
+        // The made-up type is not picked as text
+        let supported = vec![
+            "application/fooxml".to_string(),
+            "image/svg+xml".to_string(),
+        ];
+        assert_eq!(decide_mime_type("", &supported).unwrap(), "image/svg+xml");
+        let supported = vec!["application/fooxml".to_string()];
+        assert!(decide_mime_type("", &supported).is_err());
+    }
+
+    #[test]
+    fn test_json_preferred() {
+        let supported = vec!["image/png".to_string(), "application/json".to_string()];
+        assert_eq!(
+            decide_mime_type("", &supported).unwrap(),
+            "application/json"
+        );
+        assert_eq!(
+            decide_mime_type("text", &supported).unwrap(),
+            "application/json"
+        );
+        assert_eq!(
+            decide_mime_type("application/json", &supported).unwrap(),
+            "application/json"
+        );
+
+        // The plain text is still preferred
+        let supported = vec!["application/json".to_string(), "text/plain".to_string()];
+        assert_eq!(decide_mime_type("", &supported).unwrap(), "text/plain");
+    }
+
     #[test]
     fn test_exact_preferred() {
         // match a text type
@@ -1,60 +1,1019 @@
 #[cfg(target_os = "macos")]
 mod mac;
-mod mime_type;
-#[cfg(target_os = "linux")]
+pub mod mime_type;
+#[cfg(all(target_os = "linux", feature = "wayland"))]
 mod wayland;
+#[cfg(all(target_os = "linux", feature = "core-wayland"))]
+mod wayland_core;
+#[cfg(feature = "x11")]
 mod x;
 
 use super::protocol::SourceData;
-use anyhow::Result;
-#[cfg(target_os = "linux")]
-use anyhow::bail;
+use crate::SharedBuffer;
+use crate::transform::CountingWriter;
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
 use std::io::Write;
+#[cfg(all(target_os = "linux", feature = "wayland"))]
+use std::os::fd::AsRawFd;
+#[cfg(any(all(target_os = "linux", feature = "wayland"), feature = "x11"))]
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+/// Wrap the output writer based on the mime-type of the content to be pasted.
+pub type WriterWrapper = Box<dyn FnOnce(&str, Box<dyn Write>) -> Box<dyn Write>>;
+
+/// Called on every clipboard change when watching.
+pub type ChangeHandler = Box<dyn FnMut() -> Result<()>>;
+
+/// What a backend supports, so the callers can tell it before trying an operation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capabilities {
+    /// The primary selection (middle-click paste) can be copied to and pasted from.
+    pub primary_selection: bool,
+    /// The clipboard changes can be watched with [`ClipBackend::watch`].
+    pub watch: bool,
+    /// Any mime-types can be transferred, including the images, not only text.
+    pub image_types: bool,
+    /// The large content is transferred in chunks with the X INCR protocol.
+    pub incr: bool,
+    /// Multiple items can be copied at once, like the file URLs on macOS.
+    pub multiple_items: bool,
+}
 
 pub trait ClipBackend {
+    fn name(&self) -> &'static str;
+    /// The features supported by this backend. It may connect to the display server to check
+    /// the optional protocols.
+    fn capabilities(&self) -> Capabilities;
     fn copy(&self, config: CopyConfig) -> Result<()>;
     fn paste(&self, config: PasteConfig) -> Result<()>;
+    /// Block and call `config.notify_change()` on every clipboard change, until it returns false.
+    fn watch(&self, _config: WatchConfig) -> Result<()> {
+        bail!("Watching is not supported by the {} backend", self.name())
+    }
+    /// The offered mime-types and their content sizes in bytes, `None` if the size cannot be
+    /// told. By default every content is pasted once to measure it.
+    fn content_sizes(&self, use_primary: bool) -> Result<Vec<(String, Option<u64>)>> {
+        measure_content_sizes(self, use_primary)
+    }
 }
 
 pub struct PasteConfig {
     // Only list mime-types
     pub list_types_only: bool,
+    // List the raw types which are not mapped to mime-types as well. Only used by Mac.
+    pub list_all_types: bool,
+    // Prefix the listed mime-types with their indexes
+    pub list_numbered: bool,
+    // End the last listed mime-type with a newline as well
+    pub list_final_newline: bool,
+    // List the mime-types as they are, separated by NUL, for parsing the list instead of printing
+    pub list_raw: bool,
     pub use_primary: bool,
-    pub expected_mime_type: String,
+    // The preferred mime-types in order, empty means any text type
+    pub expected_mime_types: Vec<String>,
+    // Paste the mime-type at this index of the offered list, instead of the expected_mime_types
+    pub target_index: Option<usize>,
+    // Skip the TARGETS negotiation and request the expected mime-type directly. Only used by X.
+    pub x_raw: bool,
+    // Guess the mime-type from the content, if the owner doesn't offer any. Only used by X.
+    pub x_sniff: bool,
+    // Request this target atom directly, for debugging the owners with broken atom names. Only
+    // used by X.
+    pub x_target_atom: Option<u32>,
+    // Print the window owning the selection instead of pasting. Only used by X.
+    pub x_owner_info: bool,
+    // Skip the content which is already in the clipboard, and paste the next one. Only used by
+    // Wayland with wlr-data-control.
+    pub wayland_wait_for_change: bool,
+    // List the mime-types of both the clipboard and the primary selection. Only used by Wayland
+    // with wlr-data-control.
+    pub wayland_all_selections: bool,
+    // Fail if the content is not received in time. Not used by macOS.
+    pub timeout: Option<Duration>,
+    // Fail if no new chunk is received in time during an INCR transfer. Only used by X.
+    pub x_chunk_timeout: Option<Duration>,
+    // How often to check the timeout when waiting for the X events
+    pub x_poll_interval: Duration,
     pub writer: Box<dyn Write>,
+    // Transform the output, e.g. escaping, once the mime-type of the content is decided
+    pub wrap_writer: Option<WriterWrapper>,
+}
+
+impl PasteConfig {
+    /// Must be called by the backends once the mime-type of the content is decided, and before
+    /// any content is written to the writer. The writer must be flushed after all content is
+    /// written, since the wrapped writer may buffer the output.
+    fn prepare_writer(&mut self, mime_type: &str) {
+        if let Some(wrap_writer) = self.wrap_writer.take() {
+            let writer = std::mem::replace(&mut self.writer, Box::new(std::io::sink()));
+            self.writer = wrap_writer(mime_type, writer);
+        }
+    }
+
+    /// Same as `prepare_writer`, but the mime-type is guessed from the beginning of the content.
+    fn prepare_writer_sniffed(&mut self) {
+        if let Some(wrap_writer) = self.wrap_writer.take() {
+            let writer = std::mem::replace(&mut self.writer, Box::new(std::io::sink()));
+            self.writer = Box::new(SniffWriter {
+                wrap_writer: Some(wrap_writer),
+                writer,
+                buffer: vec![],
+            });
+        }
+    }
+
+    /// Paste the expected mime-type as it is, for probing the clipboard content.
+    pub(crate) fn probe(
+        use_primary: bool,
+        expected_mime_types: Vec<String>,
+        writer: Box<dyn Write>,
+    ) -> Self {
+        PasteConfig {
+            list_types_only: false,
+            list_all_types: false,
+            list_numbered: false,
+            list_final_newline: true,
+            list_raw: false,
+            use_primary,
+            expected_mime_types,
+            target_index: None,
+            x_raw: false,
+            x_sniff: false,
+            x_target_atom: None,
+            x_owner_info: false,
+            wayland_wait_for_change: false,
+            wayland_all_selections: false,
+            timeout: None,
+            x_chunk_timeout: None,
+            x_poll_interval: Duration::from_millis(50),
+            writer,
+            wrap_writer: None,
+        }
+    }
+
+    /// The error for none of the expected mime-types is offered.
+    #[cfg_attr(not(any(target_os = "macos", feature = "wayland")), allow(dead_code))]
+    fn not_offered_error(&self) -> anyhow::Error {
+        self.not_offered().into()
+    }
+
+    #[cfg_attr(
+        not(any(target_os = "macos", feature = "wayland", feature = "x11")),
+        allow(dead_code)
+    )]
+    fn not_offered(&self) -> PasteError {
+        let types = if self.expected_mime_types.is_empty() {
+            "text".to_string()
+        } else {
+            self.expected_mime_types.join(",")
+        };
+        PasteError::NotOffered(types)
+    }
+
+    /// Some apps offer the content without any mime-type, which cannot be pasted. Return a
+    /// specific error for it, unless only listing the mime-types.
+    #[cfg_attr(not(feature = "wayland"), allow(dead_code))]
+    fn check_offered_types(&self, types: &[String]) -> Result<()> {
+        if types.is_empty() && !self.list_types_only {
+            bail!("The clipboard content is offered without any mime-type");
+        }
+        Ok(())
+    }
+
+    /// Write the mime-types for `--list-types`, with the control characters escaped. Written as they
+    /// are if `list_raw` is set.
+    fn write_type_list(&mut self, types: &[String]) -> Result<()> {
+        if self.list_raw {
+            return self
+                .writer
+                .write_all(types.join("\0").as_bytes())
+                .context("Failed to write to the output");
+        }
+        let lines: Vec<String> = types
+            .iter()
+            .map(|t| mime_type::escape_mime_type(t).into_owned())
+            .collect();
+        self.write_list_lines(lines)
+    }
+
+    /// Write the mime-types of several selections for `--list-types`, every line is prefixed with
+    /// the name of its selection and a tab. The numbers start from 0 for each selection.
+    #[cfg_attr(not(feature = "wayland"), allow(dead_code))]
+    fn write_labeled_type_lists(&mut self, lists: &[(&str, &[String])]) -> Result<()> {
+        let mut lines = vec![];
+        for (label, types) in lists {
+            let types = types
+                .iter()
+                .map(|t| mime_type::escape_mime_type(t).into_owned())
+                .collect();
+            lines.extend(
+                self.numbered_lines(types)
+                    .into_iter()
+                    .map(|line| format!("{label}\t{line}")),
+            );
+        }
+        self.write_lines(lines)
+    }
+
+    /// Write the lines of `--list-types`, numbered if needed.
+    fn write_list_lines(&mut self, lines: Vec<String>) -> Result<()> {
+        let lines = self.numbered_lines(lines);
+        self.write_lines(lines)
+    }
+
+    fn numbered_lines(&self, lines: Vec<String>) -> Vec<String> {
+        lines
+            .into_iter()
+            .enumerate()
+            .map(|(i, line)| {
+                if self.list_numbered {
+                    format!("{}\t{}", i, line)
+                } else {
+                    line
+                }
+            })
+            .collect()
+    }
+
+    fn write_lines(&mut self, lines: Vec<String>) -> Result<()> {
+        let mut output = lines.join("\n");
+        if self.list_final_newline && !output.is_empty() {
+            output.push('\n');
+        }
+        self.writer
+            .write_all(output.as_bytes())
+            .context("Failed to write to the output")
+    }
+}
+
+// Enough for the magic numbers and telling the text from the binary
+const SNIFF_LEN: usize = 512;
+
+/// Buffer the beginning of the content to guess the mime-type, then wrap the writer with it.
+struct SniffWriter {
+    wrap_writer: Option<WriterWrapper>,
+    writer: Box<dyn Write>,
+    buffer: Vec<u8>,
+}
+
+impl SniffWriter {
+    fn wrap(&mut self) -> std::io::Result<()> {
+        if let Some(wrap_writer) = self.wrap_writer.take() {
+            let mime_type = mime_type::sniff_mime(&self.buffer);
+            log::debug!("The sniffed mime-type is '{mime_type}'");
+            let writer = std::mem::replace(&mut self.writer, Box::new(std::io::sink()));
+            self.writer = wrap_writer(mime_type, writer);
+            let buffer = std::mem::take(&mut self.buffer);
+            self.writer.write_all(&buffer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for SniffWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.wrap_writer.is_none() {
+            return self.writer.write(buf);
+        }
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= SNIFF_LEN {
+            self.wrap()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.wrap()?;
+        self.writer.flush()
+    }
 }
 
 pub struct CopyConfig {
     pub use_primary: bool,
+    // Own the clipboard and the primary selection together, `use_primary` is ignored. Not used
+    // by Mac.
+    pub both_selections: bool,
     pub source_data: Box<dyn SourceData>,
     // For testing X INCR mode
     pub x_chunk_size: usize,
+    // Exit after the content has been pasted once. Not used by Mac.
+    pub serve_once: bool,
+    // Stop serving the content after the duration. Not used by Mac.
+    pub expire: Option<Duration>,
+    // Stop serving the content if it is not requested for the duration. Not used by Mac.
+    pub idle_timeout: Option<Duration>,
+    // Clear the clipboard and the content in memory when expired or idle for too long
+    pub clear_on_expire: bool,
+    // Take the primary selection back when others select something. Only used by X.
+    pub x_persist_primary: bool,
+    // Fail if another window owns the selection right after taking it over. Only used by X.
+    pub x_exclusive: bool,
+    // Add the types to the pasteboard without clearing the existing ones. Only used by Mac.
+    pub mac_no_clear: bool,
+}
+
+/// When to stop serving the copied content, `expire` after the start or `idle_timeout` after the
+/// last request, whichever comes first.
+struct ServeDeadline {
+    expire_at: Option<Instant>,
+    idle_timeout: Option<Duration>,
+}
+
+impl ServeDeadline {
+    fn new(config: &CopyConfig, start_time: Instant) -> Self {
+        ServeDeadline {
+            expire_at: config.expire.map(|expire| start_time + expire),
+            idle_timeout: config.idle_timeout,
+        }
+    }
+
+    fn at(&self, last_request: Instant) -> Option<Instant> {
+        let idle_at = self.idle_timeout.map(|timeout| last_request + timeout);
+        [self.expire_at, idle_at].into_iter().flatten().min()
+    }
+}
+
+pub struct WatchConfig {
+    pub use_primary: bool,
+    // Stop watching after this number of changes. Watch forever if it is None.
+    pub count: Option<usize>,
+    // How often to check for changes, for the platforms without change events. Only used by Mac.
+    pub poll_interval: Duration,
+    pub on_change: ChangeHandler,
+}
+
+impl WatchConfig {
+    /// Must be called by the backends on every change, except the content which is already in
+    /// the clipboard when the watching starts. Return false if the watching should stop.
+    fn notify_change(&mut self) -> Result<bool> {
+        (self.on_change)()?;
+        match self.count.as_mut() {
+            Some(count) => {
+                *count = count.saturating_sub(1);
+                Ok(*count > 0)
+            }
+            None => Ok(true),
+        }
+    }
 }
 
 #[cfg(target_os = "macos")]
 use mac::MacBackend;
 
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", feature = "wayland"))]
 pub use wayland::WaylandBackend;
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", feature = "core-wayland"))]
+pub use wayland_core::CoreWaylandBackend;
+#[cfg(all(target_os = "linux", feature = "x11"))]
 pub use x::XBackend;
 
+/// Returned by [`create_backend`] if none of the backends can be used. It can be downcast from
+/// the `anyhow::Error` to tell it from the other errors.
+#[derive(Debug)]
+pub struct BackendUnavailable {
+    /// Why each backend was not picked, in the order of checking
+    pub reasons: Vec<String>,
+}
+
+impl std::fmt::Display for BackendUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not decide the clip backend")?;
+        if !self.reasons.is_empty() {
+            write!(f, ": {}", self.reasons.join(", "))?;
+        }
+        write!(
+            f,
+            ". Set WAYLAND_DISPLAY or DISPLAY for the running display server, \
+            or run 'richclip doctor' for details"
+        )
+    }
+}
+
+impl std::error::Error for BackendUnavailable {}
+
+/// Returned by the backends when the content cannot be pasted, so the scripts can tell whether
+/// the mime-type negotiation failed, or the owner failed to send the content. It can be downcast
+/// from the `anyhow::Error` like [`BackendUnavailable`].
+#[derive(Debug)]
+pub enum PasteError {
+    /// None of the requested mime-types is offered
+    NotOffered(String),
+    /// The mime-type is offered, but the owner doesn't send the content for it
+    NoContent(String),
+    /// Cancelled by Ctrl-C, the content received so far has been written
+    Cancelled,
+}
+
+impl PasteError {
+    /// The exit code of the command for the error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PasteError::NotOffered(_) => 3,
+            PasteError::NoContent(_) => 4,
+            PasteError::Cancelled => 5,
+        }
+    }
+}
+
+impl std::fmt::Display for PasteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasteError::NotOffered(t) => write!(f, "The clipboard doesn't offer mime-type {t}"),
+            PasteError::NoContent(t) => {
+                write!(
+                    f,
+                    "The clipboard offers mime-type {t}, but no content is sent"
+                )
+            }
+            PasteError::Cancelled => write!(f, "The paste is cancelled, the content is incomplete"),
+        }
+    }
+}
+
+impl std::error::Error for PasteError {}
+
+/// Set by the SIGINT handler of [`cancel_paste_on_sigint`].
+static PASTE_CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Make Ctrl-C cancel the paste instead of killing the process. The content received so far is
+/// flushed, and the paste fails with [`PasteError::Cancelled`].
+#[cfg(target_os = "linux")]
+pub fn cancel_paste_on_sigint() -> Result<()> {
+    extern "C" fn on_sigint(_: libc::c_int) {
+        PASTE_CANCELLED.store(true, Ordering::SeqCst);
+    }
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigint as *const () as libc::sighandler_t;
+        // Without SA_RESTART, the blocking reads and polls are interrupted to check the flag
+        action.sa_flags = 0;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to install the SIGINT handler");
+        }
+    }
+    Ok(())
+}
+
+fn paste_cancelled() -> bool {
+    PASTE_CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Flush the partial content to the output, and return the error for the cancelled paste.
+fn paste_cancelled_error(writer: &mut dyn Write) -> anyhow::Error {
+    if let Err(e) = writer.flush() {
+        log::warn!("Failed to flush the partial content: {e}");
+    }
+    PasteError::Cancelled.into()
+}
+
+/// Which content to paste when several offered mime-types match the expected one.
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+pub enum PreferSize {
+    /// The largest content, e.g. the image of the best quality
+    Largest,
+    /// The smallest content, e.g. for the transfer
+    Smallest,
+}
+
+/// Pick the offered mime-type of the largest or the smallest content, among the ones matching the
+/// first expected mime-type which is offered, e.g. all the image types for `image/*`. The content
+/// of every candidate is pasted once to get its size, which costs an extra transfer each. `None`
+/// if no offered mime-type matches.
+pub fn decide_mime_type_by_size(
+    backend: &dyn ClipBackend,
+    use_primary: bool,
+    expected_mime_types: &[String],
+    prefer: PreferSize,
+) -> Result<Option<String>> {
+    let offered = offered_mime_types(backend, use_primary)?;
+
+    let expected = if expected_mime_types.is_empty() {
+        vec!["".to_string()]
+    } else {
+        expected_mime_types.to_vec()
+    };
+    let Some(candidates) = expected
+        .iter()
+        .map(|t| mime_type::matching_mime_types(t, &offered))
+        .find(|candidates| !candidates.is_empty())
+    else {
+        return Ok(None);
+    };
+    if candidates.len() == 1 {
+        return Ok(candidates.into_iter().next());
+    }
+
+    let mut sizes: Vec<(String, u64)> = vec![];
+    for candidate in candidates {
+        let counter = CountingWriter::default();
+        let config = PasteConfig::probe(
+            use_primary,
+            vec![candidate.clone()],
+            Box::new(counter.clone()),
+        );
+        if let Err(e) = backend.paste(config) {
+            log::warn!("Skip '{candidate}' which cannot be pasted: {e:#}");
+            continue;
+        }
+        log::debug!("'{candidate}' has {} bytes", counter.count());
+        sizes.push((candidate, counter.count()));
+    }
+    // The first offered one wins the tie
+    let picked = match prefer {
+        PreferSize::Largest => sizes.into_iter().rev().max_by_key(|(_, size)| *size),
+        PreferSize::Smallest => sizes.into_iter().min_by_key(|(_, size)| *size),
+    };
+    Ok(picked.map(|(mime_type, _)| mime_type))
+}
+
+/// List the offered mime-types through the paste of the backend. They are not escaped like the
+/// ones of `--list-types`, so they can be used to request the content.
+pub(crate) fn offered_mime_types<B: ClipBackend + ?Sized>(
+    backend: &B,
+    use_primary: bool,
+) -> Result<Vec<String>> {
+    let buffer = SharedBuffer::default();
+    let mut config = PasteConfig::probe(use_primary, vec![], Box::new(buffer.clone()));
+    config.list_types_only = true;
+    config.list_raw = true;
+    backend
+        .paste(config)
+        .context("Failed to list the mime-types")?;
+    Ok(String::from_utf8_lossy(&buffer.take())
+        .split('\0')
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Paste every offered mime-type by its index to measure the size. The default of
+/// [`ClipBackend::content_sizes`].
+fn measure_content_sizes<B: ClipBackend + ?Sized>(
+    backend: &B,
+    use_primary: bool,
+) -> Result<Vec<(String, Option<u64>)>> {
+    let offered = offered_mime_types(backend, use_primary)?;
+    let mut sizes = vec![];
+    for (index, mime_type) in offered.into_iter().enumerate() {
+        let counter = CountingWriter::default();
+        let mut config = PasteConfig::probe(use_primary, vec![], Box::new(counter.clone()));
+        config.target_index = Some(index);
+        let size = match backend.paste(config) {
+            Ok(()) => Some(counter.count()),
+            Err(e) => {
+                log::debug!("Failed to measure '{mime_type}': {e:#}");
+                None
+            }
+        };
+        sizes.push((mime_type, size));
+    }
+    Ok(sizes)
+}
+
+/// Write the offered mime-types with their content sizes for `--list-types --with-sizes`, like
+/// `text/plain\t1234`. The size is `?` if it cannot be told.
+pub fn write_type_sizes(backend: &dyn ClipBackend, mut config: PasteConfig) -> Result<()> {
+    let lines = backend
+        .content_sizes(config.use_primary)?
+        .into_iter()
+        .map(|(mime_type, size)| {
+            let size = size.map_or("?".to_string(), |size| size.to_string());
+            format!("{}\t{size}", mime_type::escape_mime_type(&mime_type))
+        })
+        .collect();
+    config.write_list_lines(lines)
+}
+
 #[cfg(target_os = "linux")]
 pub fn create_backend() -> Result<Box<dyn ClipBackend>> {
+    let backend = select_backend(
+        std::env::var("RICHCLIP_PREFER").ok().as_deref(),
+        std::env::var("WAYLAND_DISPLAY").is_ok(),
+        std::env::var("DISPLAY").is_ok(),
+    )?;
+    log::debug!("Use the {} backend", backend.name());
+    Ok(backend)
+}
+
+#[cfg(target_os = "linux")]
+fn select_backend(
+    prefer: Option<&str>,
+    has_wayland_display: bool,
+    has_display: bool,
+) -> Result<Box<dyn ClipBackend>> {
+    let mut reasons = vec![];
+    // With 'RICHCLIP_PREFER=x11', try X11 first, e.g. to use XWayland. The availability is still
+    // detected, so Wayland is used if X11 is not available.
+    let prefer_x11 = prefer_x11(prefer);
+    if prefer_x11 {
+        if let Some(backend) = try_x_backend(has_display, &mut reasons) {
+            return Ok(backend);
+        }
+        log::debug!("X11 is preferred but not available");
+    }
+
     // Try Wayland first, but only if the required protocol is available
-    if std::env::var("WAYLAND_DISPLAY").is_ok() {
+    if let Some(backend) = try_wayland_backend(has_wayland_display, &mut reasons) {
+        return Ok(backend);
+    }
+
+    // Fall back to X11
+    if !prefer_x11 {
+        log::debug!("Trying X11 fallback");
+        if let Some(backend) = try_x_backend(has_display, &mut reasons) {
+            return Ok(backend);
+        }
+    }
+
+    Err(BackendUnavailable { reasons }.into())
+}
+
+/// Parse the value of `RICHCLIP_PREFER`.
+#[cfg(target_os = "linux")]
+fn prefer_x11(prefer: Option<&str>) -> bool {
+    match prefer {
+        Some(p) if p.eq_ignore_ascii_case("x11") || p.eq_ignore_ascii_case("x") => true,
+        Some(p) if p.is_empty() || p.eq_ignore_ascii_case("wayland") => false,
+        Some(p) => {
+            log::warn!("Unknown RICHCLIP_PREFER value '{p}', expected 'x11' or 'wayland'");
+            false
+        }
+        None => false,
+    }
+}
+
+/// Use the primary selection by default instead of the clipboard, if the command line doesn't
+/// tell which one to use.
+#[cfg(target_os = "linux")]
+const DEFAULT_SELECTION_ENV: &str = "RICHCLIP_DEFAULT_SELECTION";
+
+/// Return true if the primary selection is used by default, according to
+/// `RICHCLIP_DEFAULT_SELECTION`.
+#[cfg(target_os = "linux")]
+pub fn default_use_primary() -> bool {
+    primary_by_default(std::env::var(DEFAULT_SELECTION_ENV).ok().as_deref())
+}
+
+/// Parse the value of `RICHCLIP_DEFAULT_SELECTION`.
+#[cfg(target_os = "linux")]
+fn primary_by_default(selection: Option<&str>) -> bool {
+    match selection {
+        Some(s) if s.eq_ignore_ascii_case("primary") => true,
+        Some(s) if s.is_empty() || s.eq_ignore_ascii_case("clipboard") => false,
+        Some(s) => {
+            log::warn!(
+                "Unknown {DEFAULT_SELECTION_ENV} value '{s}', expected 'primary' or 'clipboard'"
+            );
+            false
+        }
+        None => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn try_wayland_backend(
+    has_wayland_display: bool,
+    reasons: &mut Vec<String>,
+) -> Option<Box<dyn ClipBackend>> {
+    if !has_wayland_display {
+        reasons.push("WAYLAND_DISPLAY is not set".to_string());
+        return None;
+    }
+    #[cfg(feature = "wayland")]
+    {
         if wayland::test_protocol_available() {
-            return Ok(Box::new(WaylandBackend {}));
+            return Some(Box::new(WaylandBackend {}));
         }
-        log::debug!("Wayland wlr_data_control protocol not available, trying X11 fallback");
+        log::debug!("Wayland wlr_data_control protocol not available");
+        #[cfg(feature = "core-wayland")]
+        if wayland_core::test_protocol_available() {
+            log::debug!("Use the core Wayland protocol as the fallback");
+            return Some(Box::new(CoreWaylandBackend {}));
+        }
+        reasons.push("the Wayland compositor doesn't support the clipboard protocols".to_string());
+        None
+    }
+    #[cfg(not(feature = "wayland"))]
+    {
+        reasons.push(
+            "WAYLAND_DISPLAY is set, but richclip is built without the wayland feature".to_string(),
+        );
+        None
     }
+}
 
-    // Fall back to X11
-    if std::env::var("DISPLAY").is_ok() {
-        return Ok(Box::new(XBackend {}));
+#[cfg(target_os = "linux")]
+fn try_x_backend(has_display: bool, reasons: &mut Vec<String>) -> Option<Box<dyn ClipBackend>> {
+    if !has_display {
+        reasons.push("DISPLAY is not set".to_string());
+        return None;
+    }
+    #[cfg(feature = "x11")]
+    return Some(Box::new(XBackend {}));
+    #[cfg(not(feature = "x11"))]
+    {
+        reasons.push("DISPLAY is set, but richclip is built without the x11 feature".to_string());
+        None
+    }
+}
+
+/// How many more times to try connecting to the display server, set by `--connect-retries`.
+static CONNECT_RETRIES: AtomicU32 = AtomicU32::new(0);
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(100);
+const CONNECT_RETRY_MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// Retry connecting to the display server for the given times before failing, e.g. when richclip
+/// is started before the server is ready.
+pub fn set_connect_retries(retries: u32) {
+    CONNECT_RETRIES.store(retries, Ordering::Relaxed);
+}
+
+/// Connect to the display server, with the retries of `--connect-retries`.
+#[cfg(any(all(target_os = "linux", feature = "wayland"), feature = "x11"))]
+fn connect_with_retries<T>(connect: impl FnMut() -> Result<T>) -> Result<T> {
+    retry_with_backoff(
+        CONNECT_RETRIES.load(Ordering::Relaxed),
+        CONNECT_RETRY_DELAY,
+        connect,
+    )
+}
+
+/// Call `f` until it succeeds, up to `retries` more times. The delay between the attempts starts
+/// from `delay` and doubles every time, up to `CONNECT_RETRY_MAX_DELAY`.
+#[cfg(any(all(target_os = "linux", feature = "wayland"), feature = "x11"))]
+fn retry_with_backoff<T>(
+    retries: u32,
+    mut delay: Duration,
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Ok(r) => return Ok(r),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                log::debug!("Attempt {attempt} failed: {e:#}, retry in {delay:?}");
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(CONNECT_RETRY_MAX_DELAY);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The internals measured by the benchmarks, with the `bench` feature.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    use anyhow::Result;
+
+    pub fn decide_mime_type(preferred: &str, supported: &Vec<String>) -> Result<String> {
+        super::mime_type::decide_mime_type(preferred, supported)
+    }
+
+    /// The byte ranges of the chunks which the X selection owner sends the content of the given
+    /// length in with INCR, the last one is empty.
+    #[cfg(feature = "x11")]
+    pub fn x_incr_chunks(
+        len: usize,
+        chunk_size: usize,
+    ) -> impl Iterator<Item = std::ops::Range<usize>> {
+        super::x::incr_chunks(len, chunk_size)
+    }
+}
+
+/// Override the INCR chunk size in bytes of the X selection owner.
+#[cfg(feature = "x11")]
+const X_CHUNK_SIZE_ENV: &str = "RICHCLIP_X_CHUNK_SIZE";
+/// The buffer size in bytes to read the pasted content from the Wayland pipe.
+#[cfg(all(target_os = "linux", feature = "wayland"))]
+const PASTE_BUFFER_ENV: &str = "RICHCLIP_PASTE_BUFFER";
+
+/// Read a size in bytes from the environment variable, for tuning and benchmarking. `None` if it
+/// is unset or invalid, then the default is used.
+#[cfg(any(all(target_os = "linux", feature = "wayland"), feature = "x11"))]
+fn size_from_env(name: &str) -> Option<usize> {
+    let value = std::env::var(name).ok()?;
+    let size = parse_size(&value);
+    if size.is_none() {
+        log::warn!("Invalid {name} value '{value}', use the default");
+    }
+    size
+}
+
+/// Parse a positive size in bytes, `None` if it is invalid.
+#[cfg(any(all(target_os = "linux", feature = "wayland"), feature = "x11"))]
+fn parse_size(value: &str) -> Option<usize> {
+    value.trim().parse::<usize>().ok().filter(|size| *size > 0)
+}
+
+/// Copy the pasted content from the pipe, with the buffer size of `RICHCLIP_PASTE_BUFFER` if set.
+/// Fail with [`PasteError::Cancelled`] if Ctrl-C is pressed in the middle. With the deadline, fail
+/// if the content is not completely received in time, e.g. the clipboard owner neither writes to
+/// the pipe nor closes it.
+#[cfg(all(target_os = "linux", feature = "wayland"))]
+fn copy_from_pipe(
+    reader: impl std::io::Read + AsRawFd,
+    writer: &mut dyn Write,
+    deadline: Option<Instant>,
+) -> Result<u64> {
+    let mut reader = PipeReader {
+        reader,
+        deadline,
+        received: 0,
+    };
+    let r = match size_from_env(PASTE_BUFFER_ENV) {
+        Some(size) => {
+            log::debug!("Read the pipe with buffer size {size}");
+            // io::copy reads into the buffer of BufReader directly
+            std::io::copy(
+                &mut std::io::BufReader::with_capacity(size, &mut reader),
+                writer,
+            )
+        }
+        None => std::io::copy(&mut reader, writer),
+    };
+    match r {
+        Err(_) if paste_cancelled() => Err(paste_cancelled_error(writer)),
+        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => bail!(
+            "Timed out waiting for the clipboard owner to send the content, {} bytes received",
+            reader.received
+        ),
+        r => Ok(r?),
     }
+}
 
-    bail!("Could not decide the clip backend");
+/// Stop reading once the paste is cancelled or the deadline is reached. `io::copy` retries the
+/// reads interrupted by the signal, so the interruption has to be turned into another error.
+#[cfg(all(target_os = "linux", feature = "wayland"))]
+struct PipeReader<R> {
+    reader: R,
+    deadline: Option<Instant>,
+    received: u64,
+}
+
+#[cfg(all(target_os = "linux", feature = "wayland"))]
+impl<R: std::io::Read + AsRawFd> std::io::Read for PipeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if paste_cancelled() {
+            return Err(std::io::Error::other("The paste is cancelled"));
+        }
+        if let Some(deadline) = self.deadline {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if !wait_fd_readable(self.reader.as_raw_fd(), timeout).map_err(std::io::Error::other)? {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            // Woken up by Ctrl-C, don't block in the read
+            if paste_cancelled() {
+                return Err(std::io::Error::other("The paste is cancelled"));
+            }
+        }
+        let n = self.reader.read(buf)?;
+        self.received += n as u64;
+        Ok(n)
+    }
+}
+
+/// Ignore the error of a blocking call interrupted by a signal, so it can be called again. If the
+/// signal is Ctrl-C, fail with [`PasteError::Cancelled`] instead. Ctrl-C is not handled when
+/// copying, only the other signals interrupt the calls there.
+#[cfg(all(target_os = "linux", feature = "wayland"))]
+fn check_interrupted(result: std::io::Result<()>, writer: &mut dyn Write) -> Result<()> {
+    match result {
+        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+            if paste_cancelled() {
+                return Err(paste_cancelled_error(writer));
+            }
+            log::debug!("Interrupted by a signal, keep waiting");
+            Ok(())
+        }
+        r => Ok(r?),
+    }
+}
+
+/// Wait until the fd is readable or the timeout is reached. Return false if timed out. The signals
+/// don't end the wait, except Ctrl-C which cancels the paste: true is returned then, so the caller
+/// goes on to check [`paste_cancelled`].
+#[cfg(any(all(target_os = "linux", feature = "wayland"), feature = "x11"))]
+fn wait_fd_readable(fd: RawFd, timeout: Duration) -> Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        // Rounded up, not to wake up right before the deadline
+        let timeout_ms = remaining
+            .as_nanos()
+            .div_ceil(1_000_000)
+            .min(libc::c_int::MAX as u128) as libc::c_int;
+        let r = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if r >= 0 {
+            return Ok(r > 0);
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err).context("Failed to poll the connection");
+        }
+        if paste_cancelled() {
+            return Ok(true);
+        }
+    }
+}
+
+/// Write the detected environment, the available protocols and the backend to be used. This is
+/// for `doctor` to help figuring out why the clipboard is not accessible.
+pub fn write_diagnostics(writer: &mut impl Write) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let wayland_display = std::env::var("WAYLAND_DISPLAY").ok();
+        let display = std::env::var("DISPLAY").ok();
+        let not_set = "(not set)".to_string();
+        writeln!(
+            writer,
+            "WAYLAND_DISPLAY: {}",
+            wayland_display.as_ref().unwrap_or(&not_set)
+        )?;
+        writeln!(writer, "DISPLAY: {}", display.as_ref().unwrap_or(&not_set))?;
+
+        #[cfg(feature = "wayland")]
+        if wayland_display.is_some() {
+            let availability = |available: bool| {
+                if available {
+                    "available"
+                } else {
+                    "not available"
+                }
+            };
+            writeln!(
+                writer,
+                "wlr_data_control: {}",
+                availability(wayland::test_protocol_available())
+            )?;
+            #[cfg(feature = "core-wayland")]
+            writeln!(
+                writer,
+                "wl_data_device: {}",
+                availability(wayland_core::test_protocol_available())
+            )?;
+        }
+        #[cfg(not(feature = "wayland"))]
+        writeln!(writer, "Wayland: not supported by this build")?;
+        #[cfg(feature = "x11")]
+        if display.is_some() {
+            match x::describe_server() {
+                Ok(info) => {
+                    for (key, value) in info {
+                        writeln!(writer, "{key}: {value}")?;
+                    }
+                }
+                Err(e) => writeln!(writer, "X server: {e:#}")?,
+            }
+        }
+        #[cfg(not(feature = "x11"))]
+        writeln!(writer, "X server: not supported by this build")?;
+    }
+
+    match create_backend() {
+        Ok(backend) => {
+            writeln!(writer, "Backend: {}", backend.name())?;
+            write_capabilities(writer, &backend.capabilities())?;
+        }
+        Err(e) => writeln!(writer, "Backend: none ({e:#})")?,
+    }
+    Ok(())
+}
+
+fn write_capabilities(writer: &mut impl Write, capabilities: &Capabilities) -> Result<()> {
+    let support = |supported: bool| {
+        if supported {
+            "supported"
+        } else {
+            "not supported"
+        }
+    };
+    writeln!(
+        writer,
+        "Primary selection: {}",
+        support(capabilities.primary_selection)
+    )?;
+    writeln!(writer, "Watch: {}", support(capabilities.watch))?;
+    writeln!(writer, "Image types: {}", support(capabilities.image_types))?;
+    writeln!(writer, "INCR: {}", support(capabilities.incr))?;
+    writeln!(
+        writer,
+        "Multiple items: {}",
+        support(capabilities.multiple_items)
+    )?;
+    Ok(())
 }
 
 #[cfg(target_os = "macos")]
@@ -66,5 +1025,602 @@ pub fn create_backend() -> Result<Box<dyn ClipBackend>> {
     //     return Ok(Box::new(XBackend {}));
     // }
 
+    log::debug!("Use the mac backend");
     Ok(Box::new(MacBackend {}))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paste_config(writer: SharedBuffer) -> PasteConfig {
+        let mut config = PasteConfig::probe(false, vec![], Box::new(writer));
+        config.list_types_only = true;
+        config
+    }
+
+    #[test]
+    fn test_write_type_list() {
+        let types = vec!["TARGETS".to_string(), "text/plain".to_string()];
+
+        let output = SharedBuffer::default();
+        let mut config = paste_config(output.clone());
+        config.write_type_list(&types).unwrap();
+        assert_eq!(output.take(), b"TARGETS\ntext/plain\n");
+
+        // The indexes match the ones used by '--index'
+        config.list_numbered = true;
+        config.write_type_list(&types).unwrap();
+        assert_eq!(output.take(), b"0\tTARGETS\n1\ttext/plain\n");
+
+        config.list_final_newline = false;
+        config.write_type_list(&types).unwrap();
+        assert_eq!(output.take(), b"0\tTARGETS\n1\ttext/plain");
+
+        assert_eq!(
+            mime_type::mime_type_by_index(1, &types).unwrap(),
+            "text/plain"
+        );
+
+        // The bogus mime-type is escaped, but still pasted by its index
+        let bogus = vec!["text/\x1b[2Jplain".to_string()];
+        config.write_type_list(&bogus).unwrap();
+        assert_eq!(output.take(), b"0\ttext/\\x1b[2Jplain");
+        assert_eq!(
+            mime_type::mime_type_by_index(0, &bogus).unwrap(),
+            "text/\x1b[2Jplain"
+        );
+
+        // Neither escaped nor numbered when listed for parsing
+        config.list_raw = true;
+        config.write_type_list(&bogus).unwrap();
+        assert_eq!(output.take(), b"text/\x1b[2Jplain");
+    }
+
+    #[test]
+    fn test_write_labeled_type_lists() {
+        let clipboard = vec!["text/plain".to_string(), "text/html".to_string()];
+        let primary = vec!["image/png".to_string()];
+
+        let output = SharedBuffer::default();
+        let mut config = paste_config(output.clone());
+        config
+            .write_labeled_type_lists(&[("clipboard", &clipboard), ("primary", &primary)])
+            .unwrap();
+        assert_eq!(
+            output.take(),
+            b"clipboard\ttext/plain\nclipboard\ttext/html\nprimary\timage/png\n"
+        );
+
+        // Numbered in each selection
+        config.list_numbered = true;
+        config
+            .write_labeled_type_lists(&[("clipboard", &clipboard), ("primary", &primary)])
+            .unwrap();
+        assert_eq!(
+            output.take(),
+            b"clipboard\t0\ttext/plain\nclipboard\t1\ttext/html\nprimary\t0\timage/png\n"
+        );
+
+        // An empty selection has no lines
+        config.list_numbered = false;
+        config
+            .write_labeled_type_lists(&[("clipboard", &[]), ("primary", &primary)])
+            .unwrap();
+        assert_eq!(output.take(), b"primary\timage/png\n");
+    }
+
+    #[test]
+    fn test_sniff_writer() {
+        let output = SharedBuffer::default();
+        let sniffed = std::rc::Rc::new(std::cell::RefCell::new(String::new()));
+        let mut config = paste_config(output.clone());
+        let s = sniffed.clone();
+        config.wrap_writer = Some(Box::new(move |mime_type, writer| {
+            *s.borrow_mut() = mime_type.to_string();
+            writer
+        }));
+        config.prepare_writer_sniffed();
+        config.writer.write_all(b"\x89PNG\r\n").unwrap();
+        config.writer.write_all(b"\x1a\n").unwrap();
+        // Not decided until flushing or enough content
+        assert!(sniffed.borrow().is_empty());
+        config.writer.flush().unwrap();
+        assert_eq!(*sniffed.borrow(), "image/png");
+        assert_eq!(output.take(), b"\x89PNG\r\n\x1a\n");
+    }
+
+    #[test]
+    fn test_check_offered_types() {
+        let output = SharedBuffer::default();
+        let mut config = paste_config(output.clone());
+
+        // Listing nothing is fine
+        config.check_offered_types(&[]).unwrap();
+        config.write_type_list(&[]).unwrap();
+        assert!(output.take().is_empty());
+
+        config.list_types_only = false;
+        assert!(config.check_offered_types(&[]).is_err());
+        config
+            .check_offered_types(&["text/plain".to_string()])
+            .unwrap();
+    }
+
+    #[cfg(any(all(target_os = "linux", feature = "wayland"), feature = "x11"))]
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("65536"), Some(65536));
+        assert_eq!(parse_size(" 1048576 "), Some(1048576));
+        for invalid in ["", "0", "-1", "64k"] {
+            assert_eq!(parse_size(invalid), None);
+        }
+    }
+
+    #[test]
+    fn test_write_capabilities() {
+        let mut output = Vec::new();
+        let capabilities = MockBackend { items: vec![] }.capabilities();
+        write_capabilities(&mut output, &capabilities).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Primary selection: not supported\nWatch: not supported\nImage types: supported\n\
+             INCR: not supported\nMultiple items: not supported\n"
+        );
+    }
+
+    /// Serve the fixed content of the mime-types.
+    struct MockBackend {
+        items: Vec<(&'static str, Vec<u8>)>,
+    }
+
+    impl ClipBackend for MockBackend {
+        fn name(&self) -> &'static str {
+            "mock"
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                primary_selection: false,
+                watch: false,
+                image_types: true,
+                incr: false,
+                multiple_items: false,
+            }
+        }
+
+        fn copy(&self, _config: CopyConfig) -> Result<()> {
+            bail!("Copying is not supported by the mock backend")
+        }
+
+        fn paste(&self, mut config: PasteConfig) -> Result<()> {
+            let types: Vec<String> = self.items.iter().map(|(t, _)| t.to_string()).collect();
+            if config.list_types_only {
+                return config.write_type_list(&types);
+            }
+            let mime_type = match config.target_index {
+                Some(index) => mime_type::mime_type_by_index(index, &types)?,
+                None => {
+                    let Ok(mime_type) =
+                        mime_type::decide_mime_type_in_order(&config.expected_mime_types, &types)
+                    else {
+                        return Err(config.not_offered_error());
+                    };
+                    mime_type
+                }
+            };
+            if mime_type == "broken" {
+                bail!("The content of {mime_type} is not sent");
+            }
+            let (_, content) = self.items.iter().find(|(t, _)| *t == mime_type).unwrap();
+            config.prepare_writer(&mime_type);
+            config.writer.write_all(content)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_listed_case_preserved() {
+        let backend = MockBackend {
+            items: vec![
+                ("TARGETS", vec![]),
+                ("UTF8_STRING", b"GOOD".to_vec()),
+                ("Text/HTML", b"<b>GOOD</b>".to_vec()),
+            ],
+        };
+        // Listed as advertised
+        let output = SharedBuffer::default();
+        backend.paste(paste_config(output.clone())).unwrap();
+        assert_eq!(output.take(), b"TARGETS\nUTF8_STRING\nText/HTML\n");
+
+        // Matched case-insensitively
+        let mut config = paste_config(output.clone());
+        config.list_types_only = false;
+        config.expected_mime_types = vec!["utf8_string".to_string()];
+        backend.paste(config).unwrap();
+        assert_eq!(output.take(), b"GOOD");
+
+        let mut config = paste_config(output.clone());
+        config.list_types_only = false;
+        config.expected_mime_types = vec!["text/html".to_string()];
+        backend.paste(config).unwrap();
+        assert_eq!(output.take(), b"<b>GOOD</b>");
+    }
+
+    #[test]
+    fn test_decide_mime_type_by_size() {
+        let backend = MockBackend {
+            items: vec![
+                ("text/plain", b"GOOD".to_vec()),
+                ("image/png", vec![0; 10]),
+                ("image/tiff", vec![0; 100]),
+                ("image/gif", vec![0; 10]),
+            ],
+        };
+        let decide = |expected: &[&str], prefer| {
+            let expected: Vec<String> = expected.iter().map(|t| t.to_string()).collect();
+            decide_mime_type_by_size(&backend, false, &expected, prefer).unwrap()
+        };
+
+        assert_eq!(
+            decide(&["image/*"], PreferSize::Smallest).as_deref(),
+            Some("image/png")
+        );
+        assert_eq!(
+            decide(&["image/*"], PreferSize::Largest).as_deref(),
+            Some("image/tiff")
+        );
+        // The first expected mime-type which is offered decides the candidates
+        assert_eq!(
+            decide(&["audio/*", "image/gif", "image/*"], PreferSize::Largest).as_deref(),
+            Some("image/gif")
+        );
+        assert_eq!(
+            decide(&[], PreferSize::Largest).as_deref(),
+            Some("text/plain")
+        );
+        assert_eq!(decide(&["text/html"], PreferSize::Smallest), None);
+    }
+
+    #[test]
+    fn test_offered_mime_types() {
+        let backend = MockBackend {
+            items: vec![
+                ("text/plain", b"GOOD".to_vec()),
+                ("text/\x1b[2Jplain", b"BAD".to_vec()),
+                ("text/html\nimage/png", b"BAD".to_vec()),
+            ],
+        };
+        // As offered, not split or escaped
+        assert_eq!(
+            offered_mime_types(&backend, false).unwrap(),
+            vec!["text/plain", "text/\x1b[2Jplain", "text/html\nimage/png"]
+        );
+        assert_eq!(
+            decide_mime_type_by_size(
+                &backend,
+                false,
+                &["text/*".to_string()],
+                PreferSize::Smallest
+            )
+            .unwrap()
+            .as_deref(),
+            Some("text/\x1b[2Jplain")
+        );
+
+        let backend = MockBackend { items: vec![] };
+        assert!(offered_mime_types(&backend, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_write_type_sizes() {
+        let backend = MockBackend {
+            items: vec![
+                ("text/plain", b"GOOD".to_vec()),
+                ("broken", b"BAD".to_vec()),
+                ("image/png", vec![0; 10]),
+            ],
+        };
+        let output = SharedBuffer::default();
+        let mut config = paste_config(output.clone());
+        config.list_types_only = true;
+        write_type_sizes(&backend, config).unwrap();
+        assert_eq!(output.take(), b"text/plain\t4\nbroken\t?\nimage/png\t10\n");
+
+        let mut config = paste_config(output.clone());
+        config.list_types_only = true;
+        config.list_numbered = true;
+        config.list_final_newline = false;
+        write_type_sizes(&backend, config).unwrap();
+        assert_eq!(
+            output.take(),
+            b"0\ttext/plain\t4\n1\tbroken\t?\n2\timage/png\t10"
+        );
+    }
+
+    #[cfg(any(all(target_os = "linux", feature = "wayland"), feature = "x11"))]
+    #[test]
+    fn test_retry_with_backoff() {
+        // Fails twice, then succeeds
+        let mut attempts = 0;
+        let r = retry_with_backoff(3, Duration::from_millis(1), || {
+            attempts += 1;
+            if attempts < 3 {
+                bail!("Not ready");
+            }
+            Ok(attempts)
+        });
+        assert_eq!(r.unwrap(), 3);
+
+        // Gives up after the retries
+        let mut attempts = 0;
+        let r: Result<()> = retry_with_backoff(2, Duration::from_millis(1), || {
+            attempts += 1;
+            bail!("Not ready {attempts}")
+        });
+        assert_eq!(r.unwrap_err().to_string(), "Not ready 3");
+
+        // No retry by default
+        let mut attempts = 0;
+        let r: Result<()> = retry_with_backoff(0, Duration::from_millis(1), || {
+            attempts += 1;
+            bail!("Not ready")
+        });
+        assert!(r.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_serve_deadline() {
+        let start = Instant::now();
+        let mut config = CopyConfig {
+            use_primary: false,
+            both_selections: false,
+            source_data: Box::new(Vec::<crate::protocol::SourceDataItem>::new()),
+            x_chunk_size: 0,
+            serve_once: false,
+            expire: None,
+            idle_timeout: None,
+            clear_on_expire: true,
+            x_persist_primary: false,
+            x_exclusive: false,
+            mac_no_clear: false,
+        };
+        assert_eq!(ServeDeadline::new(&config, start).at(start), None);
+
+        config.idle_timeout = Some(Duration::from_secs(10));
+        let deadline = ServeDeadline::new(&config, start);
+        assert_eq!(deadline.at(start), Some(start + Duration::from_secs(10)));
+        // Every request resets the idle timeout
+        let later = start + Duration::from_secs(5);
+        assert_eq!(deadline.at(later), Some(later + Duration::from_secs(10)));
+
+        // The expiry comes first
+        config.expire = Some(Duration::from_secs(12));
+        let deadline = ServeDeadline::new(&config, start);
+        assert_eq!(deadline.at(start), Some(start + Duration::from_secs(10)));
+        assert_eq!(deadline.at(later), Some(start + Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn test_not_offered_error() {
+        let mut config = paste_config(SharedBuffer::default());
+        let e = config.not_offered_error();
+        let paste_err = e.downcast_ref::<PasteError>().unwrap();
+        assert_eq!(paste_err.exit_code(), 3);
+        assert_eq!(e.to_string(), "The clipboard doesn't offer mime-type text");
+
+        config.expected_mime_types = vec!["image/png".to_string(), "png".to_string()];
+        let e = config.not_offered_error().context("Failed to paste");
+        assert!(matches!(
+            e.downcast_ref::<PasteError>(),
+            Some(PasteError::NotOffered(t)) if t == "image/png,png"
+        ));
+        assert_eq!(PasteError::NoContent("png".to_string()).exit_code(), 4);
+    }
+
+    #[test]
+    fn test_paste_cancelled_error() {
+        let output = SharedBuffer::default();
+        let mut writer = std::io::BufWriter::new(output.clone());
+        writer.write_all(b"partial").unwrap();
+        assert!(output.take().is_empty());
+
+        // The partial content is flushed
+        let e = paste_cancelled_error(&mut writer);
+        assert_eq!(output.take(), b"partial");
+        let paste_err = e.downcast_ref::<PasteError>().unwrap();
+        assert_eq!(paste_err.exit_code(), 5);
+    }
+
+    #[cfg(any(all(target_os = "linux", feature = "wayland"), feature = "x11"))]
+    #[test]
+    fn test_wait_fd_readable_interrupted() {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::thread::JoinHandleExt;
+
+        extern "C" fn on_signal(_: libc::c_int) {}
+        // Without SA_RESTART like the SIGINT handler, so the poll fails with EINTR
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            assert_eq!(
+                libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+                0
+            );
+        }
+
+        // Never readable
+        let (reader, _writer) = std::io::pipe().unwrap();
+        let timeout = Duration::from_millis(300);
+        let handle = std::thread::spawn(move || {
+            let start = Instant::now();
+            let readable = wait_fd_readable(reader.as_raw_fd(), timeout).unwrap();
+            (readable, start.elapsed())
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { libc::pthread_kill(handle.as_pthread_t(), libc::SIGUSR1) };
+
+        // Still waits for the rest of the timeout
+        let (readable, elapsed) = handle.join().unwrap();
+        assert!(!readable);
+        assert!(elapsed >= timeout);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "wayland"))]
+    #[test]
+    fn test_check_interrupted() {
+        let mut writer = std::io::sink();
+        assert!(check_interrupted(Ok(()), &mut writer).is_ok());
+        // Not cancelled, keep waiting
+        let interrupted = std::io::Error::from(std::io::ErrorKind::Interrupted);
+        assert!(check_interrupted(Err(interrupted), &mut writer).is_ok());
+        let broken = std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+        assert!(check_interrupted(Err(broken), &mut writer).is_err());
+    }
+
+    #[cfg(all(target_os = "linux", feature = "wayland"))]
+    #[test]
+    fn test_copy_from_pipe_deadline() {
+        // The owner which never writes the content nor closes the pipe
+        let (reader, mut silent_writer) = std::io::pipe().unwrap();
+        silent_writer.write_all(b"abc").unwrap();
+        let output = SharedBuffer::default();
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let e = copy_from_pipe(reader, &mut output.clone(), Some(deadline)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Timed out waiting for the clipboard owner to send the content, 3 bytes received"
+        );
+        assert_eq!(output.take(), b"abc");
+
+        // Finished in time
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        writer.write_all(b"GOOD").unwrap();
+        drop(writer);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let n = copy_from_pipe(reader, &mut output.clone(), Some(deadline)).unwrap();
+        assert_eq!(n, 4);
+        assert_eq!(output.take(), b"GOOD");
+        drop(silent_writer);
+    }
+
+    #[test]
+    fn test_watch_count() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let changes = Rc::new(Cell::new(0));
+        let c = changes.clone();
+        let mut config = WatchConfig {
+            use_primary: false,
+            count: Some(3),
+            poll_interval: Duration::from_millis(250),
+            on_change: Box::new(move || {
+                c.set(c.get() + 1);
+                Ok(())
+            }),
+        };
+        // Fire changes like a backend does until it stops
+        while config.notify_change().unwrap() {}
+        assert_eq!(changes.get(), 3);
+
+        // Watch forever
+        config.count = None;
+        for _ in 0..10 {
+            assert!(config.notify_change().unwrap());
+        }
+        assert_eq!(changes.get(), 13);
+
+        // Errors from the handler stop the watching
+        config.on_change = Box::new(|| bail!("Failed"));
+        assert!(config.notify_change().is_err());
+    }
+
+    #[cfg(all(target_os = "linux", feature = "x11"))]
+    #[test]
+    fn test_prefer_x11() {
+        assert!(prefer_x11(Some("x11")));
+        assert!(prefer_x11(Some("X11")));
+        assert!(prefer_x11(Some("x")));
+        assert!(!prefer_x11(Some("wayland")));
+        assert!(!prefer_x11(Some("")));
+        assert!(!prefer_x11(Some("unknown")));
+        assert!(!prefer_x11(None));
+
+        // X11 is tried first, the Wayland protocol is not even checked
+        let backend = select_backend(Some("x11"), true, true).unwrap();
+        assert_eq!(backend.name(), "x");
+        // Without the Wayland display, X11 is the fallback anyway
+        let backend = select_backend(Some("wayland"), false, true).unwrap();
+        assert_eq!(backend.name(), "x");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_default_selection() {
+        assert!(primary_by_default(Some("primary")));
+        assert!(primary_by_default(Some("PRIMARY")));
+        assert!(!primary_by_default(Some("clipboard")));
+        assert!(!primary_by_default(Some("")));
+        assert!(!primary_by_default(Some("both")));
+        assert!(!primary_by_default(None));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_backend_unavailable() {
+        let e = select_backend(None, false, false).err().unwrap();
+        let unavailable = e.downcast_ref::<BackendUnavailable>().unwrap();
+        assert_eq!(
+            unavailable.reasons,
+            ["WAYLAND_DISPLAY is not set", "DISPLAY is not set"]
+        );
+        assert_eq!(
+            e.to_string(),
+            "Could not decide the clip backend: WAYLAND_DISPLAY is not set, DISPLAY is not set. \
+            Set WAYLAND_DISPLAY or DISPLAY for the running display server, or run 'richclip \
+            doctor' for details"
+        );
+
+        // Checked in the preferred order
+        let e = select_backend(Some("x11"), false, false).err().unwrap();
+        let unavailable = e.downcast_ref::<BackendUnavailable>().unwrap();
+        assert_eq!(
+            unavailable.reasons,
+            ["DISPLAY is not set", "WAYLAND_DISPLAY is not set"]
+        );
+
+        #[cfg(not(feature = "wayland"))]
+        {
+            let e = select_backend(None, true, false).err().unwrap();
+            let unavailable = e.downcast_ref::<BackendUnavailable>().unwrap();
+            assert_eq!(
+                unavailable.reasons,
+                [
+                    "WAYLAND_DISPLAY is set, but richclip is built without the wayland feature",
+                    "DISPLAY is not set"
+                ]
+            );
+        }
+
+        #[cfg(feature = "x11")]
+        assert_eq!(select_backend(None, false, true).unwrap().name(), "x");
+        // X11 is picked first if preferred, even if WAYLAND_DISPLAY is set
+        #[cfg(feature = "x11")]
+        assert_eq!(select_backend(Some("x11"), true, true).unwrap().name(), "x");
+        #[cfg(not(feature = "x11"))]
+        {
+            let e = select_backend(None, false, true).err().unwrap();
+            let unavailable = e.downcast_ref::<BackendUnavailable>().unwrap();
+            assert_eq!(
+                unavailable.reasons,
+                [
+                    "WAYLAND_DISPLAY is not set",
+                    "DISPLAY is set, but richclip is built without the x11 feature"
+                ]
+            );
+        }
+    }
+}
@@ -1,14 +1,24 @@
+use super::Capabilities;
 use super::ClipBackend;
 use super::CopyConfig;
 use super::PasteConfig;
-use super::mime_type::decide_mime_type;
+use super::ServeDeadline;
+use super::WatchConfig;
+use super::check_interrupted;
+use super::connect_with_retries;
+use super::copy_from_pipe;
+use super::measure_content_sizes;
+use super::mime_type::{OfferedTypes, decide_mime_type_in_order, mime_type_by_index};
+use super::wait_fd_readable;
 use crate::protocol::SourceData;
-use anyhow::{Context, Error, Result};
+use anyhow::{Context, Error, Result, bail};
 use nix::unistd::pipe;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
 use std::fs::File;
 use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::time::Instant;
 use wayrs_client::core::ObjectId;
 use wayrs_client::protocol::wl_seat::WlSeat;
 use wayrs_client::{Connection, EventCtx, IoMode};
@@ -25,25 +35,62 @@ pub fn test_protocol_available() -> bool {
     create_wayland_client::<()>().is_ok()
 }
 
+// With wlr_data_control version 1, the primary selection falls back to zwp_primary_selection.
+fn test_primary_available() -> bool {
+    if create_wayland_client::<()>().is_ok_and(|client| client.primary_supported) {
+        return true;
+    }
+    #[cfg(feature = "core-wayland")]
+    return super::wayland_core::test_primary_available();
+    #[cfg(not(feature = "core-wayland"))]
+    false
+}
+
 struct WaylandClient<T> {
     conn: Connection<T>,
     seat: WlSeat,
     data_ctl_mgr: ZwlrDataControlManagerV1,
+    // The primary selection is only supported since version 2
+    primary_supported: bool,
 }
 
 struct CopyEventState {
     finished: bool,
+    // The sources which have not been cancelled, one for each selection
+    active_sources: usize,
+    serve_once: bool,
     source_data: Box<dyn SourceData>,
+    // For the idle timeout
+    last_request: Instant,
 }
 
 struct PasteEventState {
     // Stored offers for selection and primary selection (middle-click paste).
-    offers: HashMap<ZwlrDataControlOfferV1, Vec<String>>,
+    offers: HashMap<ZwlrDataControlOfferV1, OfferedTypes>,
     stage: PasteEventStage,
+    // The first selection event reports the content which is already in the clipboard, skip it
+    // for `wayland_wait_for_change`
+    skip_selection: bool,
+    // The offers of the clipboard and the primary selection for `wayland_all_selections`
+    clipboard_offer: Option<ObjectId>,
+    primary_offer: Option<ObjectId>,
 
     config: PasteConfig,
 }
 
+struct WatchEventState {
+    // The offers are not used, but they need to be destroyed once replaced
+    new_offers: HashSet<ZwlrDataControlOfferV1>,
+    selection_offer: Option<ZwlrDataControlOfferV1>,
+    primary_offer: Option<ZwlrDataControlOfferV1>,
+    // The first selection event reports the content which is already in the clipboard
+    initial: bool,
+    // Set when the watching should stop
+    result: Option<Result<()>>,
+
+    config: WatchConfig,
+}
+
 enum PasteEventStage {
     Done,
     Err(Error),
@@ -52,6 +99,20 @@ enum PasteEventStage {
 }
 
 impl ClipBackend for WaylandBackend {
+    fn name(&self) -> &'static str {
+        "wayland"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            primary_selection: test_primary_available(),
+            watch: true,
+            image_types: true,
+            incr: false,
+            multiple_items: false,
+        }
+    }
+
     fn copy(&self, config: CopyConfig) -> Result<()> {
         copy_wayland(config)
     }
@@ -59,28 +120,87 @@ impl ClipBackend for WaylandBackend {
     fn paste(&self, config: PasteConfig) -> Result<()> {
         paste_wayland(config)
     }
+
+    fn watch(&self, config: WatchConfig) -> Result<()> {
+        watch_wayland(config)
+    }
+
+    fn content_sizes(&self, use_primary: bool) -> Result<Vec<(String, Option<u64>)>> {
+        // Wayland can only tell the size by receiving the whole content through the pipe
+        log::warn!("Receiving the content of every offered mime-type to measure the sizes");
+        measure_content_sizes(self, use_primary)
+    }
 }
 
 fn create_wayland_client<T>() -> Result<WaylandClient<T>> {
-    let mut conn = Connection::<T>::connect().context("Failed to create wayland connection")?;
+    let mut conn = connect_with_retries(|| {
+        Connection::<T>::connect().context("Failed to create wayland connection")
+    })?;
     conn.blocking_roundtrip()
         .context("Failed to call 'blocking_roundtrip'")?;
 
     let seat: WlSeat = conn
         .bind_singleton(2..=4)
         .context("Failed to bind Wayland seat")?;
-    let data_ctl_mgr: ZwlrDataControlManagerV1 = conn.bind_singleton(..=2).context("Failed to bind data control manager (wlr_data_control_unstable_v1 protocol may not be available)")?;
+    let (data_ctl_mgr, primary_supported) = match conn.bind_singleton(2) {
+        Ok(data_ctl_mgr) => (data_ctl_mgr, true),
+        Err(_) => (conn.bind_singleton(1).context("Failed to bind data control manager (wlr_data_control_unstable_v1 protocol may not be available)")?, false),
+    };
 
     Ok(WaylandClient::<T> {
         conn,
         seat,
         data_ctl_mgr,
+        primary_supported,
     })
 }
 
+/// For the compositors with wlr_data_control version 1, the primary selection is accessed with the
+/// zwp_primary_selection protocol, which needs the keyboard focus like the core protocol.
+#[cfg(feature = "core-wayland")]
+fn paste_primary_fallback(cfg: PasteConfig) -> Result<()> {
+    log::debug!(
+        "wlr_data_control doesn't support the primary selection, use zwp_primary_selection"
+    );
+    super::wayland_core::paste_wayland_core(cfg)
+}
+
+#[cfg(feature = "core-wayland")]
+fn copy_primary_fallback(config: CopyConfig) -> Result<()> {
+    log::debug!(
+        "wlr_data_control doesn't support the primary selection, use zwp_primary_selection"
+    );
+    super::wayland_core::copy_wayland_core(config)
+}
+
+#[cfg(not(feature = "core-wayland"))]
+fn paste_primary_fallback(_cfg: PasteConfig) -> Result<()> {
+    Err(primary_unsupported_error())
+}
+
+#[cfg(not(feature = "core-wayland"))]
+fn copy_primary_fallback(_config: CopyConfig) -> Result<()> {
+    Err(primary_unsupported_error())
+}
+
+#[cfg(not(feature = "core-wayland"))]
+fn primary_unsupported_error() -> Error {
+    Error::msg(
+        "The primary selection is not supported by the compositor's wlr_data_control, build with \
+        the 'core-wayland' feature to use zwp_primary_selection instead",
+    )
+}
+
 fn paste_wayland(cfg: PasteConfig) -> Result<()> {
+    let start_time = Instant::now();
+    if cfg.x_owner_info {
+        bail!("The selection owner info is only supported on X, try 'RICHCLIP_PREFER=x11'");
+    }
     let mut client =
         create_wayland_client::<PasteEventState>().context("Failed to create wayland client")?;
+    if cfg.use_primary && !client.primary_supported {
+        return paste_primary_fallback(cfg);
+    }
 
     let _data_control_device = client.data_ctl_mgr.get_data_device_with_cb(
         &mut client.conn,
@@ -91,9 +211,28 @@ fn paste_wayland(cfg: PasteConfig) -> Result<()> {
     let mut state = PasteEventState {
         offers: HashMap::new(),
         stage: PasteEventStage::CollectingOffers,
+        skip_selection: cfg.wayland_wait_for_change,
+        clipboard_offer: None,
+        primary_offer: None,
         config: cfg,
     };
 
+    if state.config.wayland_all_selections {
+        // The current selections are sent right after the device is created
+        client
+            .conn
+            .blocking_roundtrip()
+            .context("Failed to call 'blocking_roundtrip'")?;
+        client.conn.dispatch_events(&mut state);
+        if let PasteEventStage::Err(err) = state.stage {
+            return Err(err);
+        }
+        if !client.primary_supported {
+            log::debug!("The primary selection is not supported by the compositor");
+        }
+        return list_all_selections(state);
+    }
+
     let selection_id = loop {
         match state.stage {
             PasteEventStage::Done => return Ok(()),
@@ -102,27 +241,34 @@ fn paste_wayland(cfg: PasteConfig) -> Result<()> {
             PasteEventStage::GotSelection(id) => break id,
         }
 
-        client.conn.flush(IoMode::Blocking).unwrap();
-        client.conn.recv_events(IoMode::Blocking).unwrap();
+        client.conn.flush(IoMode::Blocking)?;
+        check_interrupted(
+            client.conn.recv_events(IoMode::Blocking),
+            &mut state.config.writer,
+        )?;
         client.conn.dispatch_events(&mut state);
     };
 
     let (offer, supported_types) = state.offers.get_key_value(&selection_id).unwrap();
+    state.config.check_offered_types(supported_types)?;
+    log::debug!("Offer negotiation took {:?}", start_time.elapsed());
 
     // with "-l", list the mime-types and return
     if state.config.list_types_only {
-        for mt in supported_types {
-            writeln!(state.config.writer, "{mt}")?;
-        }
+        state.config.write_type_list(supported_types)?;
         return Ok(());
     }
 
-    let mime_type =
-        if let Ok(type_str) = decide_mime_type(&state.config.expected_mime_type, supported_types) {
-            CString::new(type_str)?
-        } else {
-            return Ok(());
-        };
+    let decided = match state.config.target_index {
+        Some(index) => Ok(mime_type_by_index(index, supported_types)?),
+        None => decide_mime_type_in_order(&state.config.expected_mime_types, supported_types),
+    };
+    let mime_type = if let Ok(type_str) = decided {
+        state.config.prepare_writer(&type_str);
+        CString::new(type_str)?
+    } else {
+        return Err(state.config.not_offered_error());
+    };
 
     // offer.receive needs a fd to write, we cannot use the stdin since the read side of the
     // pipe may close earlier before all data written.
@@ -130,67 +276,184 @@ fn paste_wayland(cfg: PasteConfig) -> Result<()> {
     offer.receive(&mut client.conn, mime_type, pipe_write);
     client.conn.flush(IoMode::Blocking)?;
 
-    let mut pipe_read = File::from(pipe_read);
-    std::io::copy(&mut pipe_read, &mut state.config.writer)?;
+    let pipe_read = File::from(pipe_read);
+    let transfer_start = Instant::now();
+    let deadline = state.config.timeout.map(|timeout| start_time + timeout);
+    let bytes = copy_from_pipe(pipe_read, &mut state.config.writer, deadline)?;
+    if bytes == 0 {
+        // Also the case if the owner refuses to send the mime-type, which cannot be told apart
+        log::debug!("No content is received from the clipboard owner");
+    }
+    state.config.writer.flush()?;
+    let elapsed = transfer_start.elapsed();
+    log::debug!(
+        "Read {} bytes from the pipe in {:?} ({:.2} MiB/s), total {:?}",
+        bytes,
+        elapsed,
+        bytes as f64 / 1024.0 / 1024.0 / elapsed.as_secs_f64(),
+        start_time.elapsed()
+    );
 
     Ok(())
 }
 
+/// List the mime-types of the clipboard and the primary selection, labeled with their names.
+fn list_all_selections(mut state: PasteEventState) -> Result<()> {
+    let types_of = |offer: Option<ObjectId>| -> Vec<String> {
+        offer
+            .and_then(|id| state.offers.get(&id))
+            .map(|types| types.to_vec())
+            .unwrap_or_default()
+    };
+    let clipboard = types_of(state.clipboard_offer);
+    let primary = types_of(state.primary_offer);
+    state
+        .config
+        .write_labeled_type_lists(&[("clipboard", &clipboard), ("primary", &primary)])
+}
+
 fn copy_wayland(config: CopyConfig) -> Result<()> {
     let mut client =
         create_wayland_client::<CopyEventState>().context("Failed to create wayland client")?;
+    if config.both_selections && !client.primary_supported {
+        bail!("Copying to both selections needs wlr_data_control version 2");
+    }
+    if config.use_primary && !client.primary_supported {
+        return copy_primary_fallback(config);
+    }
 
-    let source = client
-        .data_ctl_mgr
-        .create_data_source_with_cb(&mut client.conn, wl_source_cb_for_copy);
-    config.source_data.mime_types().iter().for_each(|mime| {
-        let cstr = CString::new(mime.as_bytes()).unwrap();
-        source.offer(&mut client.conn, cstr);
-    });
+    let mime_types = config.source_data.mime_types();
+    let data_ctl_mgr = client.data_ctl_mgr;
+    let create_source = |conn: &mut Connection<CopyEventState>| {
+        let source = data_ctl_mgr.create_data_source_with_cb(conn, wl_source_cb_for_copy);
+        mime_types.iter().for_each(|mime| {
+            let cstr = CString::new(mime.as_bytes()).unwrap();
+            source.offer(conn, cstr);
+        });
+        source
+    };
 
     let data_control_device = client
         .data_ctl_mgr
         .get_data_device(&mut client.conn, client.seat);
-    if config.use_primary {
+    // A source can only be used for one selection
+    let mut sources = vec![];
+    if config.use_primary || config.both_selections {
+        let source = create_source(&mut client.conn);
         data_control_device.set_primary_selection(&mut client.conn, Some(source));
-    } else {
+        sources.push(source);
+    }
+    if !config.use_primary || config.both_selections {
+        let source = create_source(&mut client.conn);
         data_control_device.set_selection(&mut client.conn, Some(source));
+        sources.push(source);
     }
 
+    let start_time = Instant::now();
+    let deadline = ServeDeadline::new(&config, start_time);
     let mut state = CopyEventState {
         finished: false,
+        active_sources: sources.len(),
+        serve_once: config.serve_once,
         source_data: config.source_data,
+        last_request: start_time,
     };
 
-    client.conn.flush(IoMode::Blocking).unwrap();
+    client.conn.flush(IoMode::Blocking)?;
     loop {
         if state.finished {
             break;
         }
-        client.conn.recv_events(IoMode::Blocking).unwrap();
+        if let Some(deadline) = deadline.at(state.last_request) {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if !wait_fd_readable(client.conn.as_raw_fd(), timeout)? {
+                log::debug!("The content expired");
+                if config.clear_on_expire {
+                    if config.use_primary || config.both_selections {
+                        data_control_device.set_primary_selection(&mut client.conn, None);
+                    }
+                    if !config.use_primary || config.both_selections {
+                        data_control_device.set_selection(&mut client.conn, None);
+                    }
+                    for source in sources {
+                        source.destroy(&mut client.conn);
+                    }
+                    client.conn.flush(IoMode::Blocking)?;
+                    state.source_data.clear_content();
+                }
+                break;
+            }
+        }
+        check_interrupted(
+            client.conn.recv_events(IoMode::Blocking),
+            &mut std::io::sink(),
+        )?;
         client.conn.dispatch_events(&mut state);
     }
 
     Ok(())
 }
 
+fn watch_wayland(config: WatchConfig) -> Result<()> {
+    let mut client =
+        create_wayland_client::<WatchEventState>().context("Failed to create wayland client")?;
+    if config.use_primary && !client.primary_supported {
+        bail!("Watching the primary selection needs wlr_data_control version 2");
+    }
+
+    let _data_control_device = client.data_ctl_mgr.get_data_device_with_cb(
+        &mut client.conn,
+        client.seat,
+        wl_device_cb_for_watch,
+    );
+
+    let mut state = WatchEventState {
+        new_offers: HashSet::new(),
+        selection_offer: None,
+        primary_offer: None,
+        initial: true,
+        result: None,
+        config,
+    };
+
+    loop {
+        if let Some(result) = state.result.take() {
+            return result;
+        }
+        client
+            .conn
+            .flush(IoMode::Blocking)
+            .context("Failed to flush wayland connection")?;
+        client
+            .conn
+            .recv_events(IoMode::Blocking)
+            .context("Failed to receive wayland events")?;
+        client.conn.dispatch_events(&mut state);
+    }
+}
+
 #[allow(clippy::collapsible_match)]
 fn wl_device_cb_for_paste(ctx: EventCtx<PasteEventState, ZwlrDataControlDeviceV1>) {
     match ctx.event {
         // Received before Selection or PrimarySelection
         // Need to request mime-types here
         zwlr_data_control_device_v1::Event::DataOffer(offer) => {
-            if ctx.state.offers.insert(offer, Vec::new()).is_some() {
+            if ctx
+                .state
+                .offers
+                .insert(offer, OfferedTypes::default())
+                .is_some()
+            {
                 log::error!("Duplicated offer received")
             }
             ctx.conn.set_callback_for(offer, |ctx| {
                 if let zwlr_data_control_offer_v1::Event::Offer(mime_type) = ctx.event {
-                    if let Ok(str) = mime_type.to_str() {
-                        let new_type = str.to_string();
+                    if mime_type.as_bytes().is_empty() {
+                        log::debug!("Skip the empty mime-type");
+                    } else if let Ok(str) = mime_type.to_str() {
                         let mime_types = ctx.state.offers.get_mut(&ctx.proxy).unwrap();
-                        if !mime_types.iter().any(|s| new_type.eq(s)) {
-                            // Duplicated mime-types could be reported (wl-paste -l shows the same)
-                            mime_types.push(new_type);
+                        if !mime_types.push(str.to_string()) {
+                            log::debug!("Skip the duplicated mime-type '{str}'");
                         }
                     } else {
                         log::error!("Failed to convert '{:x?}' to String", mime_type.as_bytes());
@@ -199,25 +462,17 @@ fn wl_device_cb_for_paste(ctx: EventCtx<PasteEventState, ZwlrDataControlDeviceV1
             });
         }
         zwlr_data_control_device_v1::Event::Selection(o) => {
-            if !ctx.state.config.use_primary {
-                let Some(obj_id) = o else {
-                    log::error!("No data in the clipboard");
-                    ctx.state.stage = PasteEventStage::Done;
-                    ctx.conn.break_dispatch_loop();
-                    return;
-                };
-                ctx.state.stage = PasteEventStage::GotSelection(obj_id);
+            if ctx.state.config.wayland_all_selections {
+                ctx.state.clipboard_offer = o;
+            } else if !ctx.state.config.use_primary {
+                on_paste_selection(ctx.state, ctx.conn, o);
             }
         }
         zwlr_data_control_device_v1::Event::PrimarySelection(o) => {
-            if ctx.state.config.use_primary {
-                let Some(obj_id) = o else {
-                    log::error!("No data in the clipboard");
-                    ctx.state.stage = PasteEventStage::Done;
-                    ctx.conn.break_dispatch_loop();
-                    return;
-                };
-                ctx.state.stage = PasteEventStage::GotSelection(obj_id);
+            if ctx.state.config.wayland_all_selections {
+                ctx.state.primary_offer = o;
+            } else if ctx.state.config.use_primary {
+                on_paste_selection(ctx.state, ctx.conn, o);
             }
         }
         zwlr_data_control_device_v1::Event::Finished => {
@@ -230,6 +485,84 @@ fn wl_device_cb_for_paste(ctx: EventCtx<PasteEventState, ZwlrDataControlDeviceV1
     }
 }
 
+/// Handle the selection event of the selection to be pasted.
+fn on_paste_selection(
+    state: &mut PasteEventState,
+    conn: &mut Connection<PasteEventState>,
+    offer: Option<ObjectId>,
+) {
+    if state.config.wayland_wait_for_change {
+        if state.skip_selection {
+            log::debug!("Skip the initial selection, wait for the next one");
+            state.skip_selection = false;
+            return;
+        }
+        if offer.is_none() {
+            log::debug!("The clipboard has been cleared, wait for the next one");
+            return;
+        }
+    }
+    let Some(obj_id) = offer else {
+        log::error!("No data in the clipboard");
+        state.stage = PasteEventStage::Done;
+        conn.break_dispatch_loop();
+        return;
+    };
+    state.stage = PasteEventStage::GotSelection(obj_id);
+}
+
+fn wl_device_cb_for_watch(ctx: EventCtx<WatchEventState, ZwlrDataControlDeviceV1>) {
+    let (is_primary, obj_id) = match ctx.event {
+        zwlr_data_control_device_v1::Event::DataOffer(offer) => {
+            // The mime-types are not needed
+            ctx.conn.set_callback_for(offer, |_| {});
+            ctx.state.new_offers.insert(offer);
+            return;
+        }
+        zwlr_data_control_device_v1::Event::Selection(o) => (false, o),
+        zwlr_data_control_device_v1::Event::PrimarySelection(o) => (true, o),
+        zwlr_data_control_device_v1::Event::Finished => {
+            log::debug!("Received 'Finished' event");
+            ctx.state.result = Some(Err(Error::msg(
+                "The data control object has been destroyed",
+            )));
+            ctx.conn.break_dispatch_loop();
+            return;
+        }
+        _ => unreachable!("Unexpected event for device callback"),
+    };
+
+    let state = ctx.state;
+    let offer = obj_id.and_then(|id| state.new_offers.take(&id));
+    let old_offer = if is_primary {
+        std::mem::replace(&mut state.primary_offer, offer)
+    } else {
+        std::mem::replace(&mut state.selection_offer, offer)
+    };
+    if let Some(old_offer) = old_offer {
+        old_offer.destroy(ctx.conn);
+    }
+
+    if is_primary != state.config.use_primary {
+        return;
+    }
+    if state.initial {
+        log::debug!("Skip the initial selection");
+        state.initial = false;
+        return;
+    }
+    if obj_id.is_none() {
+        log::debug!("The clipboard has been cleared");
+        return;
+    }
+    match state.config.notify_change() {
+        Ok(true) => return,
+        Ok(false) => state.result = Some(Ok(())),
+        Err(e) => state.result = Some(Err(e)),
+    }
+    ctx.conn.break_dispatch_loop();
+}
+
 fn wl_source_cb_for_copy(ctx: EventCtx<CopyEventState, ZwlrDataControlSourceV1>) {
     match ctx.event {
         zwlr_data_control_source_v1::Event::Send(zwlr_data_control_source_v1::SendArgs {
@@ -237,15 +570,33 @@ fn wl_source_cb_for_copy(ctx: EventCtx<CopyEventState, ZwlrDataControlSourceV1>)
             fd,
         }) => {
             log::debug!("Received 'Send' event");
+            let start_time = Instant::now();
+            ctx.state.last_request = start_time;
             let src_data = &ctx.state.source_data;
             let mut file = File::from(fd);
-            let (_, content) = src_data.content_by_mime_type(mime_type.to_str().unwrap());
-            file.write_all(&content).unwrap();
+            let (_, content) = src_data.content_by_mime_type(&mime_type.to_string_lossy());
+            // The content is sent as it is, and a requestor which stops reading doesn't stop
+            // serving the others
+            match file.write_all(&content) {
+                Ok(()) => {
+                    log::debug!("Sent {} bytes in {:?}", content.len(), start_time.elapsed())
+                }
+                Err(e) => log::error!("Failed to write the content, {e}"),
+            }
+            if ctx.state.serve_once {
+                log::debug!("Content has been served once, exit");
+                ctx.conn.break_dispatch_loop();
+                ctx.state.finished = true;
+            }
         }
         zwlr_data_control_source_v1::Event::Cancelled => {
             log::debug!("Received 'Cancelled' event");
-            ctx.conn.break_dispatch_loop();
-            ctx.state.finished = true;
+            // Keep serving the other selection, if both are set
+            ctx.state.active_sources -= 1;
+            if ctx.state.active_sources == 0 {
+                ctx.conn.break_dispatch_loop();
+                ctx.state.finished = true;
+            }
         }
         _ => unreachable!("Unexpected event for source callback"),
     }
@@ -0,0 +1,605 @@
+// Fallback backend with the core wl_data_device protocol, for the compositors which don't support
+// wlr-data-control, e.g. GNOME.
+//
+// The core protocol only allows the client with the keyboard focus to access the clipboard. To get
+// the focus, a 1x1 window is created and mapped. This steals the focus from the current window for
+// a short moment, and the compositor may show the window or refuse to focus it at all.
+//
+// The primary selection is accessed with the zwp_primary_selection protocol the same way.
+use super::Capabilities;
+use super::ClipBackend;
+use super::CopyConfig;
+use super::PasteConfig;
+use super::ServeDeadline;
+use super::check_interrupted;
+use super::connect_with_retries;
+use super::copy_from_pipe;
+use super::measure_content_sizes;
+use super::mime_type::{OfferedTypes, decide_mime_type_in_order, mime_type_by_index};
+use super::wait_fd_readable;
+use crate::protocol::SourceData;
+use anyhow::{Context, Result, bail};
+use nix::unistd::pipe;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{ErrorKind, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+use wayrs_client::core::ObjectId;
+use wayrs_client::protocol::wl_compositor::WlCompositor;
+use wayrs_client::protocol::wl_data_device::{self, WlDataDevice};
+use wayrs_client::protocol::wl_data_device_manager::WlDataDeviceManager;
+use wayrs_client::protocol::wl_data_offer::{self, WlDataOffer};
+use wayrs_client::protocol::wl_data_source::{self, WlDataSource};
+use wayrs_client::protocol::wl_keyboard::{self, WlKeyboard};
+use wayrs_client::protocol::wl_seat::WlSeat;
+use wayrs_client::protocol::wl_shm::{self, WlShm};
+use wayrs_client::protocol::wl_surface::WlSurface;
+use wayrs_client::{Connection, EventCtx, IoMode};
+use wayrs_protocols::primary_selection_unstable_v1::{
+    ZwpPrimarySelectionDeviceManagerV1,
+    zwp_primary_selection_device_v1::{self, ZwpPrimarySelectionDeviceV1},
+    zwp_primary_selection_offer_v1::{self, ZwpPrimarySelectionOfferV1},
+    zwp_primary_selection_source_v1::{self, ZwpPrimarySelectionSourceV1},
+};
+use wayrs_protocols::xdg_shell::{
+    XdgWmBase,
+    xdg_surface::{self, XdgSurface},
+    xdg_toplevel::XdgToplevel,
+    xdg_wm_base,
+};
+
+pub struct CoreWaylandBackend {}
+
+pub fn test_protocol_available() -> bool {
+    create_core_client::<()>().is_ok()
+}
+
+pub fn test_primary_available() -> bool {
+    create_core_client::<()>().is_ok_and(|client| client.primary_sel_mgr.is_some())
+}
+
+struct CoreClient<T> {
+    conn: Connection<T>,
+    seat: WlSeat,
+    data_dev_mgr: WlDataDeviceManager,
+    compositor: WlCompositor,
+    shm: WlShm,
+    wm_base: XdgWmBase,
+    // For the primary selection, it is optional
+    primary_sel_mgr: Option<ZwpPrimarySelectionDeviceManagerV1>,
+}
+
+// The tiny window to get the keyboard focus
+struct FocusWindow {
+    surface: WlSurface,
+    xdg_surface: XdgSurface,
+    toplevel: XdgToplevel,
+}
+
+struct CoreCopyState {
+    // The serial of the keyboard enter event, required by set_selection
+    focus_serial: Option<u32>,
+    finished: bool,
+    serve_once: bool,
+    source_data: Box<dyn SourceData>,
+    // For the idle timeout
+    last_request: Instant,
+}
+
+// The offer of either the clipboard or the primary selection
+struct CorePasteState<O> {
+    focus_serial: Option<u32>,
+    offers: HashMap<O, OfferedTypes>,
+    // None before receiving the selection event. Some(None) means the clipboard is empty.
+    selection: Option<Option<ObjectId>>,
+    config: PasteConfig,
+}
+
+trait FocusState {
+    fn set_focus_serial(&mut self, serial: u32);
+}
+
+trait SelectionOffer: Copy + Eq + Hash + Borrow<ObjectId> + 'static {
+    fn receive_content(
+        self,
+        conn: &mut Connection<CorePasteState<Self>>,
+        mime: CString,
+        fd: OwnedFd,
+    );
+}
+
+impl SelectionOffer for WlDataOffer {
+    fn receive_content(
+        self,
+        conn: &mut Connection<CorePasteState<Self>>,
+        mime: CString,
+        fd: OwnedFd,
+    ) {
+        self.receive(conn, mime, fd);
+    }
+}
+
+impl SelectionOffer for ZwpPrimarySelectionOfferV1 {
+    fn receive_content(
+        self,
+        conn: &mut Connection<CorePasteState<Self>>,
+        mime: CString,
+        fd: OwnedFd,
+    ) {
+        self.receive(conn, mime, fd);
+    }
+}
+
+// The source of either the clipboard or the primary selection
+enum CoreSource {
+    Clipboard(WlDataSource),
+    Primary(ZwpPrimarySelectionSourceV1),
+}
+
+impl CoreSource {
+    fn destroy(self, conn: &mut Connection<CoreCopyState>) {
+        match self {
+            CoreSource::Clipboard(source) => source.destroy(conn),
+            CoreSource::Primary(source) => source.destroy(conn),
+        }
+    }
+}
+
+impl FocusState for CoreCopyState {
+    fn set_focus_serial(&mut self, serial: u32) {
+        self.focus_serial = Some(serial);
+    }
+}
+
+impl<O> FocusState for CorePasteState<O> {
+    fn set_focus_serial(&mut self, serial: u32) {
+        self.focus_serial = Some(serial);
+    }
+}
+
+impl ClipBackend for CoreWaylandBackend {
+    fn name(&self) -> &'static str {
+        "core-wayland"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            primary_selection: test_primary_available(),
+            watch: false,
+            image_types: true,
+            incr: false,
+            multiple_items: false,
+        }
+    }
+
+    fn copy(&self, config: CopyConfig) -> Result<()> {
+        copy_wayland_core(config)
+    }
+
+    fn paste(&self, config: PasteConfig) -> Result<()> {
+        paste_wayland_core(config)
+    }
+
+    fn content_sizes(&self, use_primary: bool) -> Result<Vec<(String, Option<u64>)>> {
+        // Every paste maps the focus window again
+        log::warn!("Receiving the content of every offered mime-type to measure the sizes");
+        measure_content_sizes(self, use_primary)
+    }
+}
+
+fn create_core_client<T>() -> Result<CoreClient<T>> {
+    let mut conn = connect_with_retries(|| {
+        Connection::<T>::connect().context("Failed to create wayland connection")
+    })?;
+    conn.blocking_roundtrip()
+        .context("Failed to call 'blocking_roundtrip'")?;
+
+    let seat: WlSeat = conn
+        .bind_singleton(2..=4)
+        .context("Failed to bind Wayland seat")?;
+    let data_dev_mgr: WlDataDeviceManager = conn
+        .bind_singleton(1..=3)
+        .context("Failed to bind data device manager")?;
+    let compositor: WlCompositor = conn
+        .bind_singleton(1..=4)
+        .context("Failed to bind compositor")?;
+    let shm: WlShm = conn.bind_singleton(1).context("Failed to bind shm")?;
+    let wm_base: XdgWmBase = conn
+        .bind_singleton(1..=2)
+        .context("Failed to bind xdg_wm_base")?;
+    let primary_sel_mgr = conn.bind_singleton(1).ok();
+
+    Ok(CoreClient::<T> {
+        conn,
+        seat,
+        data_dev_mgr,
+        compositor,
+        shm,
+        wm_base,
+        primary_sel_mgr,
+    })
+}
+
+// How long to wait for the keyboard focus, if the paste has no timeout
+const FOCUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn get_primary_sel_mgr<T>(client: &CoreClient<T>) -> Result<ZwpPrimarySelectionDeviceManagerV1> {
+    client
+        .primary_sel_mgr
+        .context("The primary selection is not supported (zwp_primary_selection_unstable_v1 protocol may not be available)")
+}
+
+fn create_focus_window<T: FocusState + 'static>(client: &mut CoreClient<T>) -> Result<FocusWindow> {
+    client
+        .conn
+        .set_callback_for(client.wm_base, |ctx: EventCtx<T, XdgWmBase>| {
+            if let xdg_wm_base::Event::Ping(serial) = ctx.event {
+                ctx.proxy.pong(ctx.conn, serial);
+            }
+        });
+    let _keyboard: WlKeyboard =
+        client
+            .seat
+            .get_keyboard_with_cb(&mut client.conn, |ctx: EventCtx<T, WlKeyboard>| {
+                if let wl_keyboard::Event::Enter(args) = ctx.event {
+                    log::debug!("Got the keyboard focus");
+                    ctx.state.set_focus_serial(args.serial);
+                }
+            });
+
+    // A 1x1 transparent buffer to map the window
+    let buffer = {
+        // SAFETY: The returned fd is checked before taking the ownership
+        let fd = unsafe { libc::memfd_create(c"richclip".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            bail!("Failed to call memfd_create");
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        File::from(fd.try_clone()?).set_len(4)?;
+        let pool = client.shm.create_pool(&mut client.conn, fd, 4);
+        let buffer = pool.create_buffer(&mut client.conn, 0, 1, 1, 4, wl_shm::Format::Argb8888);
+        pool.destroy(&mut client.conn);
+        buffer
+    };
+
+    let surface = client.compositor.create_surface(&mut client.conn);
+    let xdg_surface = client.wm_base.get_xdg_surface_with_cb(
+        &mut client.conn,
+        surface,
+        move |ctx: EventCtx<T, XdgSurface>| {
+            if let xdg_surface::Event::Configure(serial) = ctx.event {
+                ctx.proxy.ack_configure(ctx.conn, serial);
+                surface.attach(ctx.conn, Some(buffer), 0, 0);
+                surface.commit(ctx.conn);
+            }
+        },
+    );
+    let toplevel = xdg_surface.get_toplevel(&mut client.conn);
+    toplevel.set_title(&mut client.conn, c"richclip".to_owned());
+    surface.commit(&mut client.conn);
+
+    Ok(FocusWindow {
+        surface,
+        xdg_surface,
+        toplevel,
+    })
+}
+
+/// Receive the events while waiting for the keyboard focus, which the compositor may never give.
+fn recv_focus_events<T>(
+    conn: &mut Connection<T>,
+    deadline: Instant,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    conn.flush(IoMode::Blocking)?;
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    if !wait_fd_readable(conn.as_raw_fd(), timeout)? {
+        bail!(
+            "Timed out waiting for the keyboard focus, the compositor may refuse to focus the \
+            window of richclip"
+        );
+    }
+    match conn.recv_events(IoMode::NonBlocking) {
+        // Woken up without any event, by a signal
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+            check_interrupted(Err(ErrorKind::Interrupted.into()), writer)
+        }
+        r => check_interrupted(r, writer),
+    }
+}
+
+fn destroy_focus_window<T>(client: &mut CoreClient<T>, window: FocusWindow) {
+    window.toplevel.destroy(&mut client.conn);
+    window.xdg_surface.destroy(&mut client.conn);
+    window.surface.destroy(&mut client.conn);
+}
+
+pub(super) fn paste_wayland_core(cfg: PasteConfig) -> Result<()> {
+    if cfg.wayland_wait_for_change {
+        bail!("Waiting for the change is only supported with wlr_data_control");
+    }
+    if cfg.wayland_all_selections {
+        bail!("Listing all the selections is only supported with wlr_data_control");
+    }
+    if cfg.x_owner_info {
+        bail!("The selection owner info is only supported on X, try 'RICHCLIP_PREFER=x11'");
+    }
+    if cfg.use_primary {
+        let mut client = create_core_client::<CorePasteState<ZwpPrimarySelectionOfferV1>>()
+            .context("Failed to create wayland client")?;
+        log::debug!("Use zwp_primary_selection for the primary selection");
+        let _device = get_primary_sel_mgr(&client)?.get_device_with_cb(
+            &mut client.conn,
+            client.seat,
+            primary_device_cb_for_paste,
+        );
+        paste_selection(client, cfg)
+    } else {
+        let mut client = create_core_client::<CorePasteState<WlDataOffer>>()
+            .context("Failed to create wayland client")?;
+        let _data_device = client.data_dev_mgr.get_data_device_with_cb(
+            &mut client.conn,
+            client.seat,
+            wl_device_cb_for_paste,
+        );
+        paste_selection(client, cfg)
+    }
+}
+
+fn paste_selection<O: SelectionOffer>(
+    mut client: CoreClient<CorePasteState<O>>,
+    cfg: PasteConfig,
+) -> Result<()> {
+    let start_time = Instant::now();
+    let window = create_focus_window(&mut client)?;
+    let focus_deadline = start_time + cfg.timeout.unwrap_or(FOCUS_TIMEOUT);
+
+    let mut state = CorePasteState {
+        focus_serial: None,
+        offers: HashMap::new(),
+        selection: None,
+        config: cfg,
+    };
+
+    // The selection event is sent right before the keyboard focus is received
+    let selection_id = loop {
+        if let (Some(_), Some(selection)) = (state.focus_serial, state.selection) {
+            match selection {
+                Some(id) => break id,
+                None => {
+                    log::error!("No data in the clipboard");
+                    destroy_focus_window(&mut client, window);
+                    return Ok(());
+                }
+            }
+        }
+
+        recv_focus_events(&mut client.conn, focus_deadline, &mut state.config.writer)?;
+        client.conn.dispatch_events(&mut state);
+    };
+    destroy_focus_window(&mut client, window);
+
+    let (offer, supported_types) = state.offers.get_key_value(&selection_id).unwrap();
+    state.config.check_offered_types(supported_types)?;
+
+    if state.config.list_types_only {
+        state.config.write_type_list(supported_types)?;
+        return Ok(());
+    }
+
+    let decided = match state.config.target_index {
+        Some(index) => Ok(mime_type_by_index(index, supported_types)?),
+        None => decide_mime_type_in_order(&state.config.expected_mime_types, supported_types),
+    };
+    let mime_type = if let Ok(type_str) = decided {
+        state.config.prepare_writer(&type_str);
+        CString::new(type_str)?
+    } else {
+        return Err(state.config.not_offered_error());
+    };
+
+    let (pipe_read, pipe_write) = pipe()?;
+    offer.receive_content(&mut client.conn, mime_type, pipe_write);
+    client.conn.flush(IoMode::Blocking)?;
+
+    let pipe_read = File::from(pipe_read);
+    let deadline = state.config.timeout.map(|timeout| start_time + timeout);
+    copy_from_pipe(pipe_read, &mut state.config.writer, deadline)?;
+    state.config.writer.flush()?;
+
+    Ok(())
+}
+
+pub(super) fn copy_wayland_core(config: CopyConfig) -> Result<()> {
+    if config.both_selections {
+        bail!("Copying to both selections is not supported by the core Wayland protocol");
+    }
+    let deadline = ServeDeadline::new(&config, Instant::now());
+    let mut client =
+        create_core_client::<CoreCopyState>().context("Failed to create wayland client")?;
+    // Fail before stealing the focus
+    let primary_sel_mgr = if config.use_primary {
+        Some(get_primary_sel_mgr(&client)?)
+    } else {
+        None
+    };
+
+    let window = create_focus_window(&mut client)?;
+    let focus_deadline = Instant::now() + FOCUS_TIMEOUT;
+
+    let mut state = CoreCopyState {
+        focus_serial: None,
+        finished: false,
+        serve_once: config.serve_once,
+        source_data: config.source_data,
+        last_request: Instant::now(),
+    };
+
+    let serial = loop {
+        if let Some(serial) = state.focus_serial {
+            break serial;
+        }
+        recv_focus_events(&mut client.conn, focus_deadline, &mut std::io::sink())?;
+        client.conn.dispatch_events(&mut state);
+    };
+
+    let mime_types = state.source_data.mime_types();
+    let source = match primary_sel_mgr {
+        Some(mgr) => {
+            log::debug!("Use zwp_primary_selection for the primary selection");
+            let device = mgr.get_device(&mut client.conn, client.seat);
+            let source = mgr.create_source_with_cb(&mut client.conn, primary_source_cb_for_copy);
+            for mime in &mime_types {
+                source.offer(&mut client.conn, CString::new(mime.as_bytes())?);
+            }
+            device.set_selection(&mut client.conn, Some(source), serial);
+            CoreSource::Primary(source)
+        }
+        None => {
+            let data_device = client
+                .data_dev_mgr
+                .get_data_device(&mut client.conn, client.seat);
+            let source = client
+                .data_dev_mgr
+                .create_data_source_with_cb(&mut client.conn, wl_source_cb_for_copy);
+            for mime in &mime_types {
+                source.offer(&mut client.conn, CString::new(mime.as_bytes())?);
+            }
+            data_device.set_selection(&mut client.conn, Some(source), serial);
+            CoreSource::Clipboard(source)
+        }
+    };
+    // The selection stays after losing the focus, give it back.
+    destroy_focus_window(&mut client, window);
+
+    client.conn.flush(IoMode::Blocking)?;
+    while !state.finished {
+        if let Some(deadline) = deadline.at(state.last_request) {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if !wait_fd_readable(client.conn.as_raw_fd(), timeout)? {
+                log::debug!("The content expired");
+                if config.clear_on_expire {
+                    // Setting the selection needs the focus again, destroying the source clears
+                    // the selection as well.
+                    source.destroy(&mut client.conn);
+                    client.conn.flush(IoMode::Blocking)?;
+                    state.source_data.clear_content();
+                }
+                break;
+            }
+        }
+        check_interrupted(
+            client.conn.recv_events(IoMode::Blocking),
+            &mut std::io::sink(),
+        )?;
+        client.conn.dispatch_events(&mut state);
+    }
+
+    Ok(())
+}
+
+/// Add the mime-type reported by the offer.
+fn add_offered_type(mime_types: &mut OfferedTypes, mime_type: &CStr) {
+    if mime_type.to_bytes().is_empty() {
+        log::debug!("Skip the empty mime-type");
+    } else if let Ok(str) = mime_type.to_str() {
+        if !mime_types.push(str.to_string()) {
+            log::debug!("Skip the duplicated mime-type '{str}'");
+        }
+    } else {
+        log::error!("Failed to convert '{:x?}' to String", mime_type.to_bytes());
+    }
+}
+
+fn wl_device_cb_for_paste(ctx: EventCtx<CorePasteState<WlDataOffer>, WlDataDevice>) {
+    match ctx.event {
+        wl_data_device::Event::DataOffer(offer) => {
+            ctx.state.offers.insert(offer, OfferedTypes::default());
+            ctx.conn.set_callback_for(offer, |ctx| {
+                if let wl_data_offer::Event::Offer(mime_type) = ctx.event {
+                    let mime_types = ctx.state.offers.get_mut(&ctx.proxy).unwrap();
+                    add_offered_type(mime_types, &mime_type);
+                }
+            });
+        }
+        wl_data_device::Event::Selection(o) => {
+            ctx.state.selection = Some(o);
+        }
+        // Drag and drop events
+        _ => (),
+    }
+}
+
+fn primary_device_cb_for_paste(
+    ctx: EventCtx<CorePasteState<ZwpPrimarySelectionOfferV1>, ZwpPrimarySelectionDeviceV1>,
+) {
+    match ctx.event {
+        zwp_primary_selection_device_v1::Event::DataOffer(offer) => {
+            ctx.state.offers.insert(offer, OfferedTypes::default());
+            ctx.conn.set_callback_for(offer, |ctx| {
+                let zwp_primary_selection_offer_v1::Event::Offer(mime_type) = ctx.event;
+                let mime_types = ctx.state.offers.get_mut(&ctx.proxy).unwrap();
+                add_offered_type(mime_types, &mime_type);
+            });
+        }
+        zwp_primary_selection_device_v1::Event::Selection(o) => {
+            ctx.state.selection = Some(o);
+        }
+    }
+}
+
+/// Write the content to the fd of the 'Send' event.
+fn send_content(state: &mut CoreCopyState, mime_type: &CStr, fd: OwnedFd) {
+    log::debug!("Received 'Send' event");
+    state.last_request = Instant::now();
+    let mut file = File::from(fd);
+    let (_, content) = state
+        .source_data
+        .content_by_mime_type(&mime_type.to_string_lossy());
+    if let Err(e) = file.write_all(&content) {
+        log::error!("Failed to write the content, {e}");
+    }
+    if state.serve_once {
+        state.finished = true;
+    }
+}
+
+fn primary_source_cb_for_copy(ctx: EventCtx<CoreCopyState, ZwpPrimarySelectionSourceV1>) {
+    match ctx.event {
+        zwp_primary_selection_source_v1::Event::Send(
+            zwp_primary_selection_source_v1::SendArgs { mime_type, fd },
+        ) => {
+            send_content(ctx.state, &mime_type, fd);
+            if ctx.state.finished {
+                ctx.conn.break_dispatch_loop();
+            }
+        }
+        zwp_primary_selection_source_v1::Event::Cancelled => {
+            log::debug!("Received 'Cancelled' event");
+            ctx.conn.break_dispatch_loop();
+            ctx.state.finished = true;
+        }
+    }
+}
+
+fn wl_source_cb_for_copy(ctx: EventCtx<CoreCopyState, WlDataSource>) {
+    match ctx.event {
+        wl_data_source::Event::Send(wl_data_source::SendArgs { mime_type, fd }) => {
+            send_content(ctx.state, &mime_type, fd);
+            if ctx.state.finished {
+                ctx.conn.break_dispatch_loop();
+            }
+        }
+        wl_data_source::Event::Cancelled => {
+            log::debug!("Received 'Cancelled' event");
+            ctx.conn.break_dispatch_loop();
+            ctx.state.finished = true;
+        }
+        // Drag and drop events
+        _ => (),
+    }
+}
@@ -1,20 +1,36 @@
+use super::Capabilities;
 use super::ClipBackend;
 use super::CopyConfig;
 use super::PasteConfig;
-use super::mime_type::decide_mime_type;
+use super::PasteError;
+use super::ServeDeadline;
+use super::WatchConfig;
+use super::connect_with_retries;
+use super::mime_type::{
+    decide_mime_type, decide_mime_type_in_order, escape_mime_type, is_text_mime_type,
+    mime_type_by_index, mime_type_matches,
+};
+use super::wait_fd_readable;
+use super::{X_CHUNK_SIZE_ENV, size_from_env};
+use super::{paste_cancelled, paste_cancelled_error};
 use crate::protocol::SourceData;
 use anyhow::{Context, Result, bail};
 use std::collections::hash_map::HashMap;
 use std::io::Write;
+use std::os::fd::AsRawFd;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 use x11rb::atom_manager;
 use x11rb::connection::Connection;
 use x11rb::connection::RequestConnection;
 use x11rb::protocol::Event;
+use x11rb::protocol::xfixes::{
+    ConnectionExt as XfixesConnectionExt, SelectionEvent, SelectionEventMask,
+};
 use x11rb::protocol::xproto::{
-    Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, CreateWindowAux, EventMask, PropMode,
-    Property, SELECTION_NOTIFY_EVENT, SelectionNotifyEvent, SelectionRequestEvent, Window,
-    WindowClass,
+    Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, CreateWindowAux, EventMask,
+    GetPropertyReply, PropMode, Property, SELECTION_NOTIFY_EVENT, SelectionNotifyEvent,
+    SelectionRequestEvent, Window, WindowClass,
 };
 use x11rb::rust_connection::RustConnection;
 use x11rb::wrapper::ConnectionExt as WrapperConnectionExt;
@@ -27,6 +43,9 @@ atom_manager! {
         // For the selection type
         PRIMARY,
         CLIPBOARD,
+        // Owned by the clipboard manager, which takes over the clipboard content when its owner
+        // exits
+        CLIPBOARD_MANAGER,
         // For selection content mime-type, AKA the target
         TARGETS,
         // Our defined atom for getting prop
@@ -49,7 +68,50 @@ struct XPasteState {
     config: PasteConfig,
     // Translate the config.primary
     selection: Atom,
-    receiver: Option<XSelectionReceiver<u8>>,
+    // The in-flight conversions, keyed by the property
+    receivers: HashMap<Atom, XSelectionReceiver<u8>>,
+    // For the timing logs
+    received_bytes: usize,
+    // When the last INCR chunk was received, for the chunk timeout
+    last_chunk_time: Instant,
+}
+
+impl XPasteState {
+    /// Find the property of the receiver which the event is for. A refused conversion is
+    /// notified with the `None` property, then the receiver is found by the target.
+    fn receiver_property(&self, property: Atom, target: Option<Atom>) -> Option<Atom> {
+        if property != u32::from(AtomEnum::NONE) {
+            return self.receivers.contains_key(&property).then_some(property);
+        }
+        let target = target?;
+        self.receivers
+            .iter()
+            .find(|(_, receiver)| receiver.expected_type == target)
+            .map(|(property, _)| *property)
+    }
+
+    /// Remove the finished receiver. Return true if all conversions are finished.
+    fn finish_receiver(&mut self, property: Atom) -> bool {
+        if let Some(receiver) = self.receivers.remove(&property) {
+            self.received_bytes += receiver.received_bytes;
+        }
+        self.receivers.is_empty()
+    }
+
+    /// The deadline of the next INCR chunk, if any conversion is in INCR mode.
+    fn chunk_deadline(&self) -> Option<Instant> {
+        let timeout = self.config.x_chunk_timeout?;
+        self.receivers
+            .values()
+            .any(|receiver| receiver.is_incr)
+            .then(|| self.last_chunk_time + timeout)
+    }
+
+    /// The bytes received so far, including the unfinished conversions.
+    fn partial_received_bytes(&self) -> usize {
+        let receiving: usize = self.receivers.values().map(|r| r.received_bytes).sum();
+        self.received_bytes + receiving
+    }
 }
 
 // For the INCR process, see:
@@ -79,6 +141,8 @@ struct XSelectionSender {
     chunk_size: usize,
     // The current content offset for INCR mode. Initialized with MAX value.
     offset: usize,
+    // For the timing logs
+    start_time: Instant,
 }
 
 struct XSelectionReceiver<T> {
@@ -92,14 +156,35 @@ struct XSelectionReceiver<T> {
     chunk_size: u32,
     // INCR flag
     is_incr: bool,
+    // For the timing logs
+    received_bytes: usize,
+    start_time: Instant,
 }
 
 struct XCopyState {
     source_data: Box<dyn SourceData>,
     ongoing_senders: HashMap<Window, XSelectionSender>,
+    // For the timing logs
+    sent_bytes: usize,
+    // If any content (not TARGETS) has been sent completely
+    served: bool,
 }
 
 impl ClipBackend for XBackend {
+    fn name(&self) -> &'static str {
+        "x"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            primary_selection: true,
+            watch: true,
+            image_types: true,
+            incr: true,
+            multiple_items: false,
+        }
+    }
+
     fn copy(&self, config: CopyConfig) -> Result<()> {
         copy_x(config)
     }
@@ -107,6 +192,10 @@ impl ClipBackend for XBackend {
     fn paste(&self, config: PasteConfig) -> Result<()> {
         paste_x(config)
     }
+
+    fn watch(&self, config: WatchConfig) -> Result<()> {
+        watch_x(config)
+    }
 }
 
 impl XSelectionSender {
@@ -132,12 +221,53 @@ impl XSelectionSender {
             content,
             chunk_size: cs,
             offset: usize::MAX,
+            start_time: Instant::now(),
         }
     }
 
     fn get_chunk_size(conn: &RustConnection) -> usize {
+        let maximum_request_bytes = conn.maximum_request_bytes();
+        match size_from_env(X_CHUNK_SIZE_ENV) {
+            Some(size) => Self::chunk_size_from_env(size, maximum_request_bytes),
+            None => Self::chunk_size_for_request_bytes(maximum_request_bytes),
+        }
+    }
+
+    /// The chunk size set by `RICHCLIP_X_CHUNK_SIZE`, which must fit in a single request.
+    fn chunk_size_from_env(size: usize, maximum_request_bytes: usize) -> usize {
+        // The fixed part of a ChangeProperty request
+        const CHANGE_PROPERTY_HEADER: usize = 24;
+        let limit = maximum_request_bytes.saturating_sub(CHANGE_PROPERTY_HEADER);
+        if size > limit {
+            log::warn!("{X_CHUNK_SIZE_ENV} {size} is too large, use chunk size {limit}");
+            return limit;
+        }
+        size
+    }
+
+    fn chunk_size_for_request_bytes(maximum_request_bytes: usize) -> usize {
+        // The X server must accept the requests of at least 4096 * 4 bytes, see the
+        // 'maximum-request-length' of the connection setup.
+        const MIN_CHUNK_SIZE: usize = 4096;
         // See xclip.c::xcin()
-        conn.maximum_request_bytes() / 4
+        let chunk_size = maximum_request_bytes / 4;
+        if chunk_size < MIN_CHUNK_SIZE {
+            log::warn!(
+                "Unexpected maximum request bytes {maximum_request_bytes}, use chunk size {MIN_CHUNK_SIZE}"
+            );
+            return MIN_CHUNK_SIZE;
+        }
+        chunk_size
+    }
+
+    // The content which is not smaller than a chunk is sent in INCR mode.
+    fn is_incr(&self) -> bool {
+        self.chunk_size <= self.content.len()
+    }
+
+    // The end of the next chunk to be sent from the current offset.
+    fn next_chunk_end(&self) -> usize {
+        chunk_end(self.offset, self.chunk_size, self.content.len())
     }
 
     // The sending is actually calling X window change_property API, and the other side could use
@@ -148,12 +278,7 @@ impl XSelectionSender {
             self.content.len(),
             self.offset
         );
-        let left_bytes = self.content.len() - self.offset;
-        let end_pos = if self.chunk_size > left_bytes {
-            self.offset + left_bytes
-        } else {
-            self.offset + self.chunk_size
-        };
+        let end_pos = self.next_chunk_end();
         let to_send = &self.content[self.offset..end_pos];
 
         conn.change_property8(
@@ -168,7 +293,7 @@ impl XSelectionSender {
     }
 
     fn send(&mut self, client: &XClient, time: u32) -> Result<TransferResult> {
-        if self.chunk_size > self.content.len() {
+        if !self.is_incr() {
             self.offset = 0;
             self.change_property_to_send(&client.conn)?;
             client.conn.send_event(
@@ -185,7 +310,11 @@ impl XSelectionSender {
                     property: self.property,
                 },
             )?;
-            client.conn.flush()?;
+            log::debug!(
+                "Sent {} bytes in {:?}",
+                self.content.len(),
+                self.start_time.elapsed()
+            );
             return Ok(TransferResult::Done);
         } else if self.offset == usize::MAX {
             return self.send_incr_begin(client, time);
@@ -223,16 +352,22 @@ impl XSelectionSender {
                 property: self.property,
             },
         )?;
-        client.conn.flush()?;
         Ok(TransferResult::Continue)
     }
 
     fn send_incr(&mut self, client: &XClient) -> Result<TransferResult> {
         log::debug!("send_incr");
+        let chunk_start = Instant::now();
+        // All data has been sent, the zero-length property marks the end of the transfer.
+        let is_last = self.offset == self.content.len();
         self.change_property_to_send(&client.conn)?;
-        client.conn.flush()?;
-        if self.offset > self.content.len() {
-            log::debug!("send_incr finished");
+        log::debug!("Queued INCR chunk in {:?}", chunk_start.elapsed());
+        if is_last {
+            log::debug!(
+                "send_incr finished, sent {} bytes in {:?}",
+                self.content.len(),
+                self.start_time.elapsed()
+            );
             Ok(TransferResult::Done)
         } else {
             Ok(TransferResult::Continue)
@@ -250,6 +385,8 @@ impl<T> XSelectionReceiver<T> {
             buffer: Vec::<T>::new(),
             chunk_size: DEFAULT_CHUNK_SIZE,
             is_incr: false,
+            received_bytes: 0,
+            start_time: Instant::now(),
         }
     }
 }
@@ -273,14 +410,7 @@ impl XSelectionReceiver<u32> {
             bail!("Receiving ATOMS TARGETS in INCR mode is not supported and should not happen");
         }
 
-        if let Some(it) = reply.value32() {
-            for v in it {
-                self.buffer.push(v)
-            }
-        } else {
-            log::debug!("Empty property received")
-        }
-
+        self.buffer.extend(atoms_from_property(&reply));
         Ok(TransferResult::Done)
     }
 }
@@ -321,9 +451,26 @@ impl XSelectionReceiver<u8> {
             return Ok(TransferResult::Continue);
         }
 
-        writer
-            .write(&reply.value)
-            .context("Failed to write to the output")?;
+        self.received_bytes += write_property_value(reply, &mut writer, |offset| {
+            log::debug!("Read the rest of the property from offset {offset}");
+            Ok(client
+                .conn
+                .get_property(
+                    false,
+                    self.receiver,
+                    self.property,
+                    self.expected_type,
+                    offset,
+                    self.chunk_size,
+                )?
+                .reply()?)
+        })?;
+        writer.flush()?;
+        log::debug!(
+            "Received {} bytes in {:?}",
+            self.received_bytes,
+            self.start_time.elapsed()
+        );
 
         Ok(TransferResult::Done)
     }
@@ -355,10 +502,16 @@ impl XSelectionReceiver<u8> {
             log::debug!("No more data to receive. Delete the property to finish");
             client.conn.delete_property(self.receiver, self.property)?;
             writer.flush()?;
+            log::debug!(
+                "Received {} bytes in INCR mode in {:?}",
+                self.received_bytes,
+                self.start_time.elapsed()
+            );
             return Ok(TransferResult::Done);
         }
 
         // Retrieve data
+        let chunk_start = Instant::now();
         let reply = client
             .conn
             .get_property(
@@ -379,13 +532,51 @@ impl XSelectionReceiver<u8> {
             return Ok(TransferResult::Continue);
         }
         writer
-            .write(&reply.value)
+            .write_all(&reply.value)
             .context("Failed to write to the output")?;
+        self.received_bytes += reply.value.len();
+        log::debug!(
+            "Received INCR chunk of {} bytes in {:?}",
+            reply.value.len(),
+            chunk_start.elapsed()
+        );
 
         Ok(TransferResult::Continue)
     }
 }
 
+/// Write the value of the property to the writer, and keep reading the rest of it while the reply
+/// has `bytes_after`. The owner should switch to INCR for the large content, but some put it in a
+/// single property which is larger than one `get_property` reply. The offset passed to `get_more`
+/// is in 32-bit units like `get_property`. Return the number of written bytes.
+fn write_property_value(
+    mut reply: GetPropertyReply,
+    writer: &mut impl Write,
+    mut get_more: impl FnMut(u32) -> Result<GetPropertyReply>,
+) -> Result<usize> {
+    let mut offset = 0;
+    let mut written = 0;
+    loop {
+        writer
+            .write_all(&reply.value)
+            .context("Failed to write to the output")?;
+        written += reply.value.len();
+        if reply.bytes_after == 0 {
+            return Ok(written);
+        }
+        // With bytes after, the reply value is always a multiple of 4 bytes
+        if reply.value.is_empty() || !reply.value.len().is_multiple_of(4) {
+            bail!(
+                "Unexpected property reply of {} bytes with {} bytes after",
+                reply.value.len(),
+                reply.bytes_after
+            );
+        }
+        offset += (reply.value.len() / 4) as u32;
+        reply = get_more(offset)?;
+    }
+}
+
 fn get_atom_id_by_name(conn: &RustConnection, name: &str) -> Result<Atom> {
     let result = conn.intern_atom(false, name.as_bytes()).context("")?;
     let id = result.reply().context("")?;
@@ -408,19 +599,41 @@ fn targets_to_strings(
 ) -> Result<Vec<String>> {
     let mut ret = Vec::<String>::new();
     for v in &receiver.buffer {
-        match get_atom_name(&client.conn, *v) {
-            Ok(name) => {
-                ret.push(name);
-            }
-            Err(error) => {
-                log::error!("Failed to get name for Atom '{}', error: {}", v, error);
-            }
-        }
+        ret.push(target_name(*v, get_atom_name(&client.conn, *v)));
     }
 
     Ok(ret)
 }
 
+const UNKNOWN_ATOM_PREFIX: &str = "Unknown-Atom-";
+
+/// Use a placeholder for the target whose name cannot be fetched, so it is still listed and can
+/// be pasted by the placeholder or `--target-atom`.
+fn target_name(atom: Atom, name: Result<String>) -> String {
+    name.unwrap_or_else(|e| {
+        log::debug!("Failed to get name for Atom '{atom}', error: {e}");
+        format!("{UNKNOWN_ATOM_PREFIX}{atom}")
+    })
+}
+
+/// The reverse of the placeholder created by `target_name`.
+fn parse_unknown_atom(name: &str) -> Option<Atom> {
+    name.strip_prefix(UNKNOWN_ATOM_PREFIX)?.parse().ok()
+}
+
+/// Parse the atoms of the TARGETS property. x11rb sets up the connection in the native byte
+/// order, so the atoms written by `change_property32` are read back as native-endian u32, and the
+/// server swaps them for the clients of the other byte order.
+fn atoms_from_property(reply: &GetPropertyReply) -> Vec<Atom> {
+    match reply.value32() {
+        Some(it) => it.collect(),
+        None => {
+            log::debug!("Empty property received");
+            vec![]
+        }
+    }
+}
+
 fn mime_types_to_targets(conn: &RustConnection, mime_types: &Vec<String>) -> Vec<u32> {
     let mut ret = vec![];
     for str in mime_types {
@@ -435,19 +648,103 @@ fn mime_types_to_targets(conn: &RustConnection, mime_types: &Vec<String>) -> Vec
     ret
 }
 
+// How many times the primary selection can be taken back with `x_persist_primary`, so two
+// clients persisting the primary selection don't fight forever
+const MAX_PRIMARY_REACQUIRES: usize = 10;
+
+// The classic X apps only ask for these targets for text
+const X_TEXT_TARGETS: [&str; 2] = ["UTF8_STRING", "STRING"];
+
+// `paste --type TARGETS` dumps the target names as they are, for debugging the selection owners
+const RAW_TARGETS_TYPE: &str = "TARGETS";
+
+/// Write the target names one per line, without the escaping and the formatting of
+/// `--list-types`. The meta targets like `MULTIPLE` and `TIMESTAMP` are included.
+fn write_raw_targets(writer: &mut dyn Write, targets: &[String]) -> Result<()> {
+    for target in targets {
+        writeln!(writer, "{target}").context("Failed to write to the output")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Return the first plain text mime-type, which can be served as the X text targets.
+fn plain_text_type(mime_types: &[String]) -> Option<&String> {
+    mime_types.iter().find(|t| {
+        t.to_ascii_lowercase().starts_with("text/plain") || mime_type_matches(t, "UTF8_STRING")
+    })
+}
+
+/// The mime-types to be listed in TARGETS. The X text targets are always offered for the plain
+/// text. For `STRING`, the UTF-8 content is sent as it is instead of converting it to Latin-1.
+fn advertised_targets(mut mime_types: Vec<String>) -> Vec<String> {
+    if plain_text_type(&mime_types).is_some() {
+        for target in X_TEXT_TARGETS {
+            if !mime_types.iter().any(|t| t == target) {
+                mime_types.push(target.to_string());
+            }
+        }
+    }
+    mime_types
+}
+
+/// Decide the mime-type to request from the offered targets. If none of them matches, a text
+/// type is still requested as it is, since some owners convert to more text targets than they
+/// offer. Other types fail early with the offered list, instead of requesting a target which
+/// doesn't exist and pasting nothing.
+fn decide_target_mime_type(config: &PasteConfig, mime_types: &Vec<String>) -> Result<String> {
+    let expected = &config.expected_mime_types;
+    match decide_mime_type_in_order(expected, mime_types) {
+        Ok(mime_type) => Ok(mime_type),
+        // The owner doesn't implement TARGETS correctly, try the expected one anyway
+        Err(_) if mime_types.is_empty() => Ok(expected.first().cloned().unwrap_or_default()),
+        Err(e) if expected.is_empty() || expected.iter().any(|t| is_text_mime_type(t)) => {
+            log::debug!("{e:#}, request '{}' anyway", expected.join(","));
+            Ok(expected.first().cloned().unwrap_or_default())
+        }
+        Err(e) => Err(e.context(config.not_offered())),
+    }
+}
+
+fn chunk_end(offset: usize, chunk_size: usize, len: usize) -> usize {
+    offset + chunk_size.min(len - offset)
+}
+
+/// The byte ranges of the chunks which the content of the given length is sent in with INCR. The
+/// last one is empty, which ends the transfer.
+#[cfg(any(test, feature = "bench"))]
+pub(super) fn incr_chunks(
+    len: usize,
+    chunk_size: usize,
+) -> impl Iterator<Item = std::ops::Range<usize>> {
+    let mut offset = Some(0);
+    std::iter::from_fn(move || {
+        let start = offset?;
+        let end = chunk_end(start, chunk_size, len);
+        offset = (end != start).then_some(end);
+        Some(start..end)
+    })
+}
+
 fn decide_mime_type_with_atom(
     conn: &RustConnection,
     preferred_atom: Atom,
     supported: &Vec<String>,
 ) -> Result<String> {
     let preferred = get_atom_name(conn, preferred_atom)?;
-    let mime_type = decide_mime_type(&preferred, supported)?;
-    Ok(mime_type)
+    match decide_mime_type(&preferred, supported) {
+        Ok(mime_type) => Ok(mime_type),
+        Err(e) if X_TEXT_TARGETS.contains(&preferred.as_str()) => {
+            plain_text_type(supported).cloned().ok_or(e)
+        }
+        Err(e) => Err(e),
+    }
 }
 
 fn create_x_client(display_name: Option<&str>) -> Result<XClient> {
-    let (conn, screen_num) =
-        x11rb::connect(display_name).context("Failed to connect to the X server")?;
+    let (conn, screen_num) = connect_with_retries(|| {
+        x11rb::connect(display_name).context("Failed to connect to the X server")
+    })?;
     let screen = &conn.setup().roots[screen_num];
     let win_id = conn.generate_id()?;
 
@@ -477,7 +774,125 @@ fn create_x_client(display_name: Option<&str>) -> Result<XClient> {
     })
 }
 
-fn paste_x(config: PasteConfig) -> Result<()> {
+/// Describe the X server and the INCR limits, for `doctor`.
+pub fn describe_server() -> Result<Vec<(&'static str, String)>> {
+    let client = create_x_client(None)?;
+    let setup = client.conn.setup();
+    Ok(vec![
+        (
+            "X server vendor",
+            String::from_utf8_lossy(&setup.vendor).into_owned(),
+        ),
+        ("X server release", setup.release_number.to_string()),
+        (
+            "X maximum request bytes",
+            client.conn.maximum_request_bytes().to_string(),
+        ),
+        (
+            "X INCR chunk size",
+            XSelectionSender::get_chunk_size(&client.conn).to_string(),
+        ),
+        (
+            "X clipboard manager",
+            match clipboard_manager(&client)? {
+                Some(manager) => format!("window {manager:#x}"),
+                None => "none".to_string(),
+            },
+        ),
+    ])
+}
+
+/// The window of the running clipboard manager, if any.
+fn clipboard_manager(client: &XClient) -> Result<Option<Window>> {
+    let manager = client
+        .conn
+        .get_selection_owner(client.atoms.CLIPBOARD_MANAGER)?
+        .reply()?
+        .owner;
+    Ok(Some(manager).filter(|m| *m != x11rb::NONE))
+}
+
+/// Log if the selection is held by the clipboard manager instead of the app which copied the
+/// content. It is pasted the same way, but the manager may only offer the targets it has saved.
+fn log_selection_owner(client: &XClient, selection: Atom) -> Result<()> {
+    let owner = client.conn.get_selection_owner(selection)?.reply()?.owner;
+    if owner == x11rb::NONE {
+        log::debug!("The selection has no owner");
+    } else if clipboard_manager(client)? == Some(owner) {
+        log::debug!("The selection is held by the clipboard manager {owner:#x}");
+    } else {
+        log::debug!("The selection is owned by window {owner:#x}");
+    }
+    Ok(())
+}
+
+/// Fail if the selection is not owned by the expected window, after taking it over.
+fn check_selection_owner(name: &str, owner: Window, expected: Window) -> Result<()> {
+    if owner == x11rb::NONE {
+        bail!("The {name} selection has no owner after taking it over");
+    } else if owner != expected {
+        bail!("The {name} selection is owned by another window {owner:#x} after taking it over");
+    }
+    log::debug!("The {name} selection is owned by window {owner:#x}");
+    Ok(())
+}
+
+/// Write the window owning the selection, with its WM_NAME and WM_CLASS if set, to tell which app
+/// holds the clipboard. The owner is often a hidden window of the app, which may have neither.
+fn write_owner_info(client: &XClient, selection: Atom, writer: &mut dyn Write) -> Result<()> {
+    let owner = client.conn.get_selection_owner(selection)?.reply()?.owner;
+    let mut lines = vec![];
+    if owner == x11rb::NONE {
+        lines.push("Owner: none".to_string());
+    } else {
+        let manager = if clipboard_manager(client)? == Some(owner) {
+            " (clipboard manager)"
+        } else {
+            ""
+        };
+        lines.push(format!("Owner: window {owner:#x}{manager}"));
+        let property = |atom: AtomEnum| -> Option<Vec<u8>> {
+            let reply = client
+                .conn
+                .get_property(false, owner, atom, AtomEnum::ANY, 0, 1024)
+                .ok()?
+                .reply()
+                .ok()?;
+            Some(reply.value).filter(|v| !v.is_empty())
+        };
+        let unknown = || "(unknown)".to_string();
+        let name = property(AtomEnum::WM_NAME).map(|v| String::from_utf8_lossy(&v).into_owned());
+        lines.push(format!("WM_NAME: {}", name.unwrap_or_else(unknown)));
+        let class = property(AtomEnum::WM_CLASS).map(|v| format_wm_class(&v));
+        lines.push(format!("WM_CLASS: {}", class.unwrap_or_else(unknown)));
+    }
+    for line in lines {
+        writeln!(writer, "{}", escape_mime_type(&line))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// WM_CLASS holds the instance and the class names, each ends with a NUL.
+fn format_wm_class(value: &[u8]) -> String {
+    value
+        .split(|b| *b == 0)
+        .filter(|s| !s.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn paste_x(mut config: PasteConfig) -> Result<()> {
+    if config.wayland_wait_for_change {
+        bail!("Waiting for the change is only supported on Wayland, use 'watch --count 1' instead");
+    }
+    if config.wayland_all_selections {
+        bail!(
+            "Listing all the selections is only supported on Wayland, list them one by one instead"
+        );
+    }
+    let start_time = Instant::now();
     let mut client = create_x_client(None)?;
 
     let selection = if config.use_primary {
@@ -490,31 +905,79 @@ fn paste_x(config: PasteConfig) -> Result<()> {
         client.win_id,
         &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
     )?;
-    // Use 'TARGETS' to list all supported mime-types of the clipboard content first
-    client
-        .conn
-        .convert_selection(
-            client.win_id,
-            selection,
-            client.atoms.TARGETS,
-            client.atoms.XCLIP_TARGETS,
-            CURRENT_TIME,
-        )
-        .context("Failed to call convert_selection to get 'TARGETS'")?;
-    client.conn.flush().context("Failed to flush connection")?;
+    if config.x_owner_info {
+        return write_owner_info(&client, selection, &mut config.writer);
+    }
+    if log::log_enabled!(log::Level::Debug) {
+        log_selection_owner(&client, selection)?;
+    }
 
     let mut state = XPasteState {
         supported_mime_types: None,
         config,
         selection,
-        receiver: None,
+        receivers: HashMap::new(),
+        received_bytes: 0,
+        last_chunk_time: start_time,
     };
 
-    loop {
-        let event = client
+    if let Some(target) = state.config.x_target_atom {
+        // The name may not be fetched, which is fine since only the writer needs it
+        let mime_type = get_atom_name(&client.conn, target).unwrap_or_default();
+        log::debug!("Request the target atom {target} ('{mime_type}') directly");
+        request_target(&client, &mut state, target, &mime_type)?;
+        state.supported_mime_types = Some(vec![]);
+    } else if state.config.x_raw {
+        // Some owners don't implement TARGETS correctly, request the content directly
+        let mime_type = match state.config.expected_mime_types.first() {
+            Some(t) if !t.is_empty() && !t.eq_ignore_ascii_case("text") => t.clone(),
+            _ => "UTF8_STRING".to_string(),
+        };
+        log::debug!("Skip TARGETS, request '{mime_type}' directly");
+        request_content(&client, &mut state, &mime_type)?;
+        state.supported_mime_types = Some(vec![]);
+    } else {
+        // Use 'TARGETS' to list all supported mime-types of the clipboard content first
+        client
             .conn
-            .wait_for_event()
-            .context("Failed to get X event")?;
+            .convert_selection(
+                client.win_id,
+                selection,
+                client.atoms.TARGETS,
+                client.atoms.XCLIP_TARGETS,
+                CURRENT_TIME,
+            )
+            .context("Failed to call convert_selection to get 'TARGETS'")?;
+        client.conn.flush().context("Failed to flush connection")?;
+    }
+
+    let deadline = state.config.timeout.map(|timeout| start_time + timeout);
+    loop {
+        let chunk_deadline = state.chunk_deadline();
+        let event = match [deadline, chunk_deadline].into_iter().flatten().min() {
+            Some(until) => {
+                match wait_for_event_until(&client.conn, until, state.config.x_poll_interval)? {
+                    Some(event) => event,
+                    None if paste_cancelled() => {
+                        return Err(paste_cancelled_error(&mut state.config.writer));
+                    }
+                    None if chunk_deadline.is_some_and(|d| Instant::now() >= d) => bail!(
+                        "The selection owner stopped sending the content, no chunk received in \
+                        {:?} after {} bytes",
+                        state.config.x_chunk_timeout.unwrap_or_default(),
+                        state.partial_received_bytes()
+                    ),
+                    None => bail!(
+                        "Timed out after {:?} waiting for the selection owner",
+                        state.config.timeout.unwrap_or_default()
+                    ),
+                }
+            }
+            None => match wait_for_event_or_cancel(&client.conn)? {
+                Some(event) => event,
+                None => return Err(paste_cancelled_error(&mut state.config.writer)),
+            },
+        };
         match event {
             Event::SelectionNotify(event) => {
                 log::debug!(
@@ -526,6 +989,10 @@ fn paste_x(config: PasteConfig) -> Result<()> {
                     continue;
                 }
                 if state.supported_mime_types.is_none() {
+                    if event.target != client.atoms.TARGETS {
+                        log::debug!("Ignore the SelectionNotify before TARGETS is received");
+                        continue;
+                    }
                     // List all the supported TARGETS (mime-types) first
                     let mut receiver = XSelectionReceiver::<u32>::new(
                         client.win_id,
@@ -537,58 +1004,72 @@ fn paste_x(config: PasteConfig) -> Result<()> {
                         .context("Failed to retrieve TARGETS")?;
                     let mime_types = targets_to_strings(&mut client, &receiver)
                         .context("Failed to get supported targets")?;
+                    log::debug!("TARGETS negotiation took {:?}", start_time.elapsed());
                     if mime_types.is_empty() {
                         log::debug!("Got 0 targets which probably means the clipboard is empty");
                         log::debug!(
-                            "Will try the expected mime-type {}",
-                            state.config.expected_mime_type
+                            "Will try the expected mime-types {:?}",
+                            state.config.expected_mime_types
                         );
                         // Don't break the loop, try to retrieve with expected mime-type in case the
                         // other side doesn't implement TARGETS correctly.
                     }
                     if state.config.list_types_only {
-                        for line in mime_types {
-                            writeln!(&mut state.config.writer, "{}", line)
-                                .context("Failed to write to the output")?;
-                        }
+                        state.config.write_type_list(&mime_types)?;
+                        break;
+                    }
+                    if state.config.target_index.is_none()
+                        && state.config.expected_mime_types == [RAW_TARGETS_TYPE]
+                    {
+                        write_raw_targets(&mut state.config.writer, &mime_types)?;
                         break;
                     }
 
+                    if mime_types.is_empty() && state.config.x_sniff {
+                        // Text is the most likely, the type is guessed from the content anyway
+                        let mime_type = match state.config.expected_mime_types.first() {
+                            Some(t) if !t.is_empty() => t.clone(),
+                            _ => "UTF8_STRING".to_string(),
+                        };
+                        log::debug!("No targets offered, request '{mime_type}' and sniff it");
+                        state.config.prepare_writer_sniffed();
+                        request_content(&client, &mut state, &mime_type)?;
+                        state.supported_mime_types = Some(mime_types);
+                        continue;
+                    }
+
                     // Request to retrieve the selection content
-                    let mime_type = decide_mime_type(&state.config.expected_mime_type, &mime_types)
-                        .unwrap_or(state.config.expected_mime_type.clone());
-                    let target = get_atom_id_by_name(&client.conn, &mime_type)
-                        .context(format!("Failed to get atom id for '{}'", mime_type))?;
-                    client
-                        .conn
-                        .convert_selection(
-                            client.win_id,
-                            selection,
-                            target,
-                            client.atoms.XCLIP_OUT,
-                            CURRENT_TIME,
-                        )
-                        .context("Failed to call convert_selection to get 'TARGETS'")?;
-                    client.conn.flush()?;
+                    let mime_type = match state.config.target_index {
+                        Some(index) => mime_type_by_index(index, &mime_types)?,
+                        None => decide_target_mime_type(&state.config, &mime_types)?,
+                    };
+                    request_content(&client, &mut state, &mime_type)?;
                     state.supported_mime_types = Some(mime_types);
-                    let content_receiver = XSelectionReceiver::<u8>::new(
-                        client.win_id,
-                        client.atoms.XCLIP_OUT,
-                        target,
-                    );
-                    state.receiver = Some(content_receiver);
                 } else {
-                    match &mut state.receiver {
-                        Some(receiver) => {
-                            if receiver.receive_and_write(&client, &mut state.config.writer)?
-                                == TransferResult::Done
-                            {
-                                break;
-                            }
+                    let Some(property) =
+                        state.receiver_property(event.property, Some(event.target))
+                    else {
+                        log::debug!("SelectionNotify without an existing receiver.");
+                        continue;
+                    };
+                    if event.property == u32::from(AtomEnum::NONE) {
+                        log::debug!("The selection owner refused the conversion");
+                        let target = state.receivers[&property].expected_type;
+                        if let Some(err) = refused_conversion_error(&client, &state, target)? {
+                            return Err(err.into());
                         }
-                        None => {
-                            log::debug!("SelectionNotify without an existing receiver.");
+                        if state.finish_receiver(property) {
+                            break;
                         }
+                        continue;
+                    }
+                    state.last_chunk_time = Instant::now();
+                    let receiver = state.receivers.get_mut(&property).unwrap();
+                    if receiver.receive_and_write(&client, &mut state.config.writer)?
+                        == TransferResult::Done
+                        && state.finish_receiver(property)
+                    {
+                        break;
                     }
                 }
             }
@@ -601,22 +1082,17 @@ fn paste_x(config: PasteConfig) -> Result<()> {
                 if event.state != Property::NEW_VALUE {
                     continue;
                 };
-                if event.atom != client.atoms.XCLIP_OUT {
+                let Some(property) = state.receiver_property(event.atom, None) else {
                     // Not the property we expect
                     continue;
                 };
-                match &mut state.receiver {
-                    Some(receiver) => {
-                        if receiver.receive_and_write_incr(&client, &mut state.config.writer)?
-                            == TransferResult::Done
-                        {
-                            break;
-                        }
-                    }
-                    None => {
-                        log::debug!("Selection receiver doesn't exist");
-                        continue;
-                    }
+                state.last_chunk_time = Instant::now();
+                let receiver = state.receivers.get_mut(&property).unwrap();
+                if receiver.receive_and_write_incr(&client, &mut state.config.writer)?
+                    == TransferResult::Done
+                    && state.finish_receiver(property)
+                {
+                    break;
                 }
             }
             event => {
@@ -625,35 +1101,189 @@ fn paste_x(config: PasteConfig) -> Result<()> {
             }
         }
     }
+    log::debug!(
+        "Pasted {} bytes in {:?}",
+        state.received_bytes,
+        start_time.elapsed()
+    );
+    Ok(())
+}
+
+/// Wait for the next event, and wake up every `poll_interval` to check the deadline. Return None
+/// if the deadline is reached or the paste is cancelled.
+fn wait_for_event_until(
+    conn: &RustConnection,
+    deadline: Instant,
+    poll_interval: Duration,
+) -> Result<Option<Event>> {
+    loop {
+        if let Some(event) = conn.poll_for_event().context("Failed to get X event")? {
+            return Ok(Some(event));
+        }
+        let now = Instant::now();
+        if now >= deadline || paste_cancelled() {
+            return Ok(None);
+        }
+        conn.flush().context("Failed to flush connection")?;
+        std::thread::sleep(poll_interval.min(deadline - now));
+    }
+}
+
+/// Wait for the next event like `wait_for_event`, but wake up on Ctrl-C. Return None if the paste
+/// is cancelled.
+fn wait_for_event_or_cancel(conn: &RustConnection) -> Result<Option<Event>> {
+    // In case the signal arrives right before polling
+    const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+    loop {
+        if let Some(event) = conn.poll_for_event().context("Failed to get X event")? {
+            return Ok(Some(event));
+        }
+        if paste_cancelled() {
+            return Ok(None);
+        }
+        conn.flush().context("Failed to flush connection")?;
+        // poll() is interrupted by the signal
+        wait_fd_readable(conn.stream().as_raw_fd(), CANCEL_CHECK_INTERVAL)?;
+    }
+}
+
+/// Tell why the owner refused to convert the target. No error if the clipboard has no owner at
+/// all, which means it is empty.
+fn refused_conversion_error(
+    client: &XClient,
+    state: &XPasteState,
+    target: Atom,
+) -> Result<Option<PasteError>> {
+    let owner = client
+        .conn
+        .get_selection_owner(state.selection)?
+        .reply()?
+        .owner;
+    let name = get_atom_name_default(&client.conn, target);
+    let offered = state
+        .supported_mime_types
+        .as_ref()
+        .is_some_and(|types| types.contains(&name));
+    if offered {
+        Ok(Some(PasteError::NoContent(name)))
+    } else if owner == x11rb::NONE {
+        Ok(None)
+    } else {
+        Ok(Some(PasteError::NotOffered(name)))
+    }
+}
+
+/// Ask the selection owner to send the content of the mime-type, and prepare the receiver.
+fn request_content(client: &XClient, state: &mut XPasteState, mime_type: &str) -> Result<()> {
+    log::debug!("Request the content of '{mime_type}'");
+    let target = match parse_unknown_atom(mime_type) {
+        Some(atom) => atom,
+        None => get_atom_id_by_name(&client.conn, mime_type)
+            .context(format!("Failed to get atom id for '{}'", mime_type))?,
+    };
+    request_target(client, state, target, mime_type)
+}
+
+fn request_target(
+    client: &XClient,
+    state: &mut XPasteState,
+    target: Atom,
+    mime_type: &str,
+) -> Result<()> {
+    state.config.prepare_writer(mime_type);
+    client
+        .conn
+        .convert_selection(
+            client.win_id,
+            state.selection,
+            target,
+            client.atoms.XCLIP_OUT,
+            CURRENT_TIME,
+        )
+        .context(format!(
+            "Failed to call convert_selection to get '{mime_type}' (atom {target})"
+        ))?;
+    client.conn.flush()?;
+    state.receivers.insert(
+        client.atoms.XCLIP_OUT,
+        XSelectionReceiver::<u8>::new(client.win_id, client.atoms.XCLIP_OUT, target),
+    );
     Ok(())
 }
 
 fn copy_x(config: CopyConfig) -> Result<()> {
+    let start_time = Instant::now();
+    let deadline = ServeDeadline::new(&config, start_time);
+    let mut last_request = start_time;
     let mut state = XCopyState {
         source_data: config.source_data,
         ongoing_senders: HashMap::new(),
+        sent_bytes: 0,
+        served: false,
     };
     let client = create_x_client(None)?;
 
-    let selection = if config.use_primary {
-        client.atoms.PRIMARY
+    // The same window can own both selections, the requests tell which one is asked
+    let mut selections = if config.both_selections {
+        vec![client.atoms.CLIPBOARD, client.atoms.PRIMARY]
+    } else if config.use_primary {
+        vec![client.atoms.PRIMARY]
     } else {
-        client.atoms.CLIPBOARD
+        vec![client.atoms.CLIPBOARD]
     };
     // Take over the clipboard
-    // Xclip does a double check which doesn't seem to be necessary:
-    // https://github.com/astrand/xclip/commit/33dc754c64c78ab0bd112b5bd34f7d517de76418
-    client
-        .conn
-        .set_selection_owner(client.win_id, selection, CURRENT_TIME)
-        .context("Failed to call set_selection_owner")?;
+    for selection in &selections {
+        client
+            .conn
+            .set_selection_owner(client.win_id, *selection, CURRENT_TIME)
+            .context("Failed to call set_selection_owner")?;
+    }
     client.conn.flush().context("Failed to flush connection")?;
+    // Xclip does a double check which is not needed normally, but another app may take the
+    // selection over in between:
+    // https://github.com/astrand/xclip/commit/33dc754c64c78ab0bd112b5bd34f7d517de76418
+    if config.x_exclusive {
+        for selection in &selections {
+            let owner = client.conn.get_selection_owner(*selection)?.reply()?.owner;
+            let name = get_atom_name_default(&client.conn, *selection);
+            check_selection_owner(&name, owner, client.win_id)?;
+        }
+    }
 
+    let mut reacquires = 0;
     loop {
-        let event = client
+        if config.serve_once && state.served && state.ongoing_senders.is_empty() {
+            log::debug!("Content has been served once, exit");
+            break;
+        }
+        // The requests of the senders are only flushed when all the pending events have been
+        // handled, instead of once per chunk.
+        let event = match client
             .conn
-            .wait_for_event()
-            .context("Failed to get X event")?;
+            .poll_for_event()
+            .context("Failed to get X event")?
+        {
+            Some(event) => event,
+            None => {
+                client.conn.flush().context("Failed to flush connection")?;
+                if let Some(deadline) = deadline.at(last_request) {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if !wait_fd_readable(client.conn.stream().as_raw_fd(), timeout)? {
+                        log::debug!("The content expired");
+                        if config.clear_on_expire {
+                            clear_selection_x(&client, &selections, &mut state)?;
+                        }
+                        break;
+                    }
+                    // The data may not be a complete event, poll again
+                    continue;
+                }
+                client
+                    .conn
+                    .wait_for_event()
+                    .context("Failed to get X event")?
+            }
+        };
         match event {
             Event::SelectionRequest(event) => {
                 log::debug!(
@@ -661,13 +1291,14 @@ fn copy_x(config: CopyConfig) -> Result<()> {
                     get_atom_name_default(&client.conn, event.target),
                     event.requestor
                 );
+                last_request = Instant::now();
                 if event.target == client.atoms.TARGETS {
                     // Ask for supported mime-types
                     // 'TARGETS' should always be the first supported target (mime-type)
                     let mut atoms = vec![client.atoms.TARGETS];
                     atoms.extend(mime_types_to_targets(
                         &client.conn,
-                        &state.source_data.mime_types(),
+                        &advertised_targets(state.source_data.mime_types()),
                     ));
                     // In theory, sending TARGETS could cause INCR transfer as well.
                     // However, that requires some complex generic handling for XSelectionSender
@@ -693,7 +1324,6 @@ fn copy_x(config: CopyConfig) -> Result<()> {
                             property: event.property,
                         },
                     )?;
-                    client.conn.flush()?;
                 } else {
                     // Ask the content of the clipboard
                     let content = match decide_mime_type_with_atom(
@@ -709,8 +1339,10 @@ fn copy_x(config: CopyConfig) -> Result<()> {
                                 "The requested target (mime-type) cannot be provided. {}",
                                 e
                             );
-                            // Cannot find content, reply empty
-                            Rc::new(Vec::<u8>::new())
+                            // Refuse the conversion, so the requestor knows the type is not
+                            // offered rather than empty
+                            refuse_conversion(&client, &event)?;
+                            continue;
                         }
                     };
                     let mut sender = XSelectionSender::new(
@@ -722,6 +1354,9 @@ fn copy_x(config: CopyConfig) -> Result<()> {
                     );
                     if sender.send(&client, event.time)? == TransferResult::Continue {
                         state.ongoing_senders.insert(event.requestor, sender);
+                    } else {
+                        state.sent_bytes += sender.content.len();
+                        state.served = true;
                     }
                 }
             }
@@ -736,8 +1371,12 @@ fn copy_x(config: CopyConfig) -> Result<()> {
                     continue;
                 };
                 if let Some(sender) = state.ongoing_senders.get_mut(&event.window) {
+                    // Not idle during the INCR transfer
+                    last_request = Instant::now();
                     if sender.send(&client, event.time)? == TransferResult::Done {
                         // INCR finished
+                        state.sent_bytes += sender.content.len();
+                        state.served = true;
                         state.ongoing_senders.remove(&event.window);
                     }
                 } else {
@@ -745,14 +1384,451 @@ fn copy_x(config: CopyConfig) -> Result<()> {
                     log::error!("Couldn't find the sender");
                 }
             }
-            Event::SelectionClear(_) => {
-                log::debug!("Received SelectionClear");
-                break;
+            Event::SelectionClear(event) => {
+                log::debug!(
+                    "Received SelectionClear for {}",
+                    get_atom_name_default(&client.conn, event.selection)
+                );
+                let selection = event.selection;
+                // Selecting text anywhere takes the primary selection
+                let persist = config.x_persist_primary && selection == client.atoms.PRIMARY;
+                if persist && reacquires < MAX_PRIMARY_REACQUIRES {
+                    reacquires += 1;
+                    log::debug!("Take the primary selection back, {reacquires} time(s)");
+                    client
+                        .conn
+                        .set_selection_owner(client.win_id, selection, CURRENT_TIME)
+                        .context("Failed to call set_selection_owner")?;
+                    let owner = client.conn.get_selection_owner(selection)?.reply()?.owner;
+                    if owner == client.win_id {
+                        continue;
+                    }
+                    log::debug!("Failed to take the primary selection back");
+                }
+                // Keep serving until all the selections are taken over
+                selections.retain(|s| *s != selection);
+                if selections.is_empty() {
+                    break;
+                }
+            }
+            event => {
+                log::debug!("Unhandled event {event:?}");
+            }
+        }
+    }
+    client.conn.flush().context("Failed to flush connection")?;
+    log::debug!(
+        "Copy finished, sent {} bytes in {:?}",
+        state.sent_bytes,
+        start_time.elapsed()
+    );
+    Ok(())
+}
+
+/// Tell the requestor the target cannot be converted, with the `None` property.
+fn refuse_conversion(client: &XClient, event: &SelectionRequestEvent) -> Result<()> {
+    client.conn.send_event(
+        false,
+        event.requestor,
+        EventMask::default(),
+        SelectionNotifyEvent {
+            response_type: SELECTION_NOTIFY_EVENT,
+            sequence: 0,
+            time: event.time,
+            requestor: event.requestor,
+            selection: event.selection,
+            target: event.target,
+            property: AtomEnum::NONE.into(),
+        },
+    )?;
+    Ok(())
+}
+
+/// Give up the selections and overwrite the content in memory.
+fn clear_selection_x(client: &XClient, selections: &[Atom], state: &mut XCopyState) -> Result<()> {
+    for selection in selections {
+        client
+            .conn
+            .set_selection_owner(x11rb::NONE, *selection, CURRENT_TIME)
+            .context("Failed to call set_selection_owner")?;
+    }
+    state.ongoing_senders.clear();
+    state.source_data.clear_content();
+    Ok(())
+}
+
+fn watch_x(mut config: WatchConfig) -> Result<()> {
+    let client = create_x_client(None)?;
+    let selection = if config.use_primary {
+        client.atoms.PRIMARY
+    } else {
+        client.atoms.CLIPBOARD
+    };
+
+    // The version has to be negotiated before using the XFixes requests
+    client
+        .conn
+        .xfixes_query_version(5, 0)
+        .context("Failed to query XFixes version")?
+        .reply()
+        .context("XFixes extension is not available")?;
+    client
+        .conn
+        .xfixes_select_selection_input(
+            client.win_id,
+            selection,
+            SelectionEventMask::SET_SELECTION_OWNER,
+        )
+        .context("Failed to call 'xfixes_select_selection_input'")?;
+    client.conn.flush().context("Failed to flush connection")?;
+
+    loop {
+        let event = client
+            .conn
+            .wait_for_event()
+            .context("Failed to get X event")?;
+        match event {
+            Event::XfixesSelectionNotify(event) => {
+                log::debug!(
+                    "Received XfixesSelectionNotify, owner {}, subtype {:?}",
+                    event.owner,
+                    event.subtype
+                );
+                if event.subtype != SelectionEvent::SET_SELECTION_OWNER
+                    || event.owner == x11rb::NONE
+                {
+                    continue;
+                }
+                if !config.notify_change()? {
+                    break;
+                }
             }
             event => {
                 log::debug!("Unhandled event {event:?}");
             }
         }
     }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender(content: &[u8], chunk_size: usize) -> XSelectionSender {
+        XSelectionSender {
+            requestor: 0,
+            selection: 0,
+            target: 0,
+            property: 0,
+            content_type: 0,
+            content: Rc::new(content.to_vec()),
+            chunk_size,
+            offset: usize::MAX,
+            start_time: Instant::now(),
+        }
+    }
+
+    fn paste_state() -> XPasteState {
+        XPasteState {
+            supported_mime_types: None,
+            config: PasteConfig::probe(false, vec![], Box::new(std::io::sink())),
+            selection: 0,
+            receivers: HashMap::new(),
+            received_bytes: 0,
+            last_chunk_time: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_chunk_deadline() {
+        let mut state = paste_state();
+        let mut receiver = XSelectionReceiver::new(0, 100, 200);
+        receiver.is_incr = true;
+        receiver.received_bytes = 10;
+        state.receivers.insert(100, receiver);
+        // Not enabled
+        assert!(state.chunk_deadline().is_none());
+
+        state.config.x_chunk_timeout = Some(Duration::from_secs(1));
+        assert_eq!(
+            state.chunk_deadline(),
+            Some(state.last_chunk_time + Duration::from_secs(1))
+        );
+        state.received_bytes = 5;
+        assert_eq!(state.partial_received_bytes(), 15);
+
+        // Not in INCR mode
+        state.receivers.get_mut(&100).unwrap().is_incr = false;
+        assert!(state.chunk_deadline().is_none());
+    }
+
+    #[test]
+    fn test_interleaved_receivers() {
+        const PROP_A: Atom = 100;
+        const PROP_B: Atom = 101;
+        const TARGET_A: Atom = 200;
+        const TARGET_B: Atom = 201;
+        let none = u32::from(AtomEnum::NONE);
+
+        let mut state = paste_state();
+        state
+            .receivers
+            .insert(PROP_A, XSelectionReceiver::new(0, PROP_A, TARGET_A));
+        let mut receiver_b = XSelectionReceiver::new(0, PROP_B, TARGET_B);
+        receiver_b.received_bytes = 10;
+        state.receivers.insert(PROP_B, receiver_b);
+
+        // Events are routed by the property
+        assert_eq!(
+            state.receiver_property(PROP_B, Some(TARGET_A)),
+            Some(PROP_B)
+        );
+        assert_eq!(state.receiver_property(PROP_A, None), Some(PROP_A));
+        // The stray events are ignored
+        assert_eq!(state.receiver_property(102, Some(TARGET_A)), None);
+        assert_eq!(state.receiver_property(none, None), None);
+        assert_eq!(state.receiver_property(none, Some(202)), None);
+        // The refused conversion is routed by the target
+        assert_eq!(state.receiver_property(none, Some(TARGET_B)), Some(PROP_B));
+
+        assert!(!state.finish_receiver(PROP_B));
+        assert_eq!(state.receiver_property(PROP_B, None), None);
+        assert!(state.finish_receiver(PROP_A));
+        assert_eq!(state.received_bytes, 10);
+    }
+
+    #[test]
+    fn test_write_property_value() {
+        // Larger than the 16 MiB get_property length of the receiver
+        let content: Vec<u8> = (0..17 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        // Serve at most 4 MiB per reply
+        let get_property = |offset: u32| {
+            let start = (offset as usize * 4).min(content.len());
+            let end = (start + 4 * 1024 * 1024).min(content.len());
+            Ok(GetPropertyReply {
+                format: 8,
+                sequence: 0,
+                length: 0,
+                type_: AtomEnum::STRING.into(),
+                bytes_after: (content.len() - end) as u32,
+                value_len: (end - start) as u32,
+                value: content[start..end].to_vec(),
+            })
+        };
+        let mut calls = 0;
+        let mut output = vec![];
+        let written = write_property_value(get_property(0).unwrap(), &mut output, |offset| {
+            calls += 1;
+            get_property(offset)
+        })
+        .unwrap();
+        assert_eq!(written, content.len());
+        assert_eq!(calls, 4);
+        assert!(output == content);
+
+        // A broken reply doesn't loop forever
+        let mut reply = get_property(0).unwrap();
+        reply.value.clear();
+        let r = write_property_value(reply, &mut vec![], get_property);
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn test_decide_target_mime_type() {
+        let mut config = paste_state().config;
+        let offered = vec!["TARGETS".to_string(), "image/png".to_string()];
+
+        config.expected_mime_types = vec!["IMAGE/PNG".to_string()];
+        assert_eq!(
+            decide_target_mime_type(&config, &offered).unwrap(),
+            "image/png"
+        );
+
+        // Not offered explicit type fails early with the offered list
+        config.expected_mime_types = vec!["image/jpeg".to_string()];
+        let e = decide_target_mime_type(&config, &offered).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<PasteError>(),
+            Some(PasteError::NotOffered(t)) if t == "image/jpeg"
+        ));
+        assert!(format!("{e:#}").contains("[TARGETS, image/png]"));
+
+        // Text is still requested
+        config.expected_mime_types = vec!["UTF8_STRING".to_string()];
+        assert_eq!(
+            decide_target_mime_type(&config, &offered).unwrap(),
+            "UTF8_STRING"
+        );
+
+        // So is anything if TARGETS is broken
+        config.expected_mime_types = vec!["image/jpeg".to_string()];
+        assert_eq!(
+            decide_target_mime_type(&config, &vec![]).unwrap(),
+            "image/jpeg"
+        );
+    }
+
+    #[test]
+    fn test_write_raw_targets() {
+        let mut output = vec![];
+        let targets = ["TARGETS", "MULTIPLE", "TIMESTAMP", "bad\x1btype"].map(String::from);
+        write_raw_targets(&mut output, &targets).unwrap();
+        assert_eq!(output, b"TARGETS\nMULTIPLE\nTIMESTAMP\nbad\x1btype\n");
+    }
+
+    #[test]
+    fn test_format_wm_class() {
+        assert_eq!(format_wm_class(b"xterm\0XTerm\0"), "xterm, XTerm");
+        // Without the trailing NUL
+        assert_eq!(format_wm_class(b"navigator\0firefox"), "navigator, firefox");
+        assert_eq!(format_wm_class(b"\0"), "");
+    }
+
+    #[test]
+    fn test_targets_property_round_trip() {
+        let atoms: Vec<Atom> = vec![4, 31, 0x1234_5678, u32::MAX];
+        let reply = |big_endian: bool| GetPropertyReply {
+            format: 32,
+            sequence: 0,
+            length: 0,
+            type_: AtomEnum::ATOM.into(),
+            bytes_after: 0,
+            value_len: atoms.len() as u32,
+            value: atoms
+                .iter()
+                .flat_map(|a| match big_endian {
+                    true => a.to_be_bytes(),
+                    false => a.to_le_bytes(),
+                })
+                .collect(),
+        };
+        // The server sends the property data in the byte order of the connection setup, which is
+        // the native one of x11rb
+        let native_big_endian = cfg!(target_endian = "big");
+        assert_eq!(atoms_from_property(&reply(native_big_endian)), atoms);
+        // Not swapped again on the client side
+        let swapped: Vec<Atom> = atoms.iter().map(|a| a.swap_bytes()).collect();
+        assert_eq!(atoms_from_property(&reply(!native_big_endian)), swapped);
+
+        // Not a list of 32-bit values
+        let mut reply = reply(native_big_endian);
+        reply.format = 8;
+        assert!(atoms_from_property(&reply).is_empty());
+    }
+
+    #[test]
+    fn test_unknown_atom_name() {
+        assert_eq!(target_name(42, Ok("text/plain".to_string())), "text/plain");
+
+        // The atom without a name
+        let name = target_name(12345, Err(anyhow::anyhow!("BadAtom")));
+        assert_eq!(name, "Unknown-Atom-12345");
+        assert_eq!(parse_unknown_atom(&name), Some(12345));
+
+        assert_eq!(parse_unknown_atom("text/plain"), None);
+        assert_eq!(parse_unknown_atom("Unknown-Atom-"), None);
+        assert_eq!(parse_unknown_atom("Unknown-Atom-abc"), None);
+    }
+
+    #[test]
+    fn test_advertised_targets() {
+        let to_strings = |types: &[&str]| types.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let r = advertised_targets(to_strings(&["text/html", "text/plain"]));
+        assert_eq!(r, ["text/html", "text/plain", "UTF8_STRING", "STRING"]);
+        assert_eq!(plain_text_type(&r).unwrap(), "text/plain");
+
+        // Not duplicated
+        let r = advertised_targets(to_strings(&["STRING", "TEXT"]));
+        assert_eq!(r, ["STRING", "TEXT", "UTF8_STRING"]);
+
+        // Not for the other types
+        let r = advertised_targets(to_strings(&["text/html", "image/png"]));
+        assert_eq!(r, ["text/html", "image/png"]);
+        assert!(plain_text_type(&r).is_none());
+    }
+
+    #[test]
+    fn test_chunk_size_clamp() {
+        assert_eq!(XSelectionSender::chunk_size_for_request_bytes(0), 4096);
+        assert_eq!(XSelectionSender::chunk_size_for_request_bytes(100), 4096);
+        // Without BIG-REQUESTS
+        assert_eq!(
+            XSelectionSender::chunk_size_for_request_bytes(65535 * 4),
+            65535
+        );
+        assert_eq!(
+            XSelectionSender::chunk_size_for_request_bytes(16 * 1024 * 1024),
+            4 * 1024 * 1024
+        );
+    }
+
+    #[test]
+    fn test_chunk_size_from_env() {
+        assert_eq!(XSelectionSender::chunk_size_from_env(100, 65535 * 4), 100);
+        assert_eq!(
+            XSelectionSender::chunk_size_from_env(1024 * 1024, 65535 * 4),
+            65535 * 4 - 24
+        );
+    }
+
+    #[test]
+    fn test_tiny_chunks() {
+        let mut s = sender(b"GOOD", 1);
+        assert!(s.is_incr());
+        s.offset = 0;
+        let mut chunks = vec![];
+        loop {
+            let end = s.next_chunk_end();
+            chunks.push(s.content[s.offset..end].to_vec());
+            if end == s.offset {
+                // The zero-length chunk ends the transfer
+                break;
+            }
+            s.offset = end;
+        }
+        assert_eq!(
+            chunks,
+            vec![
+                b"G".to_vec(),
+                b"O".to_vec(),
+                b"O".to_vec(),
+                b"D".to_vec(),
+                vec![]
+            ]
+        );
+        let ranges: Vec<std::ops::Range<usize>> = incr_chunks(10, 4).collect();
+        assert_eq!(ranges, vec![0..4, 4..8, 8..10, 10..10]);
+
+        // Same size as the chunk
+        assert!(sender(b"GOOD", 4).is_incr());
+        assert!(!sender(b"GOOD", 5).is_incr());
+        assert!(!sender(b"", 1).is_incr());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = XBackend {}.capabilities();
+        assert!(capabilities.primary_selection);
+        assert!(capabilities.watch);
+        assert!(capabilities.incr);
+        assert!(!capabilities.multiple_items);
+    }
+
+    #[test]
+    fn test_check_selection_owner() {
+        assert!(check_selection_owner("CLIPBOARD", 0x400001, 0x400001).is_ok());
+        let err = check_selection_owner("CLIPBOARD", 0x600002, 0x400001).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The CLIPBOARD selection is owned by another window 0x600002 after taking it over"
+        );
+        let err = check_selection_owner("PRIMARY", x11rb::NONE, 0x400001).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The PRIMARY selection has no owner after taking it over"
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! richclip as a library.
+//!
+//! Besides the [`clipboard`] backends and the [`protocol`] used by the command line tool, some
+//! convenience functions are provided for the most common use cases, so the embedders don't need
+//! to deal with [`clipboard::PasteConfig`]/[`clipboard::CopyConfig`] directly.
+
+pub mod clipboard;
+pub mod protocol;
+pub mod transform;
+
+use anyhow::{Context, Result};
+use protocol::SourceDataItem;
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+/// A writer which can be handed to [`clipboard::PasteConfig`] and still be read after the config
+/// has been consumed by the backend.
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SharedBuffer {
+    pub(crate) fn take(&self) -> Vec<u8> {
+        self.0.take()
+    }
+}
+
+/// The features supported by the clipboard backend for the current session, e.g. to hide the
+/// primary selection option where it is not available.
+pub fn capabilities() -> Result<clipboard::Capabilities> {
+    Ok(clipboard::create_backend()?.capabilities())
+}
+
+/// Read the content of the given mime-type from the clipboard. An empty `mime_type` means any
+/// text type.
+pub fn get_bytes(mime_type: &str, use_primary: bool) -> Result<Vec<u8>> {
+    let buffer = SharedBuffer::default();
+    let cfg = clipboard::PasteConfig::probe(
+        use_primary,
+        vec![mime_type.to_string()],
+        Box::new(buffer.clone()),
+    );
+    clipboard::create_backend()?
+        .paste(cfg)
+        .context("Failed to paste from clipboard")?;
+    Ok(buffer.take())
+}
+
+/// Read the text content from the clipboard.
+pub fn get_text(use_primary: bool) -> Result<String> {
+    let bytes = get_bytes("", use_primary)?;
+    String::from_utf8(bytes).context("The clipboard content is not valid UTF-8")
+}
+
+/// Read the content of all the offered mime-types from the clipboard. The mime-types with
+/// identical content are grouped into one item, the X specific targets like `TARGETS` are
+/// skipped.
+pub fn get_all(use_primary: bool) -> Result<Vec<SourceDataItem>> {
+    const SKIPPED_TARGETS: [&str; 4] = ["TARGETS", "MULTIPLE", "TIMESTAMP", "SAVE_TARGETS"];
+
+    let offered = clipboard::offered_mime_types(&*clipboard::create_backend()?, use_primary)?;
+
+    let mut items: Vec<SourceDataItem> = vec![];
+    for mime_type in offered.iter().map(String::as_str) {
+        if SKIPPED_TARGETS.contains(&mime_type) {
+            continue;
+        }
+        let content = match get_bytes(mime_type, use_primary) {
+            Ok(content) => content,
+            Err(e) => {
+                log::warn!("Skip '{mime_type}' which cannot be pasted: {e:#}");
+                continue;
+            }
+        };
+        match items.iter_mut().find(|item| *item.content == content) {
+            Some(item) => item.mime_type.push(mime_type.to_string()),
+            None => items.push(SourceDataItem::new(vec![mime_type.to_string()], content)),
+        }
+    }
+    Ok(items)
+}
+
+/// Copy the content to the clipboard with the given mime-types.
+///
+/// NOTE: On X and Wayland, the content is served by the current process. This function blocks
+/// until another client takes over the clipboard.
+pub fn set_bytes(content: Vec<u8>, mime_types: &[&str], use_primary: bool) -> Result<()> {
+    let source_data = vec![SourceDataItem::new(
+        mime_types.iter().map(|s| s.to_string()).collect(),
+        content,
+    )];
+    let copy_config = clipboard::CopyConfig {
+        source_data: Box::new(source_data),
+        use_primary,
+        both_selections: false,
+        x_chunk_size: 0,
+        serve_once: false,
+        expire: None,
+        idle_timeout: None,
+        clear_on_expire: false,
+        x_persist_primary: false,
+        x_exclusive: false,
+        mac_no_clear: false,
+    };
+    clipboard::create_backend()?
+        .copy(copy_config)
+        .context("Failed to copy to clipboard")
+}
+
+/// Copy the text to the clipboard with the common text mime-types.
+///
+/// NOTE: See [`set_bytes`] for the blocking behavior.
+pub fn set_text(text: &str, use_primary: bool) -> Result<()> {
+    set_bytes(text.as_bytes().to_vec(), &protocol::TEXT_TYPES, use_primary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_buffer() {
+        let buffer = SharedBuffer::default();
+        let mut writer: Box<dyn Write> = Box::new(buffer.clone());
+        writer.write_all(b"GOOD").unwrap();
+        drop(writer);
+        assert_eq!(buffer.take(), b"GOOD");
+        assert!(buffer.take().is_empty());
+    }
+}
@@ -3,37 +3,141 @@ extern crate daemonize;
 extern crate log;
 extern crate simplelog;
 
-mod clipboard;
-mod protocol;
-
-use anyhow::{Context, Result};
-use clap::{ArgAction, Args, Parser, Subcommand};
+use anyhow::{Context, Result, anyhow, bail};
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
 #[cfg(target_os = "linux")]
-use daemonize::Daemonize;
-use std::env;
+use clap::{ArgGroup, ValueEnum, builder::TypedValueParser};
 #[cfg(target_os = "linux")]
+use daemonize::{Daemonize, Outcome};
+use richclip::clipboard;
+use richclip::clipboard::PreferSize;
+use richclip::protocol;
+use richclip::protocol::SourceData;
+use richclip::transform::{
+    CountingWriter, Encoding, EncodingWriter, EscapeMode, EscapeWriter, FilterWriter, FramedWriter,
+    MultiWriter, SafeTerminalWriter, StripAnsiWriter,
+};
+use std::cell::RefCell;
+use std::env;
 use std::fs::File;
-use std::io::{stdin, stdout};
+#[cfg(target_os = "linux")]
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{IsTerminal, Read, Write, stdin, stdout};
+#[cfg(target_os = "linux")]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::process::{ChildStdin, Command, Stdio};
+use std::rc::Rc;
 use std::str::FromStr;
+#[cfg(target_os = "linux")]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// How often the clipboard is checked for changes, if the platform has no change events
+const WATCH_POLL_INTERVAL_MS: u64 = 250;
+// The content transcoded by '--charset' is always offered with this type
+const UTF8_TEXT_TYPE: &str = "text/plain;charset=utf-8";
 
 /// Clipboard utility for multiple platforms
 #[derive(Parser)]
 struct Cli {
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
+    /// Print the name of the backend to be used, e.g. 'x' or 'wayland', and exit
+    #[arg(long = "print-backend", num_args = 0)]
+    print_backend: bool,
+    /// Set the log level, overrides '$RICHCLIP_LOG_LEVEL'
+    #[arg(long = "log-level", value_name = "LEVEL", global = true)]
+    log_level: Option<String>,
+    /// Also write the debug logs to the file, overrides '$RICHCLIP_LOG_FILE'
+    #[arg(long = "log-file", value_name = "PATH", global = true)]
+    log_file: Option<String>,
+    /// Don't print the logs and the hints to the terminal, the log file is still written
+    #[arg(long = "quiet", short = 'q', num_args = 0, global = true)]
+    quiet: bool,
+    /// Load the 'alias=mime/type' lines of the file as extra mime-type aliases, overrides
+    /// '$RICHCLIP_ALIASES'
+    #[arg(long = "alias-file", value_name = "PATH", global = true)]
+    alias_file: Option<PathBuf>,
+    /// Retry connecting to the display server for the given times before failing, e.g. in the
+    /// autostart scripts which may run before the server is ready
+    #[arg(
+        long = "connect-retries",
+        value_name = "N",
+        global = true,
+        default_value_t = 0
+    )]
+    connect_retries: u32,
 }
 
 /// Arguments for copy command
 #[derive(Args)]
+// The content can be cleared on either of them
+#[cfg_attr(target_os = "linux", command(group(
+    ArgGroup::new("expiry").args(["expire", "idle_timeout"]).multiple(true)
+)))]
 struct CopyArgs {
     /// Use the 'primary' clipboard
     #[cfg(target_os = "linux")]
-    #[arg(long = "primary", short = 'p', num_args = 0)]
+    #[arg(
+        long = "primary",
+        short = 'p',
+        num_args = 0,
+        conflicts_with = "selection"
+    )]
     primary: bool,
-    /// Run in foreground
+    /// Copy to the given selection, 'both' sets the clipboard and the primary selection together
     #[cfg(target_os = "linux")]
-    #[arg(long = "foreground", num_args = 0)]
-    foreground: bool,
+    #[arg(long = "selection", value_name = "selection", value_enum)]
+    selection: Option<Selection>,
+    /// Return once the content is set, it is served by a background process if needed [default]
+    #[arg(long = "detach", num_args = 0, overrides_with = "no_detach")]
+    detach: bool,
+    /// Keep running until the content is replaced, if it needs to be served by richclip
+    #[arg(
+        long = "no-detach",
+        visible_alias = "foreground",
+        num_args = 0,
+        overrides_with = "detach"
+    )]
+    no_detach: bool,
+    /// Write the PID of the process serving the content to the file, e.g. to kill it later
+    #[cfg(target_os = "linux")]
+    #[arg(long = "pid-file", value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+    /// Exit after the content has been pasted once
+    #[cfg(target_os = "linux")]
+    #[arg(long = "serve-once", num_args = 0)]
+    serve_once: bool,
+    /// Stop serving the content after the given seconds
+    #[cfg(target_os = "linux")]
+    #[arg(long = "expire", value_name = "SECONDS")]
+    expire: Option<u64>,
+    /// Stop serving the content if it is not pasted for the given seconds
+    #[cfg(target_os = "linux")]
+    #[arg(long = "idle-timeout", value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
+    /// Clear the clipboard when the content expires, if it is still owned by richclip
+    #[cfg(target_os = "linux")]
+    #[arg(long = "clear-on-expire", value_name = "BOOL", requires = "expiry",
+        default_value_t = true, action = ArgAction::Set)]
+    clear_on_expire: bool,
+    /// Take the X primary selection back when other text is selected, up to 10 times
+    #[cfg(target_os = "linux")]
+    #[arg(long = "persist-primary", num_args = 0)]
+    persist_primary: bool,
+    /// Fail if another X client owns the selection right after richclip takes it over. The
+    /// failure is only reported with '--no-detach'
+    #[cfg(target_os = "linux")]
+    #[arg(long = "exclusive", num_args = 0)]
+    exclusive: bool,
+    /// Add the types to the pasteboard without clearing the ones already there
+    #[cfg(target_os = "macos")]
+    #[arg(long = "no-clear", num_args = 0)]
+    no_clear: bool,
     /// Enable one-shot mode, anything received from stdin will be copied as it is
     #[arg(long = "one-shot", num_args = 0)]
     oneshot: bool,
@@ -41,6 +145,44 @@ struct CopyArgs {
     #[arg(long = "type", short = 't', num_args = 0..=1,
         value_name = "mime-type", default_missing_value = "TEXT", action = ArgAction::Append )]
     mime_types: Option<Vec<String>>,
+    /// Append to the current text content of the clipboard and implicitly enable one-shot copy
+    /// mode
+    #[arg(long = "append", num_args = 0)]
+    append: bool,
+    /// Transcode the text input from the charset to UTF-8, e.g. 'latin1' or 'shift_jis', and
+    /// implicitly enable one-shot copy mode
+    #[arg(long = "charset", value_name = "NAME")]
+    charset: Option<String>,
+    /// Guess the mime-type from the beginning of the input, e.g. 'image/png' for PNG and all the
+    /// text types for UTF-8 text, and implicitly enable one-shot copy mode
+    #[arg(long = "detect-type", num_args = 0,
+        conflicts_with_all = ["mime_types", "charset", "command", "manifest"])]
+    detect_type: bool,
+    /// Run the command for every paste and copy its output instead of reading from stdin
+    #[arg(long = "command", visible_alias = "follow", value_name = "CMD",
+        conflicts_with_all = ["oneshot", "append", "charset", "max_input", "no_tty"])]
+    command: Option<String>,
+    /// Copy the mime-types and their content listed in the JSON manifest instead of reading from
+    /// stdin
+    #[arg(long = "manifest", value_name = "FILE",
+        conflicts_with_all = ["oneshot", "mime_types", "append", "charset", "command", "max_input",
+            "no_tty"])]
+    manifest: Option<std::path::PathBuf>,
+    /// Pipe the content through the command and copy its output instead
+    #[arg(long = "filter", value_name = "CMD", conflicts_with = "command")]
+    filter: Option<String>,
+    /// Listen on the Unix socket and copy the bulk mode content of every connection, until killed
+    #[cfg(target_os = "linux")]
+    #[arg(long = "socket", value_name = "PATH",
+        conflicts_with_all = ["oneshot", "mime_types", "append", "charset", "detect_type",
+            "command", "filter", "no_tty"])]
+    socket: Option<PathBuf>,
+    /// Fail if more than the given bytes are received from stdin
+    #[arg(long = "max-input", value_name = "BYTES")]
+    max_input: Option<u64>,
+    /// Fail instead of reading from stdin when it is a terminal
+    #[arg(long = "no-tty", num_args = 0)]
+    no_tty: bool,
     /// For testing X INCR mode
     #[arg(
         long = "chunk-size",
@@ -52,13 +194,152 @@ struct CopyArgs {
     chunk_size: usize,
 }
 
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+enum Selection {
+    Clipboard,
+    Primary,
+    Both,
+}
+
 /// Arguments for paste command
 #[derive(Args)]
 struct PasteArgs {
     /// List the offered mime-types of the current clipboard only without the contents
     #[arg(long = "list-types", short = 'l', num_args = 0)]
     list_types: bool,
-    /// Specify the preferred mime-type to be pasted
+    /// Prefix the listed mime-types with their indexes which can be used by '--index'
+    #[arg(long = "numbered", num_args = 0, requires = "list_types")]
+    // The raw pasteboard types are not indexed the same way as '--index'
+    #[cfg_attr(target_os = "macos", arg(conflicts_with = "all"))]
+    numbered: bool,
+    /// Don't end the last listed mime-type with a newline
+    #[arg(long = "no-final-newline", num_args = 0, requires = "list_types")]
+    no_final_newline: bool,
+    /// Also list the content size in bytes of every mime-type, '?' if unknown. The content is
+    /// transferred once to measure it
+    #[arg(long = "with-sizes", num_args = 0, requires = "list_types")]
+    with_sizes: bool,
+    /// Specify the preferred mime-type to be pasted. Multiple mime-types with weights can be
+    /// given like 'text/html;q=0.9,text/plain;q=0.8'
+    #[arg(
+        long = "type",
+        short = 't',
+        value_name = "mime-type",
+        num_args = 1,
+        default_value = ""
+    )]
+    type_: String,
+    /// Paste the mime-type at the given index of the offered list, as shown by '--list-types'
+    #[arg(long = "index", value_name = "N", conflicts_with = "type_")]
+    index: Option<usize>,
+    /// Paste the largest or the smallest content if several offered mime-types match '--type',
+    /// e.g. 'image/*'. Every candidate is transferred once more to get its size
+    #[arg(long = "prefer-size", value_name = "size", value_enum,
+        conflicts_with_all = ["list_types", "index", "all_types"])]
+    prefer_size: Option<PreferSize>,
+    /// Use the 'primary' clipboard
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long = "primary",
+        short = 'p',
+        num_args = 0,
+        conflicts_with = "selection"
+    )]
+    primary: bool,
+    /// Paste from the given selection, overrides '$RICHCLIP_DEFAULT_SELECTION'
+    #[cfg(target_os = "linux")]
+    #[arg(long = "selection", value_name = "selection",
+        value_parser = clap::builder::PossibleValuesParser::new(["clipboard", "primary"])
+            .map(|s| Selection::from_str(&s, false).unwrap()))]
+    selection: Option<Selection>,
+    /// Request the '--type' directly without asking for TARGETS, for the X selection owners which
+    /// don't implement TARGETS correctly
+    #[cfg(target_os = "linux")]
+    #[arg(long = "raw", num_args = 0, conflicts_with_all = ["list_types", "index"])]
+    raw: bool,
+    /// Guess the mime-type from the content if the X selection owner doesn't offer any
+    #[cfg(target_os = "linux")]
+    #[arg(long = "sniff", num_args = 0, conflicts_with_all = ["list_types", "index"])]
+    sniff: bool,
+    /// Request the target by its atom number, without looking up its name. For debugging the
+    /// selection owners only
+    #[cfg(target_os = "linux")]
+    #[arg(long = "target-atom", value_name = "N",
+        conflicts_with_all = ["list_types", "type_", "index", "all_types"])]
+    target_atom: Option<u32>,
+    /// Print the window which owns the selection, with its WM_NAME and WM_CLASS, instead of
+    /// pasting. Only supported on X
+    #[cfg(target_os = "linux")]
+    #[arg(long = "selection-owner-info", num_args = 0,
+        conflicts_with_all = ["list_types", "index", "all_types", "measure", "prefer_size", "raw",
+            "sniff", "target_atom", "wait_for_change", "framed"])]
+    selection_owner_info: bool,
+    /// Skip the content already in the clipboard, wait until something new is copied and paste it.
+    /// Only supported on Wayland with wlr-data-control
+    #[cfg(target_os = "linux")]
+    #[arg(long = "wait-for-change", num_args = 0, conflicts_with_all = ["all_types", "with_sizes", "prefer_size"])]
+    wait_for_change: bool,
+    /// List the mime-types of both the clipboard and the primary selection, each line is prefixed
+    /// with the selection name. Only supported on Wayland with wlr-data-control
+    #[cfg(target_os = "linux")]
+    #[arg(long = "all-selections", num_args = 0, requires = "list_types",
+        conflicts_with_all = ["primary", "selection", "with_sizes", "wait_for_change"])]
+    all_selections: bool,
+    /// Fail if the content is not received in the given seconds
+    #[arg(long = "timeout", value_name = "SECONDS")]
+    timeout: Option<u64>,
+    /// Fail if the X selection owner stops sending the chunks of a large content for the given
+    /// seconds
+    #[arg(long = "chunk-timeout", value_name = "SECONDS")]
+    chunk_timeout: Option<u64>,
+    /// For testing the timeout on X
+    #[arg(long = "poll-interval-ms", hide = true, default_value = "50")]
+    poll_interval_ms: u64,
+    /// Escape the non-printable characters of the text content
+    #[arg(long = "escape", value_name = "mode", value_enum, default_value_t = EscapeMode::None)]
+    escape: EscapeMode,
+    /// Transcode the text content, UTF-16 output starts with a BOM
+    #[arg(long = "encoding", value_name = "encoding", value_enum, default_value_t = Encoding::Utf8)]
+    encoding: Encoding,
+    /// Dump the content of all the offered mime-types, which can be copied back with 'copy'
+    #[arg(long = "all-types", num_args = 0,
+        conflicts_with_all = ["list_types", "type_", "index", "escape", "encoding", "measure"])]
+    all_types: bool,
+    /// Print the mime-type and the size in bytes of the content instead of the content
+    #[arg(long = "measure", num_args = 0, conflicts_with_all = ["list_types", "escape", "encoding"])]
+    measure: bool,
+    /// Remove the ANSI escape sequences, e.g. the colors, from the text content
+    #[arg(long = "strip-ansi", num_args = 0,
+        conflicts_with_all = ["list_types", "all_types", "measure"])]
+    strip_ansi: bool,
+    /// If the output is a terminal, remove the escape sequences and the control characters which
+    /// could control the terminal from the text content
+    #[arg(long = "safe-terminal", num_args = 0,
+        conflicts_with_all = ["list_types", "all_types", "measure"])]
+    safe_terminal: bool,
+    /// Pipe the pasted content through the command before the escaping and the transcoding
+    #[arg(long = "filter", value_name = "CMD",
+        conflicts_with_all = ["list_types", "all_types", "measure"])]
+    filter: Option<String>,
+    /// Also write the pasted content to the file, can be given multiple times
+    #[arg(long = "tee", value_name = "FILE", action = ArgAction::Append,
+        conflicts_with_all = ["all_types", "measure"])]
+    tee: Vec<PathBuf>,
+    /// Prefix the content with its length in bytes as a little-endian u32
+    #[arg(long = "framed", num_args = 0,
+        conflicts_with_all = ["list_types", "all_types", "measure"])]
+    framed: bool,
+    /// List all the raw pasteboard types, including the ones not mapped to mime-types
+    #[cfg(target_os = "macos")]
+    #[arg(long = "all", num_args = 0, requires = "list_types")]
+    all: bool,
+}
+
+/// Arguments for watch command
+#[derive(Args)]
+struct WatchArgs {
+    /// Specify the preferred mime-type to be printed, see 'paste --type'
     #[arg(
         long = "type",
         short = 't',
@@ -71,6 +352,28 @@ struct PasteArgs {
     #[cfg(target_os = "linux")]
     #[arg(long = "primary", short = 'p', num_args = 0)]
     primary: bool,
+    /// Exit after the given number of changes
+    #[arg(long = "count", value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..))]
+    count: Option<u64>,
+    /// Check the pasteboard for changes every given milliseconds
+    #[cfg(target_os = "macos")]
+    #[arg(long = "poll-interval", value_name = "MS", default_value_t = WATCH_POLL_INTERVAL_MS,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    poll_interval: u64,
+    /// Prefix every content with its length in bytes as a little-endian u32, instead of ending it
+    /// with a newline
+    #[arg(long = "framed", num_args = 0)]
+    framed: bool,
+}
+
+/// Arguments for sync command
+#[cfg(target_os = "linux")]
+#[derive(Args)]
+struct SyncArgs {
+    /// Also copy the 'clipboard' changes to the 'primary' clipboard
+    #[arg(long = "bidirectional", num_args = 0)]
+    bidirectional: bool,
 }
 
 #[derive(Subcommand)]
@@ -79,25 +382,56 @@ enum Commands {
     Copy(CopyArgs),
     /// Paste the data from clipboard to the output
     Paste(PasteArgs),
+    /// Print the content of the clipboard on every change, followed by a newline
+    Watch(WatchArgs),
+    /// Copy the text of the 'primary' clipboard to the 'clipboard' on every change
+    #[cfg(target_os = "linux")]
+    Sync(SyncArgs),
+    /// Print the detected environment and the backend to be used
+    Doctor,
     /// Print version info
     Version,
 }
 
-fn init_logger() -> Result<()> {
+fn load_aliases(alias_file: Option<PathBuf>) -> Result<()> {
+    // The command line argument takes precedence over the environment variable
+    let path = match alias_file {
+        Some(path) => path,
+        None => match env::var_os("RICHCLIP_ALIASES") {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => return Ok(()),
+        },
+    };
+    clipboard::mime_type::load_aliases_file(&path)
+}
+
+fn init_logger(log_level: Option<&str>, log_file: Option<&str>, quiet: bool) -> Result<()> {
     use simplelog::{
         ColorChoice, CombinedLogger, ConfigBuilder, LevelFilter, SharedLogger, TermLogger,
         TerminalMode, WriteLogger,
     };
 
-    let log_path = env::var("RICHCLIP_LOG_FILE").unwrap_or("".to_string());
-    let level_str = env::var("RICHCLIP_LOG_LEVEL").unwrap_or("Warn".to_string());
-    let level = LevelFilter::from_str(&level_str).unwrap_or(log::LevelFilter::Warn);
+    // The command line arguments take precedence over the environment variables
+    let log_path = match log_file {
+        Some(path) => path.to_string(),
+        None => env::var("RICHCLIP_LOG_FILE").unwrap_or("".to_string()),
+    };
+    let level = match log_level {
+        Some(level_str) => LevelFilter::from_str(level_str)
+            .map_err(|_| anyhow!("Invalid log level '{level_str}'"))?,
+        None => {
+            let level_str = env::var("RICHCLIP_LOG_LEVEL").unwrap_or("Warn".to_string());
+            LevelFilter::from_str(&level_str).unwrap_or(log::LevelFilter::Warn)
+        }
+    };
+    // The log file still gets everything
+    let term_level = if quiet { LevelFilter::Off } else { level };
     let config = ConfigBuilder::default()
         .set_time_offset_to_local()
         .expect("Failed to set time offset to local for loggers")
         .build();
     let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
-        level,
+        term_level,
         config.clone(),
         TerminalMode::Mixed,
         ColorChoice::Auto,
@@ -106,8 +440,8 @@ fn init_logger() -> Result<()> {
         let log_file = std::fs::OpenOptions::new()
             .create(true)
             .append(true)
-            .open(log_path)
-            .expect("Cannot open the log file at '$RICHCLIP_LOG_FILE'");
+            .open(&log_path)
+            .with_context(|| format!("Cannot open the log file at '{log_path}'"))?;
         loggers.push(WriteLogger::new(LevelFilter::Debug, config, log_file));
     }
     CombinedLogger::init(loggers).context("Failed to initialize loggers")?;
@@ -115,13 +449,41 @@ fn init_logger() -> Result<()> {
 }
 
 fn main() -> Result<()> {
-    init_logger()?;
-
     let cli = Cli::parse();
 
-    match cli.command {
-        Commands::Copy(copy_args) => do_copy(&copy_args)?,
-        Commands::Paste(paste_args) => do_paste(&paste_args)?,
+    init_logger(cli.log_level.as_deref(), cli.log_file.as_deref(), cli.quiet)?;
+    load_aliases(cli.alias_file)?;
+    clipboard::set_connect_retries(cli.connect_retries);
+
+    if cli.print_backend {
+        println!("{}", clipboard::create_backend()?.name());
+        return Ok(());
+    }
+    // The subcommand is only optional for '--print-backend'
+    let Some(command) = cli.command else {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "A subcommand is required",
+            )
+            .exit();
+    };
+    match command {
+        Commands::Copy(copy_args) => do_copy(&copy_args, cli.quiet)?,
+        Commands::Paste(paste_args) => {
+            if let Err(e) = do_paste(&paste_args) {
+                // Scripts can tell the type is not offered from the content is missing
+                if let Some(paste_err) = e.downcast_ref::<clipboard::PasteError>() {
+                    eprintln!("Error: {e:?}");
+                    std::process::exit(paste_err.exit_code());
+                }
+                return Err(e);
+            }
+        }
+        Commands::Watch(watch_args) => do_watch(&watch_args)?,
+        #[cfg(target_os = "linux")]
+        Commands::Sync(sync_args) => do_sync(&sync_args)?,
+        Commands::Doctor => clipboard::write_diagnostics(&mut stdout())?,
         Commands::Version => {
             let ver = env!("CARGO_PKG_VERSION");
             let git_desc = env!("VERGEN_GIT_DESCRIBE");
@@ -134,25 +496,42 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn do_copy(copy_args: &CopyArgs) -> Result<()> {
-    const TEXT_TYPES: [&str; 5] = [
-        "text/plain",
-        "text/plain;charset=utf-8",
-        "TEXT",
-        "STRING",
-        "UTF8_STRING",
-    ];
-    let stdin = stdin();
-    let oneshot = copy_args.oneshot || copy_args.mime_types.is_some();
+fn do_copy(copy_args: &CopyArgs, quiet: bool) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    let selection = match copy_args.selection {
+        Some(selection) => selection,
+        None if copy_args.primary || clipboard::default_use_primary() => Selection::Primary,
+        None => Selection::Clipboard,
+    };
+    #[cfg(target_os = "linux")]
+    let use_primary = selection == Selection::Primary;
+    #[cfg(target_os = "linux")]
+    let both_selections = selection == Selection::Both;
+    #[cfg(not(target_os = "linux"))]
+    let both_selections = false;
+    #[cfg(not(target_os = "linux"))]
+    let use_primary = false;
 
-    let source_data = if oneshot {
-        let mime_types = match &copy_args.mime_types {
-            Some(types) => types.to_vec(),
-            _ => TEXT_TYPES.iter().map(|s| s.to_string()).collect(),
-        };
-        protocol::receive_data_oneshot(&stdin, &mime_types)?
-    } else {
-        protocol::receive_data_bulk(&stdin)?
+    #[cfg(target_os = "linux")]
+    if let Some(path) = &copy_args.socket {
+        return serve_socket(path, copy_args.max_input, selection);
+    }
+
+    let source_data: Box<dyn SourceData> = match &copy_args.command {
+        Some(command) => {
+            let mime_types = match &copy_args.mime_types {
+                Some(types) => types.to_vec(),
+                _ => protocol::TEXT_TYPES.iter().map(|s| s.to_string()).collect(),
+            };
+            Box::new(protocol::CommandSourceData::new(command, mime_types)?)
+        }
+        None => match &copy_args.manifest {
+            Some(path) => Box::new(filter_source_data(
+                protocol::receive_data_manifest(path)?,
+                copy_args.filter.as_deref(),
+            )?),
+            None => Box::new(read_source_data(copy_args, use_primary, quiet)?),
+        },
     };
 
     #[cfg(target_os = "linux")]
@@ -164,8 +543,13 @@ fn do_copy(copy_args: &CopyArgs) -> Result<()> {
         // The above is copied from wl-clipboard.
         let out_null = File::create("/dev/null")?;
         let err_null = File::create("/dev/null")?;
+        // Resolve the relative path before moving to the root
+        let pid_file = match &copy_args.pid_file {
+            Some(path) => Some(std::path::absolute(path)?),
+            None => None,
+        };
 
-        if !copy_args.foreground {
+        if !copy_args.no_detach {
             let daemonize = Daemonize::new()
                 .working_directory("/") // prevent blocking fs from being unmounted.
                 .stdout(out_null)
@@ -173,36 +557,568 @@ fn do_copy(copy_args: &CopyArgs) -> Result<()> {
 
             // wl-clipboard does this
             ignore_sighub();
-            daemonize.start()?;
+            daemonize_copy(daemonize, pid_file.as_deref())?;
+        } else if let Some(path) = &pid_file {
+            write_pid_file(path)?;
         }
     }
+    // The pasteboard keeps the content, so the copy always returns immediately
+    #[cfg(not(target_os = "linux"))]
+    if copy_args.no_detach {
+        log::debug!("'--no-detach' has no effect, the content is stored by the system");
+    }
 
     let copy_config = clipboard::CopyConfig {
-        source_data: Box::new(source_data),
+        source_data,
+        use_primary,
+        both_selections,
+        x_chunk_size: copy_args.chunk_size,
         #[cfg(target_os = "linux")]
-        use_primary: copy_args.primary,
+        serve_once: copy_args.serve_once,
         #[cfg(not(target_os = "linux"))]
-        use_primary: false,
-        x_chunk_size: copy_args.chunk_size,
+        serve_once: false,
+        #[cfg(target_os = "linux")]
+        expire: copy_args.expire.map(Duration::from_secs),
+        #[cfg(not(target_os = "linux"))]
+        expire: None,
+        #[cfg(target_os = "linux")]
+        idle_timeout: copy_args.idle_timeout.map(Duration::from_secs),
+        #[cfg(not(target_os = "linux"))]
+        idle_timeout: None,
+        #[cfg(target_os = "linux")]
+        clear_on_expire: copy_args.clear_on_expire,
+        #[cfg(not(target_os = "linux"))]
+        clear_on_expire: false,
+        #[cfg(target_os = "linux")]
+        x_persist_primary: copy_args.persist_primary,
+        #[cfg(not(target_os = "linux"))]
+        x_persist_primary: false,
+        #[cfg(target_os = "linux")]
+        x_exclusive: copy_args.exclusive,
+        #[cfg(not(target_os = "linux"))]
+        x_exclusive: false,
+        #[cfg(target_os = "macos")]
+        mac_no_clear: copy_args.no_clear,
+        #[cfg(not(target_os = "macos"))]
+        mac_no_clear: false,
     };
     clipboard::create_backend()?
         .copy(copy_config)
         .context("Failed to copy to clipboard")
 }
 
+/// Read the content to be copied from stdin.
+fn read_source_data(
+    copy_args: &CopyArgs,
+    use_primary: bool,
+    quiet: bool,
+) -> Result<Vec<protocol::SourceDataItem>> {
+    let stdin = stdin();
+    let oneshot = copy_args.oneshot
+        || copy_args.mime_types.is_some()
+        || copy_args.append
+        || copy_args.charset.is_some()
+        || copy_args.detect_type;
+
+    if stdin.is_terminal() {
+        if copy_args.no_tty {
+            bail!("stdin is a terminal, pipe the input to copy instead");
+        }
+        if !quiet {
+            eprintln!("Reading from terminal; press Ctrl-D to finish, or pipe input");
+        }
+    }
+
+    let reader: Box<dyn Read> = match copy_args.max_input {
+        Some(limit) => Box::new(protocol::LimitedReader::new(stdin, limit)),
+        None => Box::new(stdin),
+    };
+    let mut source_data = if oneshot {
+        let mime_types = match &copy_args.mime_types {
+            Some(types) => types.to_vec(),
+            _ => protocol::TEXT_TYPES.iter().map(|s| s.to_string()).collect(),
+        };
+        protocol::receive_data_oneshot(reader, &mime_types)?
+    } else {
+        protocol::receive_data_bulk(reader)?
+    };
+    if copy_args.detect_type {
+        for item in &mut source_data {
+            item.mime_type = clipboard::mime_type::detect_content_type(&item.content);
+            log::debug!("Detected mime-types {:?}", item.mime_type);
+        }
+    }
+    if let Some(charset) = &copy_args.charset {
+        source_data = decode_source_data(source_data, charset)?;
+    }
+    if copy_args.append {
+        source_data = append_to_clipboard(source_data, use_primary)?;
+    }
+    filter_source_data(source_data, copy_args.filter.as_deref())
+}
+
+/// Prepend the current text content of the clipboard to the one-shot content.
+/// NOTE: The clipboard could be changed by others between reading and setting it.
+fn append_to_clipboard(
+    source_data: Vec<protocol::SourceDataItem>,
+    use_primary: bool,
+) -> Result<Vec<protocol::SourceDataItem>> {
+    // One-shot mode always has exactly one item
+    let Some(item) = source_data.into_iter().next() else {
+        bail!("Nothing to append");
+    };
+    if let Some(t) = item
+        .mime_type
+        .iter()
+        .find(|t| !clipboard::mime_type::is_text_mime_type(t))
+    {
+        bail!("'--append' only works with text, but '{t}' is given");
+    }
+
+    let existing = richclip::get_bytes("", use_primary).unwrap_or_else(|e| {
+        log::debug!("No text content to append to: {e:#}");
+        vec![]
+    });
+    if std::str::from_utf8(&existing).is_err() || std::str::from_utf8(&item.content).is_err() {
+        bail!("Cannot append, the content is not valid UTF-8 text");
+    }
+    let mut content = existing;
+    content.extend_from_slice(&item.content);
+    Ok(vec![protocol::SourceDataItem::new(item.mime_type, content)])
+}
+
+/// Transcode the one-shot text content from the charset to UTF-8, and offer it as UTF-8 text as
+/// well.
+fn decode_source_data(
+    source_data: Vec<protocol::SourceDataItem>,
+    charset: &str,
+) -> Result<Vec<protocol::SourceDataItem>> {
+    // One-shot mode always has exactly one item
+    let Some(item) = source_data.into_iter().next() else {
+        bail!("Nothing to transcode");
+    };
+    if let Some(t) = item
+        .mime_type
+        .iter()
+        .find(|t| !clipboard::mime_type::is_text_mime_type(t))
+    {
+        bail!("'--charset' only works with text, but '{t}' is given");
+    }
+    let content = richclip::transform::decode_charset(charset, &item.content)
+        .context("Failed to transcode the input")?;
+    let mut mime_types = item.mime_type;
+    if !mime_types
+        .iter()
+        .any(|t| t.eq_ignore_ascii_case(UTF8_TEXT_TYPE))
+    {
+        mime_types.push(UTF8_TEXT_TYPE.to_string());
+    }
+    Ok(vec![protocol::SourceDataItem::new(mime_types, content)])
+}
+
+/// Replace the content of every item with the output of the filter command, if it is given.
+fn filter_source_data(
+    source_data: Vec<protocol::SourceDataItem>,
+    command: Option<&str>,
+) -> Result<Vec<protocol::SourceDataItem>> {
+    let Some(command) = command else {
+        return Ok(source_data);
+    };
+    let mut filtered: Vec<protocol::SourceDataItem> = vec![];
+    for item in source_data {
+        let content = richclip::transform::filter_content(command, &item.content)
+            .with_context(|| format!("Failed to filter the content of {:?}", item.mime_type))?;
+        let item = protocol::SourceDataItem::new_dedup(item.mime_type, content, &filtered);
+        filtered.push(item);
+    }
+    Ok(filtered)
+}
+
 fn do_paste(paste_args: &PasteArgs) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    let selection = match paste_args.selection {
+        Some(selection) => selection,
+        None if paste_args.primary || clipboard::default_use_primary() => Selection::Primary,
+        None => Selection::Clipboard,
+    };
+    #[cfg(target_os = "linux")]
+    let use_primary = selection == Selection::Primary;
+    #[cfg(not(target_os = "linux"))]
+    let use_primary = false;
+    if paste_args.all_types {
+        let items = richclip::get_all(use_primary)?;
+        return protocol::send_data_bulk(stdout().lock(), &items);
+    }
+    let mut expected_mime_types =
+        clipboard::mime_type::parse_preferred_mime_types(&paste_args.type_)?;
+    #[cfg(target_os = "linux")]
+    clipboard::cancel_paste_on_sigint()?;
+    if let Some(prefer) = paste_args.prefer_size {
+        let backend = clipboard::create_backend()?;
+        if let Some(mime_type) = clipboard::decide_mime_type_by_size(
+            backend.as_ref(),
+            use_primary,
+            &expected_mime_types,
+            prefer,
+        )? {
+            log::debug!("Paste '{mime_type}' which has the {prefer:?} content");
+            expected_mime_types = vec![mime_type];
+        }
+    }
+    let mut writer = MultiWriter::default();
+    writer.push("stdout", Box::new(stdout()));
+    for path in &paste_args.tee {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create the tee file {}", path.display()))?;
+        writer.push(&path.display().to_string(), Box::new(file));
+    }
+    let writer: Box<dyn Write> = if paste_args.framed {
+        Box::new(FramedWriter::new(Box::new(writer)))
+    } else {
+        Box::new(writer)
+    };
+    let escape = paste_args.escape;
+    let encoding = paste_args.encoding;
+    let strip_ansi = paste_args.strip_ansi;
+    // Only the terminal is controlled by the sequences, the pipes and the files get them as they are
+    let safe_terminal = paste_args.safe_terminal && stdout().is_terminal();
+    let filter = paste_args.filter.clone();
+    let counter = CountingWriter::default();
+    let measured_type = Rc::new(RefCell::new(String::new()));
+    let wrap_writer: clipboard::WriterWrapper = if paste_args.measure {
+        let counter = counter.clone();
+        let measured_type = measured_type.clone();
+        Box::new(move |mime_type, _| {
+            *measured_type.borrow_mut() = mime_type.to_string();
+            Box::new(counter)
+        })
+    } else {
+        Box::new(move |mime_type, writer| {
+            let writer = wrap_paste_writer(
+                strip_ansi,
+                safe_terminal,
+                escape,
+                encoding,
+                mime_type,
+                writer,
+            );
+            match filter {
+                Some(command) => Box::new(FilterWriter::new(writer, &command)),
+                None => writer,
+            }
+        })
+    };
     let cfg = clipboard::PasteConfig {
         list_types_only: paste_args.list_types,
+        list_numbered: paste_args.numbered,
+        list_final_newline: !paste_args.no_final_newline,
+        list_raw: false,
+        #[cfg(target_os = "macos")]
+        list_all_types: paste_args.all,
+        #[cfg(not(target_os = "macos"))]
+        list_all_types: false,
+        use_primary,
+        writer,
+        expected_mime_types,
+        target_index: paste_args.index,
+        #[cfg(target_os = "linux")]
+        x_raw: paste_args.raw,
+        #[cfg(not(target_os = "linux"))]
+        x_raw: false,
+        #[cfg(target_os = "linux")]
+        x_sniff: paste_args.sniff,
+        #[cfg(not(target_os = "linux"))]
+        x_sniff: false,
+        #[cfg(target_os = "linux")]
+        x_target_atom: paste_args.target_atom,
+        #[cfg(not(target_os = "linux"))]
+        x_target_atom: None,
         #[cfg(target_os = "linux")]
-        use_primary: paste_args.primary,
+        x_owner_info: paste_args.selection_owner_info,
         #[cfg(not(target_os = "linux"))]
-        use_primary: false,
-        writer: Box::new(stdout()),
-        expected_mime_type: paste_args.type_.clone(),
+        x_owner_info: false,
+        #[cfg(target_os = "linux")]
+        wayland_wait_for_change: paste_args.wait_for_change,
+        #[cfg(not(target_os = "linux"))]
+        wayland_wait_for_change: false,
+        #[cfg(target_os = "linux")]
+        wayland_all_selections: paste_args.all_selections,
+        #[cfg(not(target_os = "linux"))]
+        wayland_all_selections: false,
+        timeout: paste_args.timeout.map(Duration::from_secs),
+        x_chunk_timeout: paste_args.chunk_timeout.map(Duration::from_secs),
+        x_poll_interval: Duration::from_millis(paste_args.poll_interval_ms),
+        wrap_writer: Some(wrap_writer),
+    };
+    let backend = clipboard::create_backend()?;
+    if paste_args.with_sizes {
+        clipboard::write_type_sizes(backend.as_ref(), cfg)
+    } else {
+        backend.paste(cfg)
+    }
+    .context("Failed to paste from clipboard")?;
+    if paste_args.measure {
+        println!("{} {}", measured_type.borrow(), counter.count());
+    }
+    Ok(())
+}
+
+fn do_watch(watch_args: &WatchArgs) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    let use_primary = watch_args.primary;
+    #[cfg(not(target_os = "linux"))]
+    let use_primary = false;
+    let expected_mime_types = clipboard::mime_type::parse_preferred_mime_types(&watch_args.type_)?;
+    let framed = watch_args.framed;
+
+    let on_change = move || -> Result<()> {
+        let writer: Box<dyn Write> = if framed {
+            Box::new(FramedWriter::new(Box::new(stdout())))
+        } else {
+            Box::new(stdout())
+        };
+        let cfg = clipboard::PasteConfig {
+            list_types_only: false,
+            list_all_types: false,
+            list_numbered: false,
+            list_final_newline: true,
+            list_raw: false,
+            use_primary,
+            writer,
+            expected_mime_types: expected_mime_types.clone(),
+            target_index: None,
+            x_raw: false,
+            x_sniff: false,
+            x_target_atom: None,
+            x_owner_info: false,
+            wayland_wait_for_change: false,
+            wayland_all_selections: false,
+            timeout: None,
+            x_chunk_timeout: None,
+            x_poll_interval: Duration::from_millis(50),
+            wrap_writer: None,
+        };
+        // The content could be gone already, or it doesn't have the expected type
+        if let Err(e) = clipboard::create_backend()?.paste(cfg) {
+            log::warn!("Failed to paste the changed content: {e:#}");
+        }
+        if framed {
+            // The frame is written when the content is flushed, and skipped if the paste failed
+            return Ok(());
+        }
+        let mut out = stdout();
+        writeln!(out)?;
+        out.flush()?;
+        Ok(())
+    };
+    let cfg = clipboard::WatchConfig {
+        use_primary,
+        count: watch_args.count.map(|c| c as usize),
+        #[cfg(target_os = "macos")]
+        poll_interval: Duration::from_millis(watch_args.poll_interval),
+        #[cfg(not(target_os = "macos"))]
+        poll_interval: Duration::from_millis(WATCH_POLL_INTERVAL_MS),
+        on_change: Box::new(on_change),
     };
     clipboard::create_backend()?
-        .paste(cfg)
-        .context("Failed to paste from clipboard")
+        .watch(cfg)
+        .context("Failed to watch the clipboard")
+}
+
+#[cfg(target_os = "linux")]
+fn do_sync(sync_args: &SyncArgs) -> Result<()> {
+    // The hash of the last synced text, shared by both directions to avoid the feedback loop
+    let last_hash = Arc::new(Mutex::new(None));
+    if sync_args.bidirectional {
+        let last_hash = last_hash.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = sync_selection(false, last_hash) {
+                log::error!("Failed to sync the clipboard to primary: {e:#}");
+                std::process::exit(1);
+            }
+        });
+    }
+    sync_selection(true, last_hash)
+}
+
+/// Watch the text changes of one selection and copy them to the other one.
+#[cfg(target_os = "linux")]
+fn sync_selection(from_primary: bool, last_hash: Arc<Mutex<Option<u64>>>) -> Result<()> {
+    let on_change = move || -> Result<()> {
+        let content = match richclip::get_bytes("", from_primary) {
+            Ok(content) if !content.is_empty() => content,
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                log::debug!("No text content to sync: {e:#}");
+                return Ok(());
+            }
+        };
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        let hash = hasher.finish();
+        {
+            let mut last_hash = last_hash.lock().unwrap();
+            if *last_hash == Some(hash) {
+                log::debug!("Skip the content which has been synced");
+                return Ok(());
+            }
+            *last_hash = Some(hash);
+        }
+        let to = if from_primary {
+            Selection::Clipboard
+        } else {
+            Selection::Primary
+        };
+        copy_in_background(to, true, |stdin| Ok(stdin.write_all(&content)?))
+    };
+    let cfg = clipboard::WatchConfig {
+        use_primary: from_primary,
+        count: None,
+        poll_interval: Duration::from_millis(WATCH_POLL_INTERVAL_MS),
+        on_change: Box::new(on_change),
+    };
+    clipboard::create_backend()?
+        .watch(cfg)
+        .context("Failed to watch the clipboard")
+}
+
+/// Accept the connections on the Unix socket one by one, and copy the bulk mode content received
+/// from each of them. The socket file is left behind when killed, and it is replaced on the next
+/// start unless another process is still listening on it.
+#[cfg(target_os = "linux")]
+fn serve_socket(path: &Path, max_input: Option<u64>, selection: Selection) -> Result<()> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            bail!("The socket '{}' is in use already", path.display());
+        }
+        log::debug!("Remove the stale socket '{}'", path.display());
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on '{}'", path.display()))?;
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Failed to accept the connection: {e}");
+                continue;
+            }
+        };
+        let reader: Box<dyn Read> = match max_input {
+            Some(limit) => Box::new(protocol::LimitedReader::new(stream, limit)),
+            None => Box::new(stream),
+        };
+        // A bad client should not stop the service
+        let result = protocol::receive_data_bulk(reader).and_then(|items| {
+            copy_in_background(selection, false, |stdin| {
+                protocol::send_data_bulk(stdin, &items)
+            })
+        });
+        match result {
+            Ok(()) => log::debug!("Copied the content from the socket"),
+            Err(e) => log::warn!("Failed to copy the content from the socket: {e:#}"),
+        }
+    }
+    Ok(())
+}
+
+/// Copy with the copy command, which serves the content in the background. The copy blocks on X
+/// and Wayland, so it cannot be done in the watching or listening process.
+#[cfg(target_os = "linux")]
+fn copy_in_background(
+    selection: Selection,
+    oneshot: bool,
+    write_input: impl FnOnce(&mut ChildStdin) -> Result<()>,
+) -> Result<()> {
+    let mut cmd = Command::new(env::current_exe()?);
+    cmd.arg("copy").stdin(Stdio::piped());
+    if oneshot {
+        cmd.arg("--one-shot");
+    }
+    if let Some(value) = selection.to_possible_value() {
+        cmd.args(["--selection", value.get_name()]);
+    }
+    let mut child = cmd.spawn().context("Failed to run the copy command")?;
+    // Close the stdin after writing, so the copy command stops reading
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("No stdin for the copy command")?;
+    let written = write_input(&mut stdin);
+    drop(stdin);
+    let status = child.wait()?;
+    written?;
+    if !status.success() {
+        bail!("The copy command failed with {status}");
+    }
+    Ok(())
+}
+
+fn wrap_paste_writer(
+    strip_ansi: bool,
+    safe_terminal: bool,
+    escape: EscapeMode,
+    encoding: Encoding,
+    mime_type: &str,
+    writer: Box<dyn Write>,
+) -> Box<dyn Write> {
+    // Transforms only apply to the text content
+    if !clipboard::mime_type::is_text_mime_type(mime_type) {
+        return writer;
+    }
+    let mut writer = writer;
+    // The escaped text is encoded
+    if encoding != Encoding::Utf8 {
+        writer = Box::new(EncodingWriter::new(writer, encoding));
+    }
+    // Filter the UTF-8 text right before the encoding
+    if safe_terminal {
+        writer = Box::new(SafeTerminalWriter::new(writer));
+    }
+    if escape != EscapeMode::None {
+        writer = Box::new(EscapeWriter::new(writer, escape));
+    }
+    // The escape sequences are stripped before the escaping
+    if strip_ansi {
+        writer = Box::new(StripAnsiWriter::new(writer));
+    }
+    writer
+}
+
+/// Move to background. With the pid file, the parent waits until the background process has
+/// written it, so the file can be read as soon as the copy returns.
+#[cfg(target_os = "linux")]
+fn daemonize_copy(daemonize: Daemonize<()>, pid_file: Option<&Path>) -> Result<()> {
+    let Some(pid_file) = pid_file else {
+        daemonize.start()?;
+        return Ok(());
+    };
+    let (mut ready_read, mut ready_write) = std::io::pipe()?;
+    match daemonize.execute() {
+        Outcome::Parent(Ok(parent)) => {
+            drop(ready_write);
+            // Closed without a byte if the background process failed to write the file
+            let mut buf = [0u8; 1];
+            if ready_read.read(&mut buf)? == 0 {
+                bail!("Failed to write the pid file {}", pid_file.display());
+            }
+            std::process::exit(parent.first_child_exit_code);
+        }
+        Outcome::Parent(Err(e)) => Err(e.into()),
+        Outcome::Child(Ok(_)) => {
+            drop(ready_read);
+            write_pid_file(pid_file)?;
+            ready_write.write_all(b"1")?;
+            Ok(())
+        }
+        Outcome::Child(Err(e)) => Err(e.into()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn write_pid_file(path: &Path) -> Result<()> {
+    std::fs::write(path, format!("{}\n", std::process::id()))
+        .with_context(|| format!("Failed to write the pid file {}", path.display()))
 }
 
 #[cfg(target_os = "linux")]
@@ -214,8 +1130,7 @@ fn ignore_sighub() {
     }
 
     const SIGHUB: i32 = 1;
-    const SIG_IGN: *const c_void = 1 as *const c_void;
     unsafe {
-        signal(SIGHUB, SIG_IGN);
+        signal(SIGHUB, libc::SIG_IGN as *const c_void);
     }
 }
@@ -0,0 +1,106 @@
+use super::source_data::SourceDataItem;
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Deserialize;
+use std::path::Path;
+
+/// One entry of the manifest, the content is either in a file or inline.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestEntry {
+    mime: String,
+    file: Option<String>,
+    data_base64: Option<String>,
+}
+
+/// Read the JSON manifest which lists the mime-types and their content, like
+/// `[{"mime":"text/html","file":"x.html"},{"mime":"text/plain","data_base64":"R09PRA=="}]`.
+/// The relative file paths are relative to the manifest.
+pub fn receive_data_manifest(path: &Path) -> Result<Vec<SourceDataItem>> {
+    let json = std::fs::read(path)
+        .with_context(|| format!("Failed to read the manifest '{}'", path.display()))?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    parse_manifest(&json, base_dir)
+        .with_context(|| format!("Invalid manifest '{}'", path.display()))
+}
+
+fn parse_manifest(json: &[u8], base_dir: &Path) -> Result<Vec<SourceDataItem>> {
+    let entries: Vec<ManifestEntry> = serde_json::from_slice(json)?;
+    if entries.is_empty() {
+        bail!("No entries in the manifest");
+    }
+    let mut ret = Vec::<SourceDataItem>::new();
+    for (i, entry) in entries.into_iter().enumerate() {
+        if entry.mime.is_empty() {
+            bail!("Entry {i} has an empty 'mime'");
+        }
+        let content = match (entry.file, entry.data_base64) {
+            (Some(file), None) => {
+                let path = base_dir.join(&file);
+                std::fs::read(&path)
+                    .with_context(|| format!("Failed to read '{}' of entry {i}", path.display()))?
+            }
+            (None, Some(data)) => BASE64
+                .decode(data.trim())
+                .with_context(|| format!("Invalid 'data_base64' of entry {i}"))?,
+            _ => bail!("Entry {i} needs exactly one of 'file' and 'data_base64'"),
+        };
+        if ret
+            .iter()
+            .any(|item| item.mime_type.iter().any(|t| t == &entry.mime))
+        {
+            bail!("Entry {i} has a duplicated mime-type '{}'", entry.mime);
+        }
+        let item = SourceDataItem::new_dedup(vec![entry.mime], content, &ret);
+        ret.push(item);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::SourceData;
+
+    #[test]
+    fn test_parse_manifest() {
+        let dir = std::env::temp_dir().join(format!("richclip-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("x.html"), b"<b>GOOD</b>").unwrap();
+
+        let json = br#"[
+            {"mime": "text/html", "file": "x.html"},
+            {"mime": "text/plain", "data_base64": "R09PRA=="},
+            {"mime": "TEXT", "data_base64": "R09PRA=="}
+        ]"#;
+        let r = parse_manifest(json, &dir).unwrap();
+        assert_eq!(r.mime_types(), ["text/html", "text/plain", "TEXT"]);
+        assert_eq!(*r.content_by_mime_type("text/html").1, b"<b>GOOD</b>");
+        assert_eq!(*r.content_by_mime_type("text/plain").1, b"GOOD");
+        // The same content is shared
+        assert!(std::rc::Rc::ptr_eq(&r[1].content, &r[2].content));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_manifest_invalid() {
+        let dir = Path::new("/nonexistent");
+        let err = |json: &str| match parse_manifest(json.as_bytes(), dir) {
+            Ok(_) => panic!("'{json}' should be invalid"),
+            Err(e) => format!("{e:#}"),
+        };
+
+        assert!(err("{}").contains("invalid type"));
+        assert!(err("[]").contains("No entries"));
+        assert!(err(r#"[{"mime": "a"}]"#).contains("exactly one of"));
+        assert!(err(r#"[{"mime": "a", "file": "f", "data_base64": ""}]"#).contains("exactly one"));
+        assert!(err(r#"[{"mime": "a", "data": ""}]"#).contains("unknown field"));
+        assert!(err(r#"[{"file": "f"}]"#).contains("missing field `mime`"));
+        assert!(err(r#"[{"mime": "a", "data_base64": "!"}]"#).contains("Invalid 'data_base64'"));
+        assert!(err(r#"[{"mime": "a", "file": "f"}]"#).contains("Failed to read"));
+        let dup = r#"[{"mime": "a", "data_base64": ""}, {"mime": "a", "data_base64": ""}]"#;
+        assert!(err(dup).contains("duplicated"));
+    }
+}
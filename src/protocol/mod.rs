@@ -1,8 +1,15 @@
+mod manifest;
 mod recv;
+mod send;
 mod source_data;
 
-#[allow(unused_imports)]
-pub use recv::PROTOCOL_VER;
+pub use manifest::receive_data_manifest;
+pub use recv::LimitedReader;
+pub use recv::PROTOCOL_VERSION;
+pub use recv::TEXT_TYPES;
 pub use recv::receive_data_bulk;
 pub use recv::receive_data_oneshot;
+pub use send::send_data_bulk;
+pub use source_data::CommandSourceData;
 pub use source_data::SourceData;
+pub use source_data::SourceDataItem;
@@ -1,11 +1,61 @@
 use anyhow::{Context, Result, bail};
-use std::io::Read;
+use std::io::{Error, ErrorKind, Read};
 
-pub static PROTOCOL_VER: u8 = 0;
-static MAGIC: [u8; 4] = [0x20, 0x09, 0x02, 0x14];
+/// The version of the bulk mode protocol, sent right after the magic header.
+pub static PROTOCOL_VERSION: u8 = 0;
+pub(super) static MAGIC: [u8; 4] = [0x20, 0x09, 0x02, 0x14];
+/// The default mime-types for the text content.
+pub const TEXT_TYPES: [&str; 5] = [
+    "text/plain",
+    "text/plain;charset=utf-8",
+    "TEXT",
+    "STRING",
+    "UTF8_STRING",
+];
 
 use super::source_data::SourceDataItem;
 
+/// A reader which fails when more than `limit` bytes are read from the inner reader. Unlike
+/// `Read::take`, exceeding the limit is an error instead of a silent EOF.
+pub struct LimitedReader<R> {
+    inner: R,
+    left: u64,
+    limit: u64,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        LimitedReader {
+            inner,
+            left: limit,
+            limit,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.left == 0 {
+            // Only an error if there is more data
+            let mut byte = [0u8; 1];
+            return match self.inner.read(&mut byte)? {
+                0 => Ok(0),
+                _ => Err(Error::other(format!(
+                    "Input exceeds the limit of {} bytes",
+                    self.limit
+                ))),
+            };
+        }
+        let max = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
 /// Receive the mime-types and the content for the clipboard.
 /// It uses a simple protocol which defines as below:
 ///
@@ -47,7 +97,7 @@ pub fn receive_data_bulk(mut reader: impl Read) -> Result<Vec<SourceDataItem>> {
     reader
         .read_exact(&mut ver)
         .context("Failed to read protocol version")?;
-    if ver[0] != PROTOCOL_VER {
+    if ver[0] != PROTOCOL_VERSION {
         bail!("Failed to match protoal version: {}", ver[0]);
     }
 
@@ -55,9 +105,17 @@ pub fn receive_data_bulk(mut reader: impl Read) -> Result<Vec<SourceDataItem>> {
     let mut type_list = Vec::new();
     let mut ret = Vec::<SourceDataItem>::new();
     loop {
-        let r = reader.read(&mut flag).context("Failed to read flag")?;
+        let r = match reader.read(&mut flag) {
+            // A pipe from e.g. ssh can be interrupted by signals
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            r => r.context("Failed to read flag")?,
+        };
         // EOF
         if r == 0 {
+            // The stream is cut in the middle of an item, e.g. the connection is lost
+            if !type_list.is_empty() {
+                bail!("The input ends after the mime-types {type_list:?} without their content");
+            }
             break;
         }
         log::debug!("Read block flag '{}'", flag[0]);
@@ -71,10 +129,8 @@ pub fn receive_data_bulk(mut reader: impl Read) -> Result<Vec<SourceDataItem>> {
                     bail!("Failed to read content with empty mime type");
                 }
                 let content = read_content(&mut reader)?;
-                ret.push(SourceDataItem {
-                    mime_type: type_list,
-                    content: content.into(),
-                });
+                let item = SourceDataItem::new_dedup(type_list, content, &ret);
+                ret.push(item);
                 type_list = Vec::new();
             }
             _ => {
@@ -86,32 +142,40 @@ pub fn receive_data_bulk(mut reader: impl Read) -> Result<Vec<SourceDataItem>> {
     Ok(ret)
 }
 
+/// Receive the content as it is, for the given mime-types. The mime-types are checked before
+/// reading anything.
 pub fn receive_data_oneshot(
     mut reader: impl Read,
     mime_types: &[String],
 ) -> Result<Vec<SourceDataItem>> {
+    check_mime_types(mime_types)?;
     let mut content = Vec::<u8>::new();
     let n = reader
         .read_to_end(&mut content)
         .context("Failed to read content")?;
     log::debug!("Read {n} bytes for oneshot mode");
 
-    let filtered: Vec<String> = mime_types
-        .iter()
-        .filter(|t| !t.is_empty())
-        .map(String::clone)
-        .collect();
-    if filtered.is_empty() {
-        bail!("All given mime_types are empty");
-    }
-
-    let ret = vec![SourceDataItem {
-        mime_type: filtered,
-        content: content.into(),
-    }];
+    let ret = vec![SourceDataItem::new(mime_types.to_vec(), content)];
     Ok(ret)
 }
 
+/// Reject the mime-types which cannot be offered, e.g. the empty ones, or the ones with newlines
+/// or NULs which would break the X atoms and the Wayland offers later.
+fn check_mime_types(mime_types: &[String]) -> Result<()> {
+    if mime_types.is_empty() {
+        bail!("No mime-type is given");
+    }
+    for mime_type in mime_types {
+        if mime_type.is_empty() {
+            bail!("Empty mime-type in {mime_types:?}");
+        }
+        if mime_type.chars().any(char::is_control) {
+            bail!("Invalid mime-type {mime_type:?}, it contains control characters");
+        }
+    }
+    Ok(())
+}
+
 fn read_mime_types(reader: &mut impl Read) -> Result<String> {
     let mut size_buf = [0u8; 4];
     reader
@@ -145,10 +209,19 @@ fn read_content(reader: &mut impl Read) -> Result<Vec<u8>> {
         + size_buf[3] as u32;
 
     log::debug!("Expected content size: {}", size);
-    let mut buf = vec![0u8; size as usize];
+    // Don't trust the size to allocate the buffer, the input could be limited.
+    let mut buf = Vec::new();
     reader
-        .read_exact(&mut buf)
+        .take(size as u64)
+        .read_to_end(&mut buf)
         .context("Failed to read content")?;
+    if buf.len() != size as usize {
+        bail!(
+            "Failed to read content, expected {} bytes, got {}",
+            size,
+            buf.len()
+        );
+    }
 
     Ok(buf)
 }
@@ -157,6 +230,7 @@ fn read_content(reader: &mut impl Read) -> Result<Vec<u8>> {
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::protocol::SourceData;
 
     #[test]
     fn test_read_mime_types() {
@@ -201,7 +275,7 @@ mod tests {
     #[test]
     fn test_receive_data_bulk() {
         // Wrong magic
-        let buf = [0x02, 0x09, 0x02, 0x14, PROTOCOL_VER, b'M'];
+        let buf = [0x02, 0x09, 0x02, 0x14, PROTOCOL_VERSION, b'M'];
         let r = receive_data_bulk(&mut &buf[..]);
         assert!(r.is_err());
 
@@ -213,7 +287,7 @@ mod tests {
         // correct
         #[rustfmt::skip]
         let buf =
-            [0x20, 0x09, 0x02, 0x14, PROTOCOL_VER,
+            [0x20, 0x09, 0x02, 0x14, PROTOCOL_VERSION,
             b'M', 0, 0, 0, 10, b't', b'e', b'x', b't', b'/', b'p', b'l', b'a', b'i', b'n',
             b'M', 0, 0, 0, 4, b'T', b'E', b'X', b'T',
             b'C', 0, 0, 0, 4, b'G', b'O', b'O', b'D',
@@ -234,6 +308,34 @@ mod tests {
         assert_eq!(data2.content.as_slice(), b"BAD");
     }
 
+    #[test]
+    fn test_receive_data_bulk_truncated() {
+        #[rustfmt::skip]
+        let buf =
+            [0x20, 0x09, 0x02, 0x14, PROTOCOL_VERSION,
+            b'M', 0, 0, 0, 4, b'T', b'E', b'X', b'T',
+            b'C', 0, 0, 0, 4, b'G', b'O', b'O', b'D',
+            b'M', 0, 0, 0, 4, b'H', b'T', b'M', b'L',
+            b'C', 0, 0, 0, 3, b'B', b'A', b'D',
+            ];
+        // The mime-types without the content
+        let e = receive_data_bulk(&buf[..32]).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "The input ends after the mime-types [\"HTML\"] without their content"
+        );
+
+        // Only the prefixes ending right after a content are complete
+        for len in 0..buf.len() {
+            let r = receive_data_bulk(&buf[..len]);
+            match len {
+                5 => assert_eq!(r.unwrap().len(), 0),
+                23 => assert_eq!(r.unwrap().len(), 1),
+                _ => assert!(r.is_err(), "{len} bytes should fail"),
+            }
+        }
+    }
+
     #[test]
     fn test_receive_data_oneshot() {
         let buf = [b'G', b'O', b'O', b'D'];
@@ -257,6 +359,80 @@ mod tests {
 
         // With zero mime-type
         let r = receive_data_oneshot(&mut &buf[..], &["".to_string()]);
-        assert!(r.is_err())
+        assert!(r.is_err());
+        let r = receive_data_oneshot(&mut &buf[..], &[]);
+        assert!(r.is_err());
+
+        // The valid types are kept as they are
+        let types = [
+            "image/svg+xml".to_string(),
+            "x-special/gnome-copied-files".to_string(),
+        ];
+        let r = receive_data_oneshot(&mut &buf[..], &types).unwrap();
+        assert_eq!(r[0].mime_type, types);
+
+        // Multiline content is kept as it is, including the trailing newlines
+        let buf = b"first\n\n  second\r\nlast\n\n";
+        let r = receive_data_oneshot(&mut &buf[..], &["text/plain".to_string()]).unwrap();
+        assert_eq!(r[0].content.as_slice(), buf);
+    }
+
+    #[test]
+    fn test_receive_data_oneshot_binary() {
+        // A real PNG has NULs, CRLFs and the bytes which are not valid UTF-8
+        let png = include_bytes!("../../test/data/test_image.png");
+        let types = ["image/png".to_string()];
+        let r = receive_data_oneshot(&mut &png[..], &types).unwrap();
+        assert_eq!(r[0].content.as_slice(), png);
+        let (found, content) = r.content_by_mime_type("image/png");
+        assert!(found);
+        assert_eq!(content.as_slice(), png);
+
+        // All the byte values are kept
+        let buf: Vec<u8> = (0..=255).collect();
+        let r = receive_data_oneshot(&mut &buf[..], &types).unwrap();
+        assert_eq!(*r[0].content, buf);
+    }
+
+    #[test]
+    fn test_receive_data_oneshot_invalid_types() {
+        let buf = [b'G', b'O', b'O', b'D'];
+
+        let r = receive_data_oneshot(&mut &buf[..], &["text".to_string(), "".to_string()]);
+        assert_eq!(
+            r.err().unwrap().to_string(),
+            r#"Empty mime-type in ["text", ""]"#
+        );
+        let r = receive_data_oneshot(&mut &buf[..], &["text/plain\nTEXT".to_string()]);
+        assert_eq!(
+            r.err().unwrap().to_string(),
+            r#"Invalid mime-type "text/plain\nTEXT", it contains control characters"#
+        );
+        let r = receive_data_oneshot(&mut &buf[..], &["text\0".to_string()]);
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn test_limited_reader() {
+        let buf = [b'G', b'O', b'O', b'D'];
+
+        // Exact the limit
+        let r =
+            receive_data_oneshot(LimitedReader::new(&buf[..], 4), &["text".to_string()]).unwrap();
+        assert_eq!(r[0].content.as_slice(), b"GOOD");
+
+        // Exceeds the limit
+        let r = receive_data_oneshot(LimitedReader::new(&buf[..], 3), &["text".to_string()]);
+        assert!(r.is_err());
+
+        // Content section size is larger than the limit
+        #[rustfmt::skip]
+        let buf =
+            [0x20, 0x09, 0x02, 0x14, PROTOCOL_VERSION,
+            b'M', 0, 0, 0, 4, b'T', b'E', b'X', b'T',
+            b'C', 0xff, 0xff, 0xff, 0xff, b'G', b'O', b'O', b'D',
+            ];
+        let r = receive_data_bulk(LimitedReader::new(&buf[..], 20));
+        assert!(r.is_err());
     }
 }
@@ -0,0 +1,119 @@
+use anyhow::{Context, Result};
+use std::io::Write;
+
+use super::recv::{MAGIC, PROTOCOL_VERSION};
+use super::source_data::SourceDataItem;
+
+/// Write the items with the same protocol which `receive_data_bulk` reads, so the output can be
+/// copied back as it is.
+pub fn send_data_bulk(mut writer: impl Write, items: &[SourceDataItem]) -> Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[PROTOCOL_VERSION])?;
+    for item in items {
+        for mime_type in &item.mime_type {
+            write_section(&mut writer, b'M', mime_type.as_bytes())?;
+        }
+        write_section(&mut writer, b'C', &item.content)?;
+    }
+    writer.flush().context("Failed to flush the output")
+}
+
+fn write_section(writer: &mut impl Write, flag: u8, data: &[u8]) -> Result<()> {
+    let len = u32::try_from(data.len()).context("The section is too large")?;
+    writer.write_all(&[flag])?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer
+        .write_all(data)
+        .context("Failed to write the section")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::receive_data_bulk;
+    use std::io::Read;
+
+    #[test]
+    fn test_send_data_bulk() {
+        let items = vec![
+            SourceDataItem::new(
+                vec!["text/plain".to_string(), "TEXT".to_string()],
+                b"GOOD".to_vec(),
+            ),
+            SourceDataItem::new(vec!["text/html".to_string()], b"BAD".to_vec()),
+        ];
+        let mut buf = vec![];
+        send_data_bulk(&mut buf, &items).unwrap();
+
+        #[rustfmt::skip]
+        let expected =
+            [0x20, 0x09, 0x02, 0x14, PROTOCOL_VERSION,
+            b'M', 0, 0, 0, 10, b't', b'e', b'x', b't', b'/', b'p', b'l', b'a', b'i', b'n',
+            b'M', 0, 0, 0, 4, b'T', b'E', b'X', b'T',
+            b'C', 0, 0, 0, 4, b'G', b'O', b'O', b'D',
+            b'M', 0, 0, 0, 9, b't', b'e', b'x', b't', b'/', b'h', b't', b'm', b'l',
+            b'C', 0, 0, 0, 3, b'B', b'A', b'D',
+            ];
+        assert_eq!(buf, expected);
+    }
+
+    // Returns a few bytes on every read, like a pipe from ssh
+    struct ChunkedReader<'a> {
+        data: &'a [u8],
+        reads: usize,
+    }
+
+    impl Read for ChunkedReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            let n = buf.len().min(self.data.len()).min(self.reads % 7 + 1);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_round_trip_chunked() {
+        let png = include_bytes!("../../test/data/test_image.png");
+        let items = vec![
+            SourceDataItem::new(
+                vec!["text/plain".to_string(), "UTF8_STRING".to_string()],
+                b"GOOD\n".to_vec(),
+            ),
+            SourceDataItem::new(vec!["image/png".to_string()], png.to_vec()),
+            SourceDataItem::new(vec!["text/html".to_string()], vec![]),
+        ];
+        let mut buf = vec![];
+        send_data_bulk(&mut buf, &items).unwrap();
+
+        let reader = ChunkedReader {
+            data: &buf,
+            reads: 0,
+        };
+        let r = receive_data_bulk(reader).unwrap();
+        assert_eq!(r.len(), items.len());
+        for (received, sent) in r.iter().zip(&items) {
+            assert_eq!(received.mime_type, sent.mime_type);
+            assert_eq!(received.content, sent.content);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let items = vec![
+            SourceDataItem::new(vec!["image/png".to_string()], vec![0x89, 0, 0xff, b'\n']),
+            SourceDataItem::new(vec!["text/plain".to_string()], vec![]),
+            SourceDataItem::new(vec!["application/octet-stream".to_string()], vec![7; 300]),
+        ];
+        let mut buf = vec![];
+        send_data_bulk(&mut buf, &items).unwrap();
+
+        let r = receive_data_bulk(&buf[..]).unwrap();
+        assert_eq!(r.len(), items.len());
+        for (received, sent) in r.iter().zip(&items) {
+            assert_eq!(received.mime_type, sent.mime_type);
+            assert_eq!(received.content, sent.content);
+        }
+    }
+}
@@ -1,3 +1,7 @@
+use crate::clipboard::mime_type::{mime_type_base_matches, mime_type_matches};
+use anyhow::{Context, Result, bail};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
 use std::rc::Rc;
 
 pub struct SourceDataItem {
@@ -5,39 +9,130 @@ pub struct SourceDataItem {
     pub content: Rc<Vec<u8>>,
 }
 
+impl SourceDataItem {
+    /// Offer the same content for all the given mime-types. The content is shared by the
+    /// mime-types instead of being copied for each of them.
+    pub fn new(mime_types: Vec<String>, content: Vec<u8>) -> Self {
+        SourceDataItem {
+            mime_type: mime_types,
+            content: Rc::new(content),
+        }
+    }
+
+    /// Same as `new`, but reuse the content of the `existing` items if they have identical
+    /// bytes, so large content sent for multiple mime-types is only kept once in memory.
+    pub fn new_dedup(
+        mime_types: Vec<String>,
+        content: Vec<u8>,
+        existing: &[SourceDataItem],
+    ) -> Self {
+        match existing.iter().find(|item| *item.content == content) {
+            Some(item) => {
+                log::debug!(
+                    "Share the content of {:?} with {:?}",
+                    item.mime_type,
+                    mime_types
+                );
+                SourceDataItem {
+                    mime_type: mime_types,
+                    content: item.content.clone(),
+                }
+            }
+            None => Self::new(mime_types, content),
+        }
+    }
+}
+
+/// How well an offered mime-type matches the requested one, the better first.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum MatchRank {
+    Exact,
+    IgnoreCase,
+    // e.g. 'UTF8_STRING' for 'TEXT'
+    Alias,
+    // e.g. 'text/plain;charset=utf-8' for 'text/plain'
+    BaseType,
+}
+
+impl MatchRank {
+    fn of(offered: &str, requested: &str) -> Option<Self> {
+        if offered == requested {
+            Some(MatchRank::Exact)
+        } else if offered.eq_ignore_ascii_case(requested) {
+            Some(MatchRank::IgnoreCase)
+        } else if mime_type_matches(offered, requested) {
+            Some(MatchRank::Alias)
+        } else if mime_type_base_matches(offered, requested) {
+            Some(MatchRank::BaseType)
+        } else {
+            None
+        }
+    }
+}
+
 pub trait SourceData {
     /// Find the best match of the content of the mime_type.
     /// `(result, content)` is returned where the `result` will be false if no content matches
     /// the `mime_type`. In such case content will be an empty vector.
     fn content_by_mime_type(&self, mime_type: &str) -> (bool, Rc<Vec<u8>>);
+    /// All the contents matching the mime_type, the best match first, so the caller can pick
+    /// another one if it prefers. Empty if nothing matches.
+    fn contents_by_mime_type(&self, mime_type: &str) -> Vec<Rc<Vec<u8>>> {
+        match self.content_by_mime_type(mime_type) {
+            (true, content) => vec![content],
+            (false, _) => vec![],
+        }
+    }
     /// Returns all supported mime-types.
     fn mime_types(&self) -> Vec<String>;
+    /// Overwrite the content with zeros and drop it. The content which is still referenced
+    /// elsewhere is only dropped, so this is best-effort.
+    fn clear_content(&mut self);
 }
 
 impl SourceData for Vec<SourceDataItem> {
     fn content_by_mime_type(&self, mime_type: &str) -> (bool, Rc<Vec<u8>>) {
-        // TODO: Need a more flexible way to match text types.
         log::debug!("content_by_mime_type was called with '{}'", mime_type);
-        let mut filter_it = self
+        match self.contents_by_mime_type(mime_type).into_iter().next() {
+            Some(content) => (true, content),
+            _ => {
+                log::debug!("The required mime_type '{mime_type}' is not supported");
+                (false, Rc::new(vec![]))
+            }
+        }
+    }
+
+    fn contents_by_mime_type(&self, mime_type: &str) -> Vec<Rc<Vec<u8>>> {
+        let mut found: Vec<(MatchRank, &SourceDataItem)> = self
             .iter()
-            .filter(|item| {
-                item.mime_type
+            .filter_map(|item| {
+                let rank = item
+                    .mime_type
                     .iter()
-                    .filter(|mt| {
-                        log::debug!("check mime-type {mt}");
-                        mt.eq_ignore_ascii_case(mime_type)
-                    })
-                    .peekable()
-                    .peek()
-                    .is_some()
+                    .filter_map(|mt| MatchRank::of(mt, mime_type))
+                    .min()?;
+                Some((rank, item))
             })
-            .peekable();
+            .collect();
+        // Stable, the items of the same rank are kept in order
+        found.sort_by_key(|(rank, _)| *rank);
+        found
+            .into_iter()
+            .map(|(_, item)| item.content.clone())
+            .collect()
+    }
 
-        match filter_it.peek() {
-            Some(src_data) => (true, src_data.content.clone()),
-            _ => {
-                log::debug!("The required mime_type '{mime_type}' is not supported");
-                (false, Rc::new(vec![]))
+    fn clear_content(&mut self) {
+        let contents: Vec<Rc<Vec<u8>>> = self
+            .iter_mut()
+            .map(|item| std::mem::take(&mut item.content))
+            .collect();
+        // The shared content can be unwrapped after the last reference is reached
+        for content in contents {
+            if let Ok(mut buf) = Rc::try_unwrap(content) {
+                // Volatile writes so they are not optimized away before the drop
+                buf.iter_mut()
+                    .for_each(|b| unsafe { std::ptr::write_volatile(b, 0) });
             }
         }
     }
@@ -53,17 +148,90 @@ impl SourceData for Vec<SourceDataItem> {
     }
 }
 
+/// Run the command for every request, and offer its output as the content of all the mime-types,
+/// so the pasted content is always up to date.
+pub struct CommandSourceData {
+    command: String,
+    mime_types: Vec<String>,
+    // The copy process may change its working directory, e.g. when moving to background
+    working_dir: PathBuf,
+}
+
+impl CommandSourceData {
+    pub fn new(command: &str, mime_types: Vec<String>) -> Result<Self> {
+        let mime_types: Vec<String> = mime_types.into_iter().filter(|t| !t.is_empty()).collect();
+        if mime_types.is_empty() {
+            bail!("All given mime_types are empty");
+        }
+        Ok(CommandSourceData {
+            command: command.to_string(),
+            mime_types,
+            working_dir: std::env::current_dir().context("Failed to get the working directory")?,
+        })
+    }
+
+    fn run(&self) -> Result<Vec<u8>> {
+        let start_time = std::time::Instant::now();
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .current_dir(&self.working_dir)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(|| format!("Failed to run '{}'", self.command))?;
+        if !output.status.success() {
+            bail!("'{}' failed with {}", self.command, output.status);
+        }
+        log::debug!(
+            "Got {} bytes from the command in {:?}",
+            output.stdout.len(),
+            start_time.elapsed()
+        );
+        Ok(output.stdout)
+    }
+}
+
+impl SourceData for CommandSourceData {
+    fn content_by_mime_type(&self, mime_type: &str) -> (bool, Rc<Vec<u8>>) {
+        log::debug!("content_by_mime_type was called with '{}'", mime_type);
+        if !self
+            .mime_types
+            .iter()
+            .any(|mt| MatchRank::of(mt, mime_type).is_some())
+        {
+            log::debug!("The required mime_type '{mime_type}' is not supported");
+            return (false, Rc::new(vec![]));
+        }
+        match self.run() {
+            Ok(content) => (true, Rc::new(content)),
+            Err(e) => {
+                log::error!("Failed to get the content: {e:#}");
+                (true, Rc::new(vec![]))
+            }
+        }
+    }
+
+    fn mime_types(&self) -> Vec<String> {
+        self.mime_types.clone()
+    }
+
+    fn clear_content(&mut self) {
+        // Nothing is kept in memory
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::protocol::PROTOCOL_VER;
-    use crate::protocol::receive_data_bulk;
+    use crate::protocol::PROTOCOL_VERSION;
+    use crate::protocol::{TEXT_TYPES, receive_data_bulk, receive_data_oneshot};
 
     #[test]
     fn test_content_by_mime_type() {
         #[rustfmt::skip]
         let buf =
-            [0x20, 0x09, 0x02, 0x14, PROTOCOL_VER,
+            [0x20, 0x09, 0x02, 0x14, PROTOCOL_VERSION,
             b'M', 0, 0, 0, 10, b't', b'e', b'x', b't', b'/', b'p', b'l', b'a', b'i', b'n',
             b'M', 0, 0, 0, 4, b'T', b'E', b'X', b'T',
             b'C', 0, 0, 0, 4, b'G', b'O', b'O', b'D',
@@ -87,5 +255,149 @@ mod tests {
         let (result, content) = r.content_by_mime_type("no_mime");
         assert!(!result);
         assert!(content.is_empty());
+        // Aliases of 'TEXT'
+        let (result, content) = r.content_by_mime_type("UTF8_STRING");
+        assert!(result);
+        assert_eq!(content.as_slice(), b"GOOD");
+        let (result, content) = r.content_by_mime_type("text/plain;charset=utf-8");
+        assert!(result);
+        assert_eq!(content.as_slice(), b"GOOD");
+    }
+
+    #[test]
+    fn test_exact_case_match() {
+        let r = vec![
+            SourceDataItem::new(vec!["TEXT/PLAIN".to_string()], b"UPPER".to_vec()),
+            SourceDataItem::new(vec!["text/plain".to_string()], b"LOWER".to_vec()),
+        ];
+        let (result, content) = r.content_by_mime_type("text/plain");
+        assert!(result);
+        assert_eq!(content.as_slice(), b"LOWER");
+        let (_, content) = r.content_by_mime_type("TEXT/PLAIN");
+        assert_eq!(content.as_slice(), b"UPPER");
+        // No exact match, the first one wins
+        let (_, content) = r.content_by_mime_type("Text/Plain");
+        assert_eq!(content.as_slice(), b"UPPER");
+
+        let contents = r.contents_by_mime_type("text/plain");
+        assert_eq!(contents.len(), 2);
+        assert_eq!(contents[0].as_slice(), b"LOWER");
+        assert_eq!(contents[1].as_slice(), b"UPPER");
+        assert!(r.contents_by_mime_type("text/html").is_empty());
+    }
+
+    #[test]
+    fn test_parameter_match() {
+        let r = vec![SourceDataItem::new(
+            vec!["text/plain".to_string()],
+            b"BARE".to_vec(),
+        )];
+        let (result, content) = r.content_by_mime_type("text/plain;charset=utf-8");
+        assert!(result);
+        assert_eq!(content.as_slice(), b"BARE");
+
+        let r = vec![SourceDataItem::new(
+            vec!["text/plain;charset=utf-8".to_string()],
+            b"PARAM".to_vec(),
+        )];
+        let (result, content) = r.content_by_mime_type("text/plain");
+        assert!(result);
+        assert_eq!(content.as_slice(), b"PARAM");
+        assert!(
+            r.contents_by_mime_type("text/plain;charset=utf-16")
+                .is_empty()
+        );
+
+        // The exact match is preferred
+        let r = vec![
+            SourceDataItem::new(
+                vec!["text/plain;charset=utf-8".to_string()],
+                b"PARAM".to_vec(),
+            ),
+            SourceDataItem::new(vec!["text/plain".to_string()], b"BARE".to_vec()),
+        ];
+        let (_, content) = r.content_by_mime_type("text/plain");
+        assert_eq!(content.as_slice(), b"BARE");
+        let (_, content) = r.content_by_mime_type("text/plain;charset=utf-8");
+        assert_eq!(content.as_slice(), b"PARAM");
+
+        // Both forms are offered for the default text types
+        assert!(TEXT_TYPES.contains(&"text/plain"));
+        assert!(TEXT_TYPES.contains(&"text/plain;charset=utf-8"));
+    }
+
+    #[test]
+    fn test_command_source_data() {
+        let r = CommandSourceData::new("printf GOOD", vec!["TEXT".to_string()]).unwrap();
+        assert_eq!(r.mime_types(), vec!["TEXT"]);
+        let (result, content) = r.content_by_mime_type("UTF8_STRING");
+        assert!(result);
+        assert_eq!(content.as_slice(), b"GOOD");
+        let (result, _) = r.content_by_mime_type("text/html");
+        assert!(!result);
+
+        // The command runs for every request
+        let r = CommandSourceData::new("echo $$", vec!["TEXT".to_string()]).unwrap();
+        let (_, first) = r.content_by_mime_type("TEXT");
+        let (_, second) = r.content_by_mime_type("TEXT");
+        assert!(!first.is_empty());
+        assert_ne!(first, second);
+
+        // Failed commands give empty content
+        let r = CommandSourceData::new("printf BAD; exit 1", vec!["TEXT".to_string()]).unwrap();
+        let (result, content) = r.content_by_mime_type("TEXT");
+        assert!(result);
+        assert!(content.is_empty());
+
+        assert!(CommandSourceData::new("true", vec!["".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut r = vec![
+            SourceDataItem::new(vec!["TEXT".to_string()], b"SECRET".to_vec()),
+            SourceDataItem::new(vec!["HTML".to_string()], b"<b>SECRET</b>".to_vec()),
+        ];
+        let (_, held) = r.content_by_mime_type("HTML");
+        r.clear_content();
+
+        assert_eq!(r.mime_types(), vec!["TEXT", "HTML"]);
+        let (_, content) = r.content_by_mime_type("TEXT");
+        assert!(content.is_empty());
+        let (_, content) = r.content_by_mime_type("HTML");
+        assert!(content.is_empty());
+        // Still referenced, cannot be overwritten
+        assert_eq!(held.as_slice(), b"<b>SECRET</b>");
+    }
+
+    #[test]
+    fn test_shared_content() {
+        // Identical content sections share the same buffer
+        #[rustfmt::skip]
+        let buf =
+            [0x20, 0x09, 0x02, 0x14, PROTOCOL_VERSION,
+            b'M', 0, 0, 0, 4, b'T', b'E', b'X', b'T',
+            b'C', 0, 0, 0, 4, b'G', b'O', b'O', b'D',
+            b'M', 0, 0, 0, 4, b'H', b'T', b'M', b'L',
+            b'C', 0, 0, 0, 3, b'B', b'A', b'D',
+            b'M', 0, 0, 0, 6, b'S', b'T', b'R', b'I', b'N', b'G',
+            b'C', 0, 0, 0, 4, b'G', b'O', b'O', b'D',
+            ];
+        let r = receive_data_bulk(&mut &buf[..]).unwrap();
+        let (_, text) = r.content_by_mime_type("TEXT");
+        let (_, html) = r.content_by_mime_type("HTML");
+        let (_, string) = r.content_by_mime_type("STRING");
+        assert!(Rc::ptr_eq(&text, &string));
+        assert!(!Rc::ptr_eq(&text, &html));
+
+        // All the default text types share the same buffer
+        let mime_types: Vec<String> = TEXT_TYPES.iter().map(|s| s.to_string()).collect();
+        let r = receive_data_oneshot(&mut &b"GOOD"[..], &mime_types).unwrap();
+        let (_, first) = r.content_by_mime_type(TEXT_TYPES[0]);
+        for t in TEXT_TYPES {
+            let (result, content) = r.content_by_mime_type(t);
+            assert!(result);
+            assert!(Rc::ptr_eq(&first, &content));
+        }
     }
 }
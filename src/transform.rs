@@ -0,0 +1,876 @@
+//! Writers which transform the pasted content before it reaches the output.
+
+use crate::SharedBuffer;
+use clap::ValueEnum;
+use std::cell::Cell;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, channel};
+use std::thread::JoinHandle;
+
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+pub enum EscapeMode {
+    /// Output as it is
+    None,
+    /// Quote the content with bash's `$'...'`
+    Shell,
+    /// Escape the content like a C string literal
+    C,
+}
+
+/// Escape the non-printable bytes written to it. Bytes above 0x7f are kept as they are, so the
+/// UTF-8 text stays readable.
+pub struct EscapeWriter {
+    inner: Box<dyn Write>,
+    mode: EscapeMode,
+    // For the shell mode, if the opening quote has been written
+    quoted: bool,
+}
+
+impl EscapeWriter {
+    pub fn new(inner: Box<dyn Write>, mode: EscapeMode) -> Self {
+        EscapeWriter {
+            inner,
+            mode,
+            quoted: false,
+        }
+    }
+
+    fn escape_byte(&self, b: u8, out: &mut Vec<u8>) {
+        match b {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'"' if self.mode == EscapeMode::C => out.extend_from_slice(b"\\\""),
+            b'\'' if self.mode == EscapeMode::Shell => out.extend_from_slice(b"\\'"),
+            0..=0x1f | 0x7f => out.extend_from_slice(format!("\\x{:02x}", b).as_bytes()),
+            _ => out.push(b),
+        }
+    }
+}
+
+impl Write for EscapeWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.mode == EscapeMode::None {
+            return self.inner.write(buf);
+        }
+        let mut out = Vec::with_capacity(buf.len() + 2);
+        if self.mode == EscapeMode::Shell && !self.quoted {
+            out.extend_from_slice(b"$'");
+            self.quoted = true;
+        }
+        for b in buf {
+            self.escape_byte(*b, &mut out);
+        }
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    /// For the shell mode, the closing quote is written when flushing. Writing after flushing
+    /// starts a new quoted string, which will be concatenated by the shell.
+    fn flush(&mut self) -> Result<()> {
+        if self.mode == EscapeMode::Shell {
+            if !self.quoted {
+                self.inner.write_all(b"$'")?;
+            }
+            self.inner.write_all(b"'")?;
+            self.quoted = false;
+        }
+        self.inner.flush()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+pub enum Encoding {
+    /// Output as it is
+    Utf8,
+    /// UTF-16 little-endian with BOM
+    Utf16le,
+    /// UTF-16 big-endian with BOM
+    Utf16be,
+}
+
+/// Transcode the UTF-8 text written to it. Invalid UTF-8 is replaced with U+FFFD.
+pub struct EncodingWriter {
+    inner: Box<dyn Write>,
+    encoding: Encoding,
+    // Incomplete UTF-8 sequence from the last write
+    pending: Vec<u8>,
+    bom_written: bool,
+}
+
+impl EncodingWriter {
+    pub fn new(inner: Box<dyn Write>, encoding: Encoding) -> Self {
+        EncodingWriter {
+            inner,
+            encoding,
+            pending: vec![],
+            bom_written: false,
+        }
+    }
+
+    fn encode(&mut self, text: &str, out: &mut Vec<u8>) {
+        if !self.bom_written {
+            self.encode_char('\u{feff}', out);
+            self.bom_written = true;
+        }
+        text.chars().for_each(|c| self.encode_char(c, out));
+    }
+
+    fn encode_char(&self, c: char, out: &mut Vec<u8>) {
+        let mut buf = [0u16; 2];
+        for unit in c.encode_utf16(&mut buf) {
+            match self.encoding {
+                Encoding::Utf16be => out.extend_from_slice(&unit.to_be_bytes()),
+                _ => out.extend_from_slice(&unit.to_le_bytes()),
+            }
+        }
+    }
+}
+
+impl Write for EncodingWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.encoding == Encoding::Utf8 {
+            return self.inner.write(buf);
+        }
+        self.pending.extend_from_slice(buf);
+        let pending = std::mem::take(&mut self.pending);
+        let mut out = Vec::with_capacity(pending.len() * 2 + 2);
+        let mut rest = &pending[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(text) => {
+                    self.encode(text, &mut out);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    // Safe since it has been validated
+                    self.encode(std::str::from_utf8(valid).unwrap(), &mut out);
+                    match e.error_len() {
+                        Some(len) => {
+                            self.encode("\u{fffd}", &mut out);
+                            rest = &invalid[len..];
+                        }
+                        // Incomplete sequence at the end, wait for more bytes
+                        None => {
+                            rest = invalid;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.pending = rest.to_vec();
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.encoding != Encoding::Utf8 {
+            // Empty content still gets the BOM, and an incomplete sequence at the end is replaced
+            let text = if self.pending.is_empty() {
+                ""
+            } else {
+                "\u{fffd}"
+            };
+            self.pending.clear();
+            let mut out = vec![];
+            self.encode(text, &mut out);
+            self.inner.write_all(&out)?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Discard the content written to it and only count the bytes. The clones share the same count,
+/// so it can be read after the writer has been consumed.
+#[derive(Clone, Default)]
+pub struct CountingWriter {
+    count: Rc<Cell<u64>>,
+}
+
+impl CountingWriter {
+    pub fn count(&self) -> u64 {
+        self.count.get()
+    }
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.count.set(self.count.get() + buf.len() as u64);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Write the same content to all the writers, e.g. stdout and the `--tee` files. Every buffer is
+/// written completely to each writer in turn, and the error tells which writer failed.
+#[derive(Default)]
+pub struct MultiWriter {
+    writers: Vec<(String, Box<dyn Write>)>,
+}
+
+impl MultiWriter {
+    /// Add a writer, the name is used in the error messages.
+    pub fn push(&mut self, name: &str, writer: Box<dyn Write>) {
+        self.writers.push((name.to_string(), writer));
+    }
+
+    fn for_each(&mut self, mut f: impl FnMut(&mut dyn Write) -> Result<()>) -> Result<()> {
+        for (name, writer) in &mut self.writers {
+            f(writer.as_mut())
+                .map_err(|e| Error::new(e.kind(), format!("Failed to write to {name}: {e}")))?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for MultiWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.for_each(|writer| writer.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.for_each(|writer| writer.flush())
+    }
+}
+
+/// Prefix the content with its length as a little-endian u32, so a stream of contents can be told
+/// apart without relying on EOF. The content is buffered until flushing, which writes the frame.
+/// Writing after flushing starts a new frame.
+pub struct FramedWriter {
+    inner: Box<dyn Write>,
+    buffer: Vec<u8>,
+    // If a frame has been written, so flushing again without new content doesn't write an empty one
+    framed: bool,
+}
+
+impl FramedWriter {
+    pub fn new(inner: Box<dyn Write>) -> Self {
+        FramedWriter {
+            inner,
+            buffer: vec![],
+            framed: false,
+        }
+    }
+}
+
+impl Write for FramedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.buffer.len() + buf.len() > u32::MAX as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "The content is too large for a frame",
+            ));
+        }
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.buffer.is_empty() || !self.framed {
+            // Checked when writing
+            let len = self.buffer.len() as u32;
+            self.inner.write_all(&len.to_le_bytes())?;
+            self.inner.write_all(&std::mem::take(&mut self.buffer))?;
+            self.framed = true;
+        }
+        self.inner.flush()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum AnsiState {
+    Text,
+    // After an ESC
+    Escape,
+    // Inside a CSI sequence, after `ESC [`
+    Csi,
+}
+
+/// Remove the ANSI CSI escape sequences, e.g. the SGR colors `ESC [ 31 m`, from the text written
+/// to it. The state is kept between the writes, so a sequence can be split across them. An ESC
+/// which doesn't start a CSI sequence is kept as it is.
+pub struct StripAnsiWriter {
+    inner: Box<dyn Write>,
+    state: AnsiState,
+}
+
+impl StripAnsiWriter {
+    pub fn new(inner: Box<dyn Write>) -> Self {
+        StripAnsiWriter {
+            inner,
+            state: AnsiState::Text,
+        }
+    }
+
+    fn strip_byte(&mut self, b: u8, out: &mut Vec<u8>) {
+        match self.state {
+            AnsiState::Text if b == 0x1b => self.state = AnsiState::Escape,
+            AnsiState::Text => out.push(b),
+            AnsiState::Escape if b == b'[' => self.state = AnsiState::Csi,
+            AnsiState::Escape => {
+                self.state = AnsiState::Text;
+                out.push(0x1b);
+                self.strip_byte(b, out);
+            }
+            // The parameter and the intermediate bytes
+            AnsiState::Csi if (0x20..=0x3f).contains(&b) => {}
+            // The final byte
+            AnsiState::Csi if (0x40..=0x7e).contains(&b) => self.state = AnsiState::Text,
+            // A malformed sequence ends at the unexpected byte, which is kept
+            AnsiState::Csi => {
+                self.state = AnsiState::Text;
+                self.strip_byte(b, out);
+            }
+        }
+    }
+}
+
+impl Write for StripAnsiWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut out = Vec::with_capacity(buf.len());
+        for b in buf {
+            self.strip_byte(*b, &mut out);
+        }
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    /// A trailing ESC is written as it is, and an unfinished CSI sequence is dropped.
+    fn flush(&mut self) -> Result<()> {
+        if self.state == AnsiState::Escape {
+            self.inner.write_all(&[0x1b])?;
+        }
+        self.state = AnsiState::Text;
+        self.inner.flush()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum TerminalState {
+    Text,
+    // After an ESC, or its intermediate bytes
+    Escape,
+    // Inside a CSI sequence
+    Csi,
+    // Inside the string of an OSC, DCS, SOS, PM or APC sequence
+    String,
+    // After an ESC inside the string, which is `ESC \` to end it
+    StringEscape,
+    // After 0xc2, which starts the UTF-8 encoded C1 controls
+    C2,
+}
+
+/// Remove the bytes which could control the terminal from the text written to it: the escape
+/// sequences like CSI and OSC, the C0 control characters except the tab and the line breaks, and
+/// the UTF-8 encoded C1 control characters. So a crafted clipboard content cannot set the title,
+/// write to the clipboard with OSC 52, or end a bracketed paste with `ESC [ 201 ~`.
+pub struct SafeTerminalWriter {
+    inner: Box<dyn Write>,
+    state: TerminalState,
+}
+
+impl SafeTerminalWriter {
+    pub fn new(inner: Box<dyn Write>) -> Self {
+        SafeTerminalWriter {
+            inner,
+            state: TerminalState::Text,
+        }
+    }
+
+    fn filter_byte(&mut self, b: u8, out: &mut Vec<u8>) {
+        match self.state {
+            TerminalState::Text => match b {
+                0x1b => self.state = TerminalState::Escape,
+                b'\t' | b'\n' | b'\r' => out.push(b),
+                0..=0x1f | 0x7f => {}
+                0xc2 => self.state = TerminalState::C2,
+                _ => out.push(b),
+            },
+            TerminalState::Escape => match b {
+                b'[' => self.state = TerminalState::Csi,
+                b']' | b'P' | b'X' | b'^' | b'_' => self.state = TerminalState::String,
+                0x20..=0x2f => {}
+                0x30..=0x7e => self.state = TerminalState::Text,
+                // Not an escape sequence, the byte is kept
+                _ => {
+                    self.state = TerminalState::Text;
+                    self.filter_byte(b, out);
+                }
+            },
+            TerminalState::Csi => match b {
+                0x20..=0x3f => {}
+                0x40..=0x7e => self.state = TerminalState::Text,
+                _ => {
+                    self.state = TerminalState::Text;
+                    self.filter_byte(b, out);
+                }
+            },
+            TerminalState::String => match b {
+                // BEL ends OSC as well
+                0x07 => self.state = TerminalState::Text,
+                0x1b => self.state = TerminalState::StringEscape,
+                _ => {}
+            },
+            TerminalState::StringEscape if b == b'\\' => self.state = TerminalState::Text,
+            // Another ESC cancels the string and starts a new sequence
+            TerminalState::StringEscape => {
+                self.state = TerminalState::Escape;
+                self.filter_byte(b, out);
+            }
+            // The C1 forms of CSI, and of DCS, SOS, OSC, PM and APC
+            TerminalState::C2 if b == 0x9b => self.state = TerminalState::Csi,
+            TerminalState::C2 if [0x90, 0x98, 0x9d, 0x9e, 0x9f].contains(&b) => {
+                self.state = TerminalState::String
+            }
+            TerminalState::C2 if (0x80..=0x9f).contains(&b) => self.state = TerminalState::Text,
+            TerminalState::C2 => {
+                self.state = TerminalState::Text;
+                out.push(0xc2);
+                self.filter_byte(b, out);
+            }
+        }
+    }
+}
+
+impl Write for SafeTerminalWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut out = Vec::with_capacity(buf.len());
+        for b in buf {
+            self.filter_byte(*b, &mut out);
+        }
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    /// An unfinished sequence is dropped.
+    fn flush(&mut self) -> Result<()> {
+        if self.state == TerminalState::C2 {
+            self.inner.write_all(&[0xc2])?;
+        }
+        self.state = TerminalState::Text;
+        self.inner.flush()
+    }
+}
+
+/// Pipe the content written to it through a shell command, and write the command's output to
+/// the inner writer. The command is started on the first write, and flushing closes its stdin and
+/// waits for it to exit, so nothing should be written after flushing.
+pub struct FilterWriter {
+    inner: Box<dyn Write>,
+    command: String,
+    process: Option<FilterProcess>,
+}
+
+struct FilterProcess {
+    child: Child,
+    // None once the command stops reading
+    stdin: Option<ChildStdin>,
+    output: Receiver<Vec<u8>>,
+    reader: JoinHandle<Result<()>>,
+}
+
+impl FilterWriter {
+    pub fn new(inner: Box<dyn Write>, command: &str) -> Self {
+        FilterWriter {
+            inner,
+            command: command.to_string(),
+            process: None,
+        }
+    }
+
+    fn process(&mut self) -> Result<&mut FilterProcess> {
+        if self.process.is_none() {
+            self.process = Some(spawn_filter(&self.command)?);
+        }
+        Ok(self.process.as_mut().unwrap())
+    }
+}
+
+fn spawn_filter(command: &str) -> Result<FilterProcess> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdin = child.stdin.take();
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    // The output is read in another thread, otherwise both sides could be blocked on the full
+    // pipes
+    let (sender, output) = channel();
+    let reader = std::thread::spawn(move || -> Result<()> {
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = stdout.read(&mut buf)?;
+            if n == 0 || sender.send(buf[..n].to_vec()).is_err() {
+                return Ok(());
+            }
+        }
+    });
+    Ok(FilterProcess {
+        child,
+        stdin,
+        output,
+        reader,
+    })
+}
+
+impl Write for FilterWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let process = self.process()?;
+        if let Some(stdin) = process.stdin.as_mut() {
+            match stdin.write_all(buf) {
+                Ok(()) => (),
+                // The command doesn't need the rest, e.g. `head`
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => process.stdin = None,
+                Err(e) => return Err(e),
+            }
+        }
+        let output: Vec<Vec<u8>> = process.output.try_iter().collect();
+        for chunk in output {
+            self.inner.write_all(&chunk)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut process = match self.process.take() {
+            Some(process) => process,
+            // Empty content still runs the command
+            None => spawn_filter(&self.command)?,
+        };
+        drop(process.stdin.take());
+        for chunk in process.output.iter() {
+            self.inner.write_all(&chunk)?;
+        }
+        process
+            .reader
+            .join()
+            .map_err(|_| Error::other("Failed to read the output of the filter command"))??;
+        let status = process.child.wait()?;
+        if !status.success() {
+            return Err(Error::other(format!(
+                "The filter command '{}' failed with {status}",
+                self.command
+            )));
+        }
+        self.inner.flush()
+    }
+}
+
+/// Run the content through the shell command, and return its output.
+pub fn filter_content(command: &str, content: &[u8]) -> Result<Vec<u8>> {
+    let output = SharedBuffer::default();
+    let mut writer = FilterWriter::new(Box::new(output.clone()), command);
+    writer.write_all(content)?;
+    writer.flush()?;
+    drop(writer);
+    Ok(output.take())
+}
+
+/// Transcode the text content from the charset to UTF-8. The charset is given by its label,
+/// e.g. `latin1` or `shift_jis`, see the WHATWG Encoding Standard.
+pub fn decode_charset(charset: &str, content: &[u8]) -> Result<Vec<u8>> {
+    let Some(encoding) = encoding_rs::Encoding::for_label(charset.as_bytes()) else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Unknown charset '{charset}'"),
+        ));
+    };
+    let (text, had_errors) = encoding.decode_without_bom_handling(content);
+    if had_errors {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("The content is not valid {}", encoding.name()),
+        ));
+    }
+    Ok(text.into_owned().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn escape(mode: EscapeMode, chunks: &[&[u8]]) -> Vec<u8> {
+        let output = SharedBuffer::default();
+        let mut writer = EscapeWriter::new(Box::new(output.clone()), mode);
+        for chunk in chunks {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.flush().unwrap();
+        output.take()
+    }
+
+    fn encode(encoding: Encoding, chunks: &[&[u8]]) -> Vec<u8> {
+        let output = SharedBuffer::default();
+        let mut writer = EncodingWriter::new(Box::new(output.clone()), encoding);
+        for chunk in chunks {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.flush().unwrap();
+        output.take()
+    }
+
+    fn strip_ansi(chunks: &[&[u8]]) -> Vec<u8> {
+        let output = SharedBuffer::default();
+        let mut writer = StripAnsiWriter::new(Box::new(output.clone()));
+        for chunk in chunks {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.flush().unwrap();
+        output.take()
+    }
+
+    fn safe_terminal(chunks: &[&[u8]]) -> Vec<u8> {
+        let output = SharedBuffer::default();
+        let mut writer = SafeTerminalWriter::new(Box::new(output.clone()));
+        for chunk in chunks {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.flush().unwrap();
+        output.take()
+    }
+
+    #[test]
+    fn test_encoding() {
+        let r = encode(Encoding::Utf8, &["a中".as_bytes()]);
+        assert_eq!(r, "a中".as_bytes());
+
+        let r = encode(Encoding::Utf16le, &["a中".as_bytes()]);
+        assert_eq!(r, [0xff, 0xfe, b'a', 0, 0x2d, 0x4e]);
+        let r = encode(Encoding::Utf16be, &["a中".as_bytes()]);
+        assert_eq!(r, [0xfe, 0xff, 0, b'a', 0x4e, 0x2d]);
+
+        // Surrogate pairs
+        let r = encode(Encoding::Utf16le, &["😀".as_bytes()]);
+        assert_eq!(r, [0xff, 0xfe, 0x3d, 0xd8, 0x00, 0xde]);
+
+        // Empty content
+        let r = encode(Encoding::Utf16le, &[]);
+        assert_eq!(r, [0xff, 0xfe]);
+    }
+
+    #[test]
+    fn test_encoding_split_and_invalid() {
+        // A sequence split across writes
+        let bytes = "中".as_bytes();
+        let r = encode(Encoding::Utf16le, &[&bytes[..1], &bytes[1..2], &bytes[2..]]);
+        assert_eq!(r, [0xff, 0xfe, 0x2d, 0x4e]);
+
+        // Invalid bytes in the middle and an incomplete sequence at the end
+        let r = encode(Encoding::Utf16le, &[b"a\xffb", &bytes[..2]]);
+        assert_eq!(r, [0xff, 0xfe, b'a', 0, 0xfd, 0xff, b'b', 0, 0xfd, 0xff]);
+    }
+
+    #[test]
+    fn test_decode_charset() {
+        let r = decode_charset("latin1", b"caf\xe9").unwrap();
+        assert_eq!(r, "café".as_bytes());
+        let r = decode_charset("ISO-8859-1", b"\xa9 2024").unwrap();
+        assert_eq!(r, "© 2024".as_bytes());
+
+        let r = decode_charset("shift_jis", b"\x93\xfa\x96\x7b\x8c\xea").unwrap();
+        assert_eq!(r, "日本語".as_bytes());
+        let r = decode_charset("sjis", b"abc").unwrap();
+        assert_eq!(r, b"abc");
+        // Truncated double-byte character
+        assert!(decode_charset("shift_jis", b"\x93").is_err());
+
+        assert!(decode_charset("no-such-charset", b"abc").is_err());
+    }
+
+    #[test]
+    fn test_counting_writer() {
+        let counter = CountingWriter::default();
+        let mut writer: Box<dyn Write> = Box::new(counter.clone());
+        writer.write_all(b"abc").unwrap();
+        writer.write_all("中".as_bytes()).unwrap();
+        drop(writer);
+        assert_eq!(counter.count(), 6);
+    }
+
+    /// Split the framed output back to the contents.
+    fn decode_frames(mut output: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = vec![];
+        while !output.is_empty() {
+            let (len, rest) = output.split_at(4);
+            let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+            frames.push(rest[..len].to_vec());
+            output = &rest[len..];
+        }
+        frames
+    }
+
+    #[test]
+    fn test_framed_writer() {
+        let output = SharedBuffer::default();
+        let mut writer = FramedWriter::new(Box::new(output.clone()));
+        writer.write_all(b"GO").unwrap();
+        writer.write_all(b"OD").unwrap();
+        writer.flush().unwrap();
+        // No empty frame without new content
+        writer.flush().unwrap();
+        writer.write_all(&[0u8, 1, 2]).unwrap();
+        writer.flush().unwrap();
+        let framed = output.take();
+        assert_eq!(&framed[..4], &[4u8, 0, 0, 0]);
+        assert_eq!(
+            decode_frames(&framed),
+            vec![b"GOOD".to_vec(), vec![0u8, 1, 2]]
+        );
+
+        // Empty content still gets a frame
+        let mut writer = FramedWriter::new(Box::new(output.clone()));
+        writer.flush().unwrap();
+        assert_eq!(output.take(), vec![0u8; 4]);
+    }
+
+    #[test]
+    fn test_multi_writer() {
+        let outputs = [SharedBuffer::default(), SharedBuffer::default()];
+        let mut writer = MultiWriter::default();
+        for (i, output) in outputs.iter().enumerate() {
+            writer.push(&format!("output {i}"), Box::new(output.clone()));
+        }
+        writer.write_all(b"abc").unwrap();
+        writer.write_all(&[0xff; 10000]).unwrap();
+        writer.flush().unwrap();
+        let expected = [b"abc".as_slice(), &[0xff; 10000]].concat();
+        for output in &outputs {
+            assert_eq!(output.take(), expected);
+        }
+
+        // The failed writer is reported
+        let mut writer = MultiWriter::default();
+        writer.push("stdout", Box::new(SharedBuffer::default()));
+        writer.push("/full", Box::new(std::io::Cursor::new([0u8; 2])));
+        let e = writer.write_all(b"abc").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WriteZero);
+        assert!(
+            e.to_string().starts_with("Failed to write to /full: "),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn test_filter_content() {
+        let r = filter_content("tr a-z A-Z", b"abc\n").unwrap();
+        assert_eq!(r, b"ABC\n");
+
+        // Larger than the pipe buffers
+        let content = vec![b'a'; 1024 * 1024];
+        let r = filter_content("cat", &content).unwrap();
+        assert_eq!(r, content);
+
+        // The command doesn't read all the input
+        let r = filter_content("head -c 3", &content).unwrap();
+        assert_eq!(r, b"aaa");
+
+        // Empty content
+        let r = filter_content("echo empty", b"").unwrap();
+        assert_eq!(r, b"empty\n");
+
+        assert!(filter_content("cat; exit 3", b"abc").is_err());
+    }
+
+    #[test]
+    fn test_filter_writer_streaming() {
+        let output = SharedBuffer::default();
+        let mut writer = FilterWriter::new(Box::new(output.clone()), "cat");
+        for _ in 0..100 {
+            writer.write_all(&[b'x'; 4096]).unwrap();
+        }
+        writer.flush().unwrap();
+        assert_eq!(output.take().len(), 100 * 4096);
+    }
+
+    #[test]
+    fn test_escape_none() {
+        let r = escape(EscapeMode::None, &[b"a\x1b[0m\n"]);
+        assert_eq!(r, b"a\x1b[0m\n");
+    }
+
+    #[test]
+    fn test_escape_c() {
+        let r = escape(EscapeMode::C, &[b"a\"b'\\\x1b[0m\n\t\x7f", "中".as_bytes()]);
+        assert_eq!(r, "a\\\"b'\\\\\\x1b[0m\\n\\t\\x7f中".as_bytes());
+    }
+
+    #[test]
+    fn test_escape_shell() {
+        let r = escape(EscapeMode::Shell, &[b"it's", b"\x1b[0m\n"]);
+        assert_eq!(r, b"$'it\\'s\\x1b[0m\\n'");
+
+        // Empty content
+        let r = escape(EscapeMode::Shell, &[]);
+        assert_eq!(r, b"$''");
+    }
+
+    #[test]
+    fn test_strip_ansi_writer() {
+        // SGR colors and reset
+        let r = strip_ansi(&[b"\x1b[1;31mERROR\x1b[0m: \x1b[mdone"]);
+        assert_eq!(r, b"ERROR: done");
+        // 256 colors and true colors
+        let r = strip_ansi(&[b"\x1b[38;5;208ma\x1b[48;2;10;20;30mb\x1b[39;49m"]);
+        assert_eq!(r, b"ab");
+        // Cursor movement, erasing and private modes
+        let r = strip_ansi(&[b"\x1b[2K\x1b[1Gline\x1b[?25l\x1b[?25h\n"]);
+        assert_eq!(r, b"line\n");
+        // Split across the writes
+        let r = strip_ansi(&[b"red \x1b", b"[3", b"1mtext\x1b[", "0m中".as_bytes()]);
+        assert_eq!(r, "red text中".as_bytes());
+        // ESC not followed by `[` is kept
+        let r = strip_ansi(&[b"a\x1b(Bb\x1b\x1b[1mc"]);
+        assert_eq!(r, b"a\x1b(Bb\x1bc");
+        // A malformed sequence ends at the unexpected byte
+        let r = strip_ansi(&[b"\x1b[31\nnext"]);
+        assert_eq!(r, b"\nnext");
+        // A trailing ESC is kept, and an unfinished sequence is dropped
+        assert_eq!(strip_ansi(&[b"end\x1b"]), b"end\x1b");
+        assert_eq!(strip_ansi(&[b"end\x1b[1;3"]), b"end");
+        // Text without escapes passes through
+        assert_eq!(strip_ansi(&[b"plain", b" text"]), b"plain text");
+        assert_eq!(strip_ansi(&[]), b"");
+    }
+
+    #[test]
+    fn test_safe_terminal_writer() {
+        // CSI, including the end of a bracketed paste
+        let r = safe_terminal(&[b"\x1b[31mred\x1b[0m ls\x1b[201~; rm -rf ~\n"]);
+        assert_eq!(r, b"red ls; rm -rf ~\n");
+        // OSC ended by BEL or ST, e.g. setting the title or writing to the clipboard
+        let r = safe_terminal(&[b"a\x1b]0;title\x07b\x1b]52;c;ZXZpbA==\x1b\\c"]);
+        assert_eq!(r, b"abc");
+        // DCS, and the other escape sequences
+        let r = safe_terminal(&[b"\x1bP+q544e\x1b\\x\x1b(By\x1bcz"]);
+        assert_eq!(r, b"xyz");
+        // An ESC inside the string starts a new sequence
+        let r = safe_terminal(&[b"\x1b]0;title\x1b[1mtext"]);
+        assert_eq!(r, b"text");
+        // The control characters except the tab and the line breaks
+        let r = safe_terminal(&[b"a\x07b\x08c\x00d\x7f\te\r\n"]);
+        assert_eq!(r, b"abcd\te\r\n");
+        // The UTF-8 encoded C1 controls, like CSI U+009B, but not the other characters
+        let r = safe_terminal(&[b"\xc2\x9b31mred\xc2\x85", "\u{a9}中".as_bytes()]);
+        assert_eq!(r, "red\u{a9}中".as_bytes());
+        let r = safe_terminal(&[b"a\xc2\x9d0;title\x07b"]);
+        assert_eq!(r, b"ab");
+        // Split across the writes
+        let r = safe_terminal(&[b"a\x1b", b"]0;ti", b"tle\x1b", b"\\b\xc2", b"\x85c"]);
+        assert_eq!(r, b"abc");
+        // An unfinished sequence is dropped
+        assert_eq!(safe_terminal(&[b"end\x1b]0;never ended"]), b"end");
+        assert_eq!(safe_terminal(&[b"end\xc2"]), b"end\xc2");
+    }
+}
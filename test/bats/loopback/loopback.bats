@@ -66,3 +66,173 @@ teardown() {
     run -0 "$RICHCLIP" paste -t Faker
     [ "$output" = "TestDaTA" ]
 }
+
+@test "copy with '--no-tty'" {
+    # stdin is not a terminal
+    echo "TestDaTA" | $RICHCLIP copy --one-shot --no-tty
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "TestDaTA" ]
+
+    # stdin is a terminal
+    if [ -t 0 ]; then
+        run -1 --separate-stderr "$RICHCLIP" copy --one-shot --no-tty
+    fi
+}
+
+@test "copy with '--max-input'" {
+    printf "TestDaTA" | $RICHCLIP copy --one-shot --max-input 8
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "TestDaTA" ]
+
+    run -1 --separate-stderr "$RICHCLIP" copy --one-shot --max-input 7 <<< "TestDaTA"
+}
+
+@test "copy with '--append'" {
+    printf "TestDaTA" | $RICHCLIP copy --one-shot
+    echo " more" | $RICHCLIP copy --append
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "TestDaTA more" ]
+
+    # Only for text
+    run -1 --separate-stderr bash -c "printf 'a' | $RICHCLIP copy --append --type image/png"
+}
+
+@test "paste with '--encoding'" {
+    printf "a" | $RICHCLIP copy --one-shot
+
+    run -0 bash -c "$RICHCLIP paste --encoding utf16le | od -An -tx1"
+    [ "$(echo $output)" = "ff fe 61 00" ]
+
+    run -0 bash -c "$RICHCLIP paste --encoding utf16be | od -An -tx1"
+    [ "$(echo $output)" = "fe ff 00 61" ]
+
+    # Not for the non-text types
+    printf "a" | $RICHCLIP copy --type "image/png"
+    run -0 bash -c "$RICHCLIP paste -t image/png --encoding utf16le | od -An -tx1"
+    [ "$(echo $output)" = "61" ]
+}
+
+@test "paste with '--escape'" {
+    printf "it's\tDaTA" | $RICHCLIP copy --one-shot
+
+    run -0 "$RICHCLIP" paste --escape c
+    [ "$output" = 'it'"'"'s\tDaTA' ]
+
+    run -0 "$RICHCLIP" paste --escape shell
+    [ "$output" = '$'"'"'it\'"'"'s\tDaTA'"'" ]
+}
+
+@test "paste with '--index'" {
+    echo "TestDaTA" | $RICHCLIP copy --type TypE --type Faker
+
+    run -0 "$RICHCLIP" paste --index 2
+    [ "$output" = "TestDaTA" ]
+
+    run -1 --separate-stderr "$RICHCLIP" paste --index 3
+}
+
+@test "list-types with '--numbered'" {
+    echo "TestDaTA" | $RICHCLIP copy --type TypE --type Faker
+
+    run -0 "$RICHCLIP" paste -l --numbered
+    [ "${lines[0]}" = "0	TARGETS" ]
+    [ "${lines[1]}" = "1	TypE" ]
+    [ "${lines[2]}" = "2	Faker" ]
+}
+
+@test "one-shot mode: multiline content is copied verbatim" {
+    printf 'first line\n\n  indented\r\nlast line\n\n' > "$BATS_TEST_TMPDIR/multiline"
+
+    $RICHCLIP copy --one-shot --type text/plain < "$BATS_TEST_TMPDIR/multiline"
+    "$RICHCLIP" paste -t text/plain > "$BATS_TEST_TMPDIR/pasted"
+    cmp "$BATS_TEST_TMPDIR/multiline" "$BATS_TEST_TMPDIR/pasted"
+
+    # Here-doc
+    $RICHCLIP copy --type text/plain <<EOF_DOC
+first line
+second line
+EOF_DOC
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "first line
+second line" ]
+    run -0 bash -c "$RICHCLIP paste | wc -l"
+    [ "$output" = "2" ]
+}
+
+@test "paste with '--quiet'" {
+    printf "TestDaTA" | $RICHCLIP copy --one-shot
+    run -0 --separate-stderr "$RICHCLIP" paste --log-level debug --quiet
+    [ "$output" = "TestDaTA" ]
+    [ "$stderr" = "" ]
+
+    # The log file is still written
+    run -0 --separate-stderr "$RICHCLIP" paste --quiet --log-file "$BATS_TEST_TMPDIR/log"
+    [ "$stderr" = "" ]
+    [ -s "$BATS_TEST_TMPDIR/log" ]
+}
+
+@test "copy with '--manifest'" {
+    printf "<b>GOOD</b>" > "$BATS_TEST_TMPDIR/x.html"
+    cat > "$BATS_TEST_TMPDIR/manifest.json" <<'JSON'
+[
+  { "mime": "text/html", "file": "x.html" },
+  { "mime": "text/plain", "data_base64": "R09PRA==" }
+]
+JSON
+    $RICHCLIP copy --manifest "$BATS_TEST_TMPDIR/manifest.json"
+    run -0 "$RICHCLIP" paste -l
+    [ "${lines[1]}" = "text/html" ]
+    [ "${lines[2]}" = "text/plain" ]
+    run -0 "$RICHCLIP" paste -t text/html
+    [ "$output" = "<b>GOOD</b>" ]
+    run -0 "$RICHCLIP" paste -t text/plain
+    [ "$output" = "GOOD" ]
+
+    echo '[{ "mime": "text/plain" }]' > "$BATS_TEST_TMPDIR/bad.json"
+    run -1 --separate-stderr "$RICHCLIP" copy --manifest "$BATS_TEST_TMPDIR/bad.json"
+}
+
+@test "copy with '--charset'" {
+    printf 'caf\xe9' | $RICHCLIP copy --charset latin1
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "café" ]
+    run -0 "$RICHCLIP" paste -t "text/plain;charset=utf-8"
+    [ "$output" = "café" ]
+
+    printf '\x93\xfa\x96\x7b' | $RICHCLIP copy --charset shift_jis
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "日本" ]
+
+    run -1 --separate-stderr bash -c "printf '\x93' | $RICHCLIP copy --charset shift_jis"
+}
+
+@test "copy with '--pid-file'" {
+    echo "TestDaTA" | $RICHCLIP copy --one-shot --pid-file "$BATS_TEST_TMPDIR/pid"
+    pid=$(cat "$BATS_TEST_TMPDIR/pid")
+    # The background process, not the one which returned
+    kill -0 "$pid"
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "TestDaTA" ]
+
+    kill "$pid"
+    for _ in $(seq 50); do
+        kill -0 "$pid" 2>/dev/null || break
+        sleep 0.1
+    done
+    run ! kill -0 "$pid"
+}
+
+@test "paste '--all-types' and copy through a pipe like ssh" {
+    "$RICHCLIP" copy --type image/png --type image/x-png < "$ROOT_DIR/test/data/test_image.png"
+    # 'dd bs=1' splits the stream into single bytes, like a slow remote connection
+    "$RICHCLIP" paste --all-types | dd bs=1 status=none | "$RICHCLIP" copy
+    "$RICHCLIP" paste --type image/x-png > "$BATS_TEST_TMPDIR/pasted.png"
+    cmp "$ROOT_DIR/test/data/test_image.png" "$BATS_TEST_TMPDIR/pasted.png"
+
+    # The stream cut in the middle of a content
+    "$RICHCLIP" paste --all-types | head -c 40 > "$BATS_TEST_TMPDIR/cut"
+    run -1 "$RICHCLIP" copy < "$BATS_TEST_TMPDIR/cut"
+    # The clipboard is kept
+    "$RICHCLIP" paste --type image/png > "$BATS_TEST_TMPDIR/kept.png"
+    cmp "$ROOT_DIR/test/data/test_image.png" "$BATS_TEST_TMPDIR/kept.png"
+}
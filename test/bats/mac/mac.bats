@@ -34,3 +34,71 @@ setup_file() {
     [ "${lines[0]}" = "public.utf8-plain-text" ]
     [ "${lines[1]}" = "public.html" ]
 }
+
+@test "MacOS list all raw types" {
+    "$RICHCLIP" copy 3>&- < "$TEST_DATA_DIR/test_data_0"
+
+    run -0 "$RICHCLIP" paste -l --all
+    [[ "$output" == *"public.utf8-plain-text"* ]]
+    [[ "$output" == *"public.html"* ]]
+
+    # Raw types can be requested directly
+    run -0 "$RICHCLIP" paste -t "public.html"
+    [ "$output" = "BAD" ]
+}
+
+@test "MacOS copy and paste image types" {
+    printf 'GIF89a' | "$RICHCLIP" copy --type image/gif 3>&-
+
+    run -0 "$RICHCLIP" paste -l
+    [ "$output" = "com.compuserve.gif" ]
+
+    run -0 "$RICHCLIP" paste --type image/gif
+    [ "$output" = "GIF89a" ]
+    run -0 "$RICHCLIP" paste --measure --type image/gif
+    [ "$output" = "image/gif 6" ]
+}
+
+@test "MacOS paste large data" {
+    head -c 5000000 /dev/urandom > "$BATS_TEST_TMPDIR/large"
+    "$RICHCLIP" copy --type image/png 3>&- < "$BATS_TEST_TMPDIR/large"
+
+    "$RICHCLIP" paste --type image/png > "$BATS_TEST_TMPDIR/pasted"
+    cmp "$BATS_TEST_TMPDIR/large" "$BATS_TEST_TMPDIR/pasted"
+}
+
+@test "MacOS copy with '--no-clear'" {
+    echo -n "Text" | pbcopy 3>&-
+    echo -n "<b>Html</b>" | "$RICHCLIP" copy --no-clear --type text/html 3>&-
+    run -0 "$RICHCLIP" paste --type text/html
+    [ "$output" = "<b>Html</b>" ]
+    run -0 pbpaste
+    [ "$output" = "Text" ]
+
+    # The pasteboard is cleared by default
+    echo -n "<b>Html</b>" | "$RICHCLIP" copy --type text/html 3>&-
+    run -0 "$RICHCLIP" paste -l
+    [ "$output" = "public.html" ]
+}
+
+@test "MacOS copy with an unsupported type keeps the pasteboard" {
+    echo -n "Old" | pbcopy 3>&-
+    run -1 "$RICHCLIP" copy --type text/plain --type x-unknown/type 3>&- <<< "New"
+    run -0 pbpaste
+    [ "$output" = "Old" ]
+}
+
+@test "MacOS copy and paste a PNG byte-identically" {
+    "$RICHCLIP" copy --type image/png 3>&- < "$TEST_DATA_DIR/test_image.png"
+    "$RICHCLIP" paste --type image/png > "$BATS_TEST_TMPDIR/pasted.png"
+    cmp "$TEST_DATA_DIR/test_image.png" "$BATS_TEST_TMPDIR/pasted.png"
+
+    # Piped from a screenshot tool without the type
+    "$RICHCLIP" copy --detect-type 3>&- < "$TEST_DATA_DIR/test_image.png"
+    "$RICHCLIP" paste --type image/png > "$BATS_TEST_TMPDIR/detected.png"
+    cmp "$TEST_DATA_DIR/test_image.png" "$BATS_TEST_TMPDIR/detected.png"
+}
+
+@test "MacOS '--numbered' cannot be used with '--all'" {
+    run -2 "$RICHCLIP" paste --list-types --all --numbered
+}
@@ -0,0 +1,40 @@
+#!/usr/bin/env bats
+bats_require_minimum_version 1.5.0
+
+SWAY_PID=""
+SWAY_RUNTIME_DIR=""
+
+setup_suite() {
+    if [ -z "$WAYLAND_DISPLAY" ] && command -v sway > /dev/null; then
+        # Start a headless compositor which supports wlr-data-control for testing. sway creates
+        # the default seat even without any input devices. A private runtime dir is used to find
+        # the socket name which is picked by sway.
+        SWAY_RUNTIME_DIR=$(mktemp -d)
+        chmod 700 "$SWAY_RUNTIME_DIR"
+        export XDG_RUNTIME_DIR="$SWAY_RUNTIME_DIR"
+        WLR_BACKENDS=headless WLR_LIBINPUT_NO_DEVICES=1 WLR_RENDERER=pixman \
+            sway -c /dev/null 3>&- &
+        SWAY_PID=$!
+        local socket=""
+        for _ in $(seq 50); do
+            socket=$(find "$XDG_RUNTIME_DIR" -maxdepth 1 -type s -name 'wayland-*' -printf '%f\n' | head -n 1)
+            if [ -n "$socket" ]; then
+                break
+            fi
+            sleep 0.1
+        done
+        export WAYLAND_DISPLAY="$socket"
+    fi
+    # Make sure the X backend is not picked by accident
+    unset DISPLAY
+    run -0 cargo build
+}
+
+teardown_suite() {
+    if [ -n "$SWAY_PID" ]; then
+        kill "$SWAY_PID"
+    fi
+    if [ -n "$SWAY_RUNTIME_DIR" ]; then
+        rm -rf "$SWAY_RUNTIME_DIR"
+    fi
+}
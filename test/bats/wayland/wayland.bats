@@ -9,14 +9,13 @@ TEST_DATA_DIR=$(realpath "$ROOT_DIR/test/data")
 RICHCLIP="$ROOT_DIR/target/debug/richclip"
 
 setup_file() {
-    # Currently there is no way to test this on CI like what we do for X window. The Headless
-    # weston could be an option. However, it doesn't have wl-seat for the headless mode. See:
+    # setup_suite starts a headless sway if it is installed. The headless weston doesn't have
+    # wl-seat for the headless mode, so it cannot be used. See:
     # https://gitlab.freedesktop.org/wayland/weston/-/merge_requests/209
     # https://gitlab.freedesktop.org/wayland/weston/-/issues/294
     if [ -z "$WAYLAND_DISPLAY" ]; then
         skip
     fi
-    run -0 cargo build
 }
 
 teardown() {
@@ -38,7 +37,7 @@ teardown() {
     [ "$output" = "special_mime_type" ]
 
     # Expected mime-type does not exist
-    run -0 --separate-stderr "$RICHCLIP" paste -t "not_this_type"
+    run -3 --separate-stderr "$RICHCLIP" paste -t "not_this_type"
     [ "$output" = "" ]
 }
 
@@ -88,3 +87,95 @@ teardown() {
     run -0 wl-paste -p -t "text/html"
     [ "$output" = "BAD" ]
 }
+
+@test "wayland copy with '--selection both'" {
+    echo -n "Both" | "$RICHCLIP" copy --one-shot --selection both 3>&-
+    run -0 wl-paste
+    [ "$output" = "Both" ]
+    run -0 wl-paste -p
+    [ "$output" = "Both" ]
+}
+
+@test "wayland copy with '--idle-timeout'" {
+    echo -n "Idle" | "$RICHCLIP" copy --one-shot --idle-timeout 1 3>&-
+    # Every paste resets the timeout
+    for _ in 1 2 3; do
+        sleep 0.6
+        run -0 wl-paste
+        [ "$output" = "Idle" ]
+    done
+    pgrep -x richclip
+
+    # Exits with no requestors for the idle period, and clears the clipboard
+    sleep 1.5
+    run -1 pgrep -x richclip
+    run -0 --separate-stderr wl-paste -l
+    [ "$output" = "" ]
+}
+
+@test "wayland paste JSON" {
+    echo -n '{"a": 1}' | wl-copy --type application/json
+    run -0 "$RICHCLIP" paste
+    [ "$output" = '{"a": 1}' ]
+    run -0 "$RICHCLIP" paste --type text
+    [ "$output" = '{"a": 1}' ]
+    run -0 "$RICHCLIP" paste --type application/json
+    [ "$output" = '{"a": 1}' ]
+}
+
+@test "wayland paste list mime-types with '--with-sizes'" {
+    echo -n "TestDaTA" | wl-copy --type some-type
+    run -0 --separate-stderr "$RICHCLIP" paste -l --with-sizes
+    [ "$output" = "$(printf 'some-type\t8')" ]
+}
+
+@test "wayland print the backend" {
+    run -0 "$RICHCLIP" --print-backend
+    [ "$output" = "wayland" ]
+
+    # X11 is used if preferred and available, e.g. XWayland
+    if [ -n "$DISPLAY" ]; then
+        RICHCLIP_PREFER=x11 run -0 "$RICHCLIP" --print-backend
+        [ "$output" = "x" ]
+    fi
+}
+
+@test "wayland paste with '--wait-for-change'" {
+    wl-copy "Initial" 3>&-
+    "$RICHCLIP" paste --wait-for-change > "$BATS_TEST_TMPDIR/out" 3>&- &
+    local pid=$!
+    sleep 0.5
+    # Still waiting, the initial content is skipped
+    kill -0 "$pid"
+
+    wl-copy "Changed" 3>&-
+    wait "$pid"
+    [ "$(cat "$BATS_TEST_TMPDIR/out")" = "Changed" ]
+}
+
+@test "wayland paste with '--list-types --all-selections'" {
+    wl-copy -t "text/plain" "Clip" 3>&-
+    wl-copy -p -t "image/png" "Primary" 3>&-
+    run -0 "$RICHCLIP" paste -l --all-selections
+    [ "${lines[0]}" = "clipboard	text/plain" ]
+    [ "${lines[1]}" = "primary	image/png" ]
+    [ "${#lines[@]}" -eq 2 ]
+
+    run -0 "$RICHCLIP" paste -l --all-selections --numbered
+    [ "${lines[1]}" = "primary	0	image/png" ]
+
+    run -2 "$RICHCLIP" paste --all-selections
+}
+
+@test "wayland copy and paste a PNG byte-identically" {
+    "$RICHCLIP" copy --type image/png 3>&- < "$TEST_DATA_DIR/test_image.png"
+    "$RICHCLIP" paste --type image/png > "$BATS_TEST_TMPDIR/pasted.png"
+    cmp "$TEST_DATA_DIR/test_image.png" "$BATS_TEST_TMPDIR/pasted.png"
+    wl-paste -t image/png > "$BATS_TEST_TMPDIR/wl-paste.png"
+    cmp "$TEST_DATA_DIR/test_image.png" "$BATS_TEST_TMPDIR/wl-paste.png"
+
+    # Piped from a screenshot tool without the type
+    "$RICHCLIP" copy --detect-type 3>&- < "$TEST_DATA_DIR/test_image.png"
+    "$RICHCLIP" paste --type image/png > "$BATS_TEST_TMPDIR/detected.png"
+    cmp "$TEST_DATA_DIR/test_image.png" "$BATS_TEST_TMPDIR/detected.png"
+}
@@ -27,8 +27,10 @@ teardown() {
     [ "$output" = "special_mime_type" ]
 
     # Expected mime-type does not exist
-    run -0 "$RICHCLIP" paste -t "not_this_type"
+    run -3 --separate-stderr "$RICHCLIP" paste -t "not_this_type"
     [ "$output" = "" ]
+    # Fails before requesting it, with the offered mime-types
+    [[ "$stderr" == *"in the supported list ["*"spec/type"* ]]
 }
 
 @test "X paste with empty clipboard" {
@@ -63,6 +65,15 @@ teardown() {
     [ "${lines[1]}" = "other-type" ]
 }
 
+@test "X paste list mime-types in the advertised case" {
+    echo -n "TestDaTA" | xclip -i -selection clipboard -target "UTF8_STRING" 3>&-
+    run -0 "$RICHCLIP" paste -l
+    [ "${lines[0]}" = "TARGETS" ]
+    [ "${lines[1]}" = "UTF8_STRING" ]
+    run -0 "$RICHCLIP" paste --type utf8_string
+    [ "$output" = "TestDaTA" ]
+}
+
 @test "X copy" {
     "$RICHCLIP" copy 3>&- < "$TEST_DATA_DIR/test_data_0"
 
@@ -83,3 +94,384 @@ teardown() {
     run -0 xclip -o -selection primary -target "text/html"
     [ "$output" = "BAD" ]
 }
+
+@test "X copy with '--serve-once'" {
+    "$RICHCLIP" copy --serve-once 3>&- < "$TEST_DATA_DIR/test_data_0"
+
+    # TARGETS doesn't count
+    run -0 xclip -o -selection clipboard -target TARGETS
+    run -0 xclip -o -selection clipboard
+    [ "$output" = "GOOD" ]
+    sleep 0.5
+    run -1 pgrep -x richclip
+}
+
+@test "X paste with '--raw'" {
+    echo "special_mime_type" | xclip -i -selection clipboard -target "spec/type" 3>&-
+    run -0 "$RICHCLIP" paste --raw -t "spec/type"
+    [ "$output" = "special_mime_type" ]
+
+    echo "TestDaTA" | xclip -i -selection clipboard 3>&-
+    run -0 "$RICHCLIP" paste --raw
+    [ "$output" = "TestDaTA" ]
+}
+
+@test "X paste with '--timeout'" {
+    "$RICHCLIP" copy --foreground 3>&- < "$TEST_DATA_DIR/test_data_0" &
+    local pid=$!
+    sleep 0.5
+    run -0 "$RICHCLIP" paste --timeout 1
+    [ "$output" = "GOOD" ]
+
+    # The selection owner doesn't respond
+    kill -STOP $pid
+    run -1 --separate-stderr "$RICHCLIP" paste --timeout 1 --poll-interval-ms 10
+    kill -CONT $pid
+}
+
+@test "X copy with '--expire'" {
+    "$RICHCLIP" copy --expire 1 3>&- < "$TEST_DATA_DIR/test_data_0"
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "GOOD" ]
+
+    sleep 1.5
+    run -1 pgrep -x richclip
+    # The selection has been cleared
+    run -0 --separate-stderr "$RICHCLIP" paste -l
+    [ "$output" = "" ]
+}
+
+@test "X copy with '--idle-timeout'" {
+    "$RICHCLIP" copy --idle-timeout 1 3>&- < "$TEST_DATA_DIR/test_data_0"
+    # Every paste resets the timeout
+    for _ in 1 2 3; do
+        sleep 0.6
+        run -0 "$RICHCLIP" paste
+        [ "$output" = "GOOD" ]
+    done
+    pgrep -x richclip
+
+    sleep 1.5
+    run -1 pgrep -x richclip
+    run -0 --separate-stderr "$RICHCLIP" paste -l
+    [ "$output" = "" ]
+}
+
+@test "X watch with '--count'" {
+    "$RICHCLIP" watch --count 2 > "$BATS_TEST_TMPDIR/watched" 3>&- &
+    local pid=$!
+    sleep 0.5
+    echo -n "First" | xclip -i -selection clipboard 3>&-
+    sleep 0.5
+    echo -n "Second" | xclip -i -selection clipboard 3>&-
+    wait $pid
+
+    run -0 cat "$BATS_TEST_TMPDIR/watched"
+    [ "${lines[0]}" = "First" ]
+    [ "${lines[1]}" = "Second" ]
+}
+
+@test "X copy offers UTF8_STRING and STRING for plain text" {
+    echo -n "GOOD" | "$RICHCLIP" copy --type text/plain 3>&-
+    run -0 xclip -o -selection clipboard -target TARGETS
+    [[ "$output" == *"UTF8_STRING"* ]]
+    [[ "$output" == *"STRING"* ]]
+
+    run -0 xclip -o -selection clipboard -target STRING
+    [ "$output" = "GOOD" ]
+    run -0 xclip -o -selection clipboard -target UTF8_STRING
+    [ "$output" = "GOOD" ]
+}
+
+@test "X paste with '--measure'" {
+    "$RICHCLIP" copy < "$TEST_DATA_DIR/test_data_0" 3>&-
+    run -0 "$RICHCLIP" paste --measure
+    [ "$output" = "text/plain 4" ]
+    run -0 "$RICHCLIP" paste --measure --type text/html
+    [ "$output" = "text/html 3" ]
+}
+
+@test "X sync" {
+    "$RICHCLIP" sync --bidirectional 3>&- &
+    sleep 0.5
+    echo -n "FromPrimary" | xclip -i -selection primary 3>&-
+    sleep 0.5
+    run -0 xclip -o -selection clipboard
+    [ "$output" = "FromPrimary" ]
+
+    echo -n "FromClipboard" | xclip -i -selection clipboard 3>&-
+    sleep 0.5
+    run -0 xclip -o -selection primary
+    [ "$output" = "FromClipboard" ]
+}
+
+@test "X paste with '--all-types'" {
+    "$RICHCLIP" copy 3>&- < "$TEST_DATA_DIR/test_data_0"
+    "$RICHCLIP" paste --all-types > "$BATS_TEST_TMPDIR/dump"
+
+    # The dump can be copied back in bulk mode
+    "$RICHCLIP" copy 3>&- < "$BATS_TEST_TMPDIR/dump"
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "GOOD" ]
+    run -0 "$RICHCLIP" paste -t text/html
+    [ "$output" = "BAD" ]
+}
+
+@test "X paste with '--target-atom'" {
+    echo -n "TestDaTA" | xclip -i -selection clipboard -target STRING 3>&-
+    # 31 is the predefined atom of 'STRING'
+    run -0 "$RICHCLIP" paste --target-atom 31
+    [ "$output" = "TestDaTA" ]
+}
+
+@test "X paste with '--safe-terminal'" {
+    printf 'a\e]0;title\ab\e[201~c\x07d' | "$RICHCLIP" copy --one-shot 3>&-
+    # Not a terminal, the content is kept
+    run -0 "$RICHCLIP" paste --safe-terminal
+    [ "$output" = "$(printf 'a\e]0;title\ab\e[201~c\x07d')" ]
+
+    run -0 script -qec "'$RICHCLIP' paste --safe-terminal" /dev/null
+    [ "$output" = "abcd" ]
+}
+
+@test "X copy and paste with 'RICHCLIP_DEFAULT_SELECTION'" {
+    echo -n "Clipboard" | xclip -i -selection clipboard 3>&-
+    echo -n "Primary" | RICHCLIP_DEFAULT_SELECTION=primary "$RICHCLIP" copy --one-shot 3>&-
+    run -0 xclip -o -selection primary
+    [ "$output" = "Primary" ]
+    run -0 xclip -o -selection clipboard
+    [ "$output" = "Clipboard" ]
+
+    RICHCLIP_DEFAULT_SELECTION=primary run -0 "$RICHCLIP" paste
+    [ "$output" = "Primary" ]
+    RICHCLIP_DEFAULT_SELECTION=clipboard run -0 "$RICHCLIP" paste
+    [ "$output" = "Clipboard" ]
+
+    # The command line options take precedence
+    echo -n "Explicit" | RICHCLIP_DEFAULT_SELECTION=primary \
+        "$RICHCLIP" copy --one-shot --selection clipboard 3>&-
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "Explicit" ]
+    RICHCLIP_DEFAULT_SELECTION=primary run -0 "$RICHCLIP" paste --selection clipboard
+    [ "$output" = "Explicit" ]
+    run -2 "$RICHCLIP" paste --selection both
+}
+
+@test "X paste with '--selection-owner-info'" {
+    echo -n "TestDaTA" | xclip -i -selection clipboard 3>&-
+    run -0 "$RICHCLIP" paste --selection-owner-info
+    [[ "${lines[0]}" == "Owner: window 0x"* ]]
+    [[ "${lines[1]}" == "WM_NAME: "* ]]
+    [[ "${lines[2]}" == "WM_CLASS: "* ]]
+    [ "${#lines[@]}" -eq 3 ]
+}
+
+@test "X copy with '--command'" {
+    "$RICHCLIP" copy --command 'date +%s%N' 3>&-
+    run -0 "$RICHCLIP" paste
+    local first="$output"
+    [ -n "$first" ]
+    run -0 "$RICHCLIP" paste
+    [ "$output" != "$first" ]
+}
+
+@test "X copy and paste with '--filter'" {
+    echo -n "abc" | "$RICHCLIP" copy --one-shot --filter 'tr a-z A-Z' 3>&-
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "ABC" ]
+
+    run -0 "$RICHCLIP" paste --filter 'tr A-Z a-z'
+    [ "$output" = "abc" ]
+    run -0 "$RICHCLIP" paste --filter cat
+    [ "$output" = "ABC" ]
+
+    run -1 "$RICHCLIP" paste --filter 'cat; exit 1'
+}
+
+@test "X paste with '--strip-ansi'" {
+    printf '\e[1;31mred\e[0m \e[38;5;208mtext\e[m' | "$RICHCLIP" copy --one-shot 3>&-
+    run -0 "$RICHCLIP" paste --strip-ansi
+    [ "$output" = "red text" ]
+
+    # Binary content is not changed
+    printf '\e[1mbold' | "$RICHCLIP" copy --one-shot --type application/octet-stream 3>&-
+    run -0 "$RICHCLIP" paste --strip-ansi --type application/octet-stream
+    [ "$output" = "$(printf '\e[1mbold')" ]
+}
+
+@test "X copy with '--persist-primary'" {
+    echo -n "Persisted" | "$RICHCLIP" copy --one-shot --primary --persist-primary 3>&-
+    echo -n "Selected" | xclip -i -selection primary 3>&-
+    sleep 0.5
+    run -0 "$RICHCLIP" paste --primary
+    [ "$output" = "Persisted" ]
+}
+
+@test "X copy with '--exclusive'" {
+    echo -n "Exclusive" | "$RICHCLIP" copy --one-shot --exclusive 3>&-
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "Exclusive" ]
+}
+
+@test "X copy with '--socket'" {
+    local socket="$BATS_TEST_TMPDIR/richclip.sock"
+    "$RICHCLIP" copy --socket "$socket" 3>&- &
+    local pid=$!
+    sleep 0.5
+
+    python3 -c 'import socket, sys; s = socket.socket(socket.AF_UNIX); s.connect(sys.argv[1]); s.sendall(sys.stdin.buffer.read())' \
+        "$socket" < "$TEST_DATA_DIR/test_data_0"
+    sleep 0.5
+    run -0 "$RICHCLIP" paste
+    [ "$output" = "GOOD" ]
+    run -0 "$RICHCLIP" paste -t text/html
+    [ "$output" = "BAD" ]
+
+    kill "$pid"
+}
+
+@test "X copy with '--selection both'" {
+    echo -n "Both" | "$RICHCLIP" copy --one-shot --selection both 3>&-
+    run -0 xclip -o -selection clipboard
+    [ "$output" = "Both" ]
+    run -0 xclip -o -selection primary
+    [ "$output" = "Both" ]
+
+    # Still serving the other one
+    echo -n "Other" | xclip -i -selection clipboard 3>&-
+    run -0 xclip -o -selection primary
+    [ "$output" = "Both" ]
+}
+
+@test "X paste with '--chunk-timeout'" {
+    # Small chunks to make the transfer slow enough to be stalled
+    head -c 8000000 /dev/zero | "$RICHCLIP" copy --one-shot --no-detach --chunk-size 64 3>&- &
+    local pid=$!
+    sleep 0.5
+
+    "$RICHCLIP" paste --chunk-timeout 1 > /dev/null 2> "$BATS_TEST_TMPDIR/stderr" &
+    local paste_pid=$!
+    sleep 0.2
+    # The owner stalls in the middle of the INCR transfer
+    kill -STOP "$pid"
+    run -1 wait "$paste_pid"
+    kill -CONT "$pid"
+    kill "$pid"
+    grep -q "stopped sending the content" "$BATS_TEST_TMPDIR/stderr"
+}
+
+@test "X paste list mime-types with '--no-final-newline'" {
+    echo -n "TestDaTA" | xclip -i -selection clipboard -target some-type 3>&-
+    "$RICHCLIP" paste -l --no-final-newline > "$BATS_TEST_TMPDIR/types"
+    [ "$(tail -c 1 "$BATS_TEST_TMPDIR/types")" != "" ]
+    [ "$(tail -n 1 "$BATS_TEST_TMPDIR/types")" = "some-type" ]
+}
+
+@test "X copy and paste JSON" {
+    echo -n '{"a": 1}' | "$RICHCLIP" copy --type application/json 3>&-
+    run -0 "$RICHCLIP" paste
+    [ "$output" = '{"a": 1}' ]
+    run -0 "$RICHCLIP" paste --type text
+    [ "$output" = '{"a": 1}' ]
+    run -0 "$RICHCLIP" paste --type application/json
+    [ "$output" = '{"a": 1}' ]
+}
+
+@test "X paste with '--tee'" {
+    "$RICHCLIP" copy 3>&- < "$TEST_DATA_DIR/test_data_0"
+    run -0 "$RICHCLIP" paste --tee "$BATS_TEST_TMPDIR/a" --tee "$BATS_TEST_TMPDIR/b"
+    [ "$output" = "GOOD" ]
+    [ "$(cat "$BATS_TEST_TMPDIR/a")" = "GOOD" ]
+    [ "$(cat "$BATS_TEST_TMPDIR/b")" = "GOOD" ]
+
+    run -1 "$RICHCLIP" paste --tee "$BATS_TEST_TMPDIR/no/such/dir"
+}
+
+@test "X paste with '--type TARGETS'" {
+    echo -n "TestDaTA" | xclip -i -selection clipboard -target some-type 3>&-
+    run -0 "$RICHCLIP" paste --type TARGETS
+    [ "${lines[0]}" = "TARGETS" ]
+    [ "${lines[1]}" = "some-type" ]
+}
+
+@test "X paste cancelled by Ctrl-C" {
+    # Small chunks to make the transfer slow enough to be cancelled
+    head -c 8000000 /dev/zero | "$RICHCLIP" copy --one-shot --no-detach --chunk-size 64 3>&- &
+    local pid=$!
+    sleep 0.5
+
+    "$RICHCLIP" paste > "$BATS_TEST_TMPDIR/partial" 2> "$BATS_TEST_TMPDIR/stderr" &
+    local paste_pid=$!
+    sleep 0.2
+    kill -STOP "$pid"
+    kill -INT "$paste_pid"
+    run -5 wait "$paste_pid"
+    kill -CONT "$pid"
+    kill "$pid"
+    grep -q "cancelled" "$BATS_TEST_TMPDIR/stderr"
+    # The content received so far is written
+    local size
+    size=$(stat -c %s "$BATS_TEST_TMPDIR/partial")
+    [ "$size" -gt 0 ]
+    [ "$size" -lt 8000000 ]
+}
+
+@test "X paste with '--alias-file'" {
+    echo -n "# Title" | xclip -i -selection clipboard -target text/markdown 3>&-
+    printf '# Aliases\nmd=text/markdown\n' > "$BATS_TEST_TMPDIR/aliases"
+    run -0 "$RICHCLIP" paste --alias-file "$BATS_TEST_TMPDIR/aliases" --type md
+    [ "$output" = "# Title" ]
+    RICHCLIP_ALIASES="$BATS_TEST_TMPDIR/aliases" run -0 "$RICHCLIP" paste --type md
+    [ "$output" = "# Title" ]
+
+    echo "broken" > "$BATS_TEST_TMPDIR/aliases"
+    run -1 "$RICHCLIP" paste --alias-file "$BATS_TEST_TMPDIR/aliases" --type md
+}
+
+@test "X paste list mime-types with '--with-sizes'" {
+    echo -n "TestDaTA" | xclip -i -selection clipboard -target some-type 3>&-
+    run -0 "$RICHCLIP" paste -l --with-sizes
+    [ "${lines[1]}" = "$(printf 'some-type\t8')" ]
+}
+
+@test "X doctor reports the clipboard manager" {
+    # No clipboard manager runs in the test X server
+    run -0 "$RICHCLIP" doctor
+    [[ "$output" == *"X clipboard manager: none"* ]]
+}
+
+@test "X paste with '--framed'" {
+    "$RICHCLIP" copy 3>&- < "$TEST_DATA_DIR/test_data_0"
+    run -0 bash -c "'$RICHCLIP' paste --framed | od -An -tx1 | tr -d ' \n'"
+    # 4 bytes length in little-endian, then "GOOD"
+    [ "$output" = "04000000474f4f44" ]
+}
+
+@test "X copy with '--detect-type'" {
+    printf '\x89PNG\r\n\x1a\nIHDR' | "$RICHCLIP" copy --detect-type 3>&-
+    run -0 "$RICHCLIP" paste -l
+    [[ "$output" == *"image/png"* ]]
+    [[ "$output" != *"text/plain"* ]]
+
+    "$RICHCLIP" copy --detect-type 3>&- < "$TEST_DATA_DIR/test_data_0"
+    run -0 "$RICHCLIP" paste -t text/plain
+    [ "$output" = "GOOD" ]
+}
+
+@test "X print the backend" {
+    run -0 "$RICHCLIP" --print-backend
+    [ "$output" = "x" ]
+}
+
+@test "X copy and paste a PNG byte-identically" {
+    "$RICHCLIP" copy --type image/png 3>&- < "$TEST_DATA_DIR/test_image.png"
+    "$RICHCLIP" paste --type image/png > "$BATS_TEST_TMPDIR/pasted.png"
+    cmp "$TEST_DATA_DIR/test_image.png" "$BATS_TEST_TMPDIR/pasted.png"
+    xclip -o -selection clipboard -target image/png > "$BATS_TEST_TMPDIR/xclip.png"
+    cmp "$TEST_DATA_DIR/test_image.png" "$BATS_TEST_TMPDIR/xclip.png"
+
+    # Piped from a screenshot tool without the type
+    "$RICHCLIP" copy --detect-type 3>&- < "$TEST_DATA_DIR/test_image.png"
+    "$RICHCLIP" paste --type image/png > "$BATS_TEST_TMPDIR/detected.png"
+    cmp "$TEST_DATA_DIR/test_image.png" "$BATS_TEST_TMPDIR/detected.png"
+}
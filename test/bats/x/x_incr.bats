@@ -54,3 +54,30 @@ teardown() {
     run -0 "$RICHCLIP" paste -p -t "text/html"
     [ "$output" = "BAD" ]
 }
+
+@test "X INCR copy with '--serve-once'" {
+    "$RICHCLIP" copy --serve-once --chunk-size=1 3>&- < "$TEST_DATA_DIR/test_data_0"
+
+    run -0 xclip -o -selection clipboard
+    [ "$output" = "GOOD" ]
+    sleep 0.5
+    run -1 pgrep -x richclip
+}
+
+@test "X INCR round trip of a large binary payload" {
+    head -c 3000000 /dev/urandom > "$BATS_TEST_TMPDIR/large"
+
+    # Small chunks
+    "$RICHCLIP" copy --type "application/octet-stream" --chunk-size=4096 3>&- \
+        < "$BATS_TEST_TMPDIR/large"
+    "$RICHCLIP" paste -t "application/octet-stream" > "$BATS_TEST_TMPDIR/pasted"
+    cmp "$BATS_TEST_TMPDIR/large" "$BATS_TEST_TMPDIR/pasted"
+
+    # The default chunk size, which is decided by the X server's maximum request size
+    "$RICHCLIP" copy --type "application/octet-stream" 3>&- < "$BATS_TEST_TMPDIR/large"
+    "$RICHCLIP" paste -t "application/octet-stream" > "$BATS_TEST_TMPDIR/pasted"
+    cmp "$BATS_TEST_TMPDIR/large" "$BATS_TEST_TMPDIR/pasted"
+
+    xclip -o -selection clipboard -target "application/octet-stream" > "$BATS_TEST_TMPDIR/pasted"
+    cmp "$BATS_TEST_TMPDIR/large" "$BATS_TEST_TMPDIR/pasted"
+}